@@ -0,0 +1,93 @@
+//! Converts a generated app store, once on a single thread and once on all CPUs
+//!
+//! Run with `cargo bench --features cli --bench convert`.
+
+use std::{fmt::Write, path::Path};
+
+use citadel_apps::cli::{convert_dir, root::CitadelRoot, ConvertOptions};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const APP_COUNT: usize = 150;
+
+/// An app with a few services, rendered from a template like many apps in the app stores
+fn app_yml_template(app_id: &str) -> String {
+    let mut template = format!(
+        "citadel_version: 4
+metadata:
+  name: {app_id}
+  version: 1.0.0
+  category: Benchmark
+  tagline: A generated app
+  developers: {{Citadel: https://runcitadel.space}}
+  permissions: []
+  repo: {{Public: https://github.com/runcitadel/apps}}
+  support: https://runcitadel.space
+  description: A generated app
+services:
+  main:
+    image: ghcr.io/runcitadel/{app_id}:main
+    port: 3000
+    user: \"1000:1000\"
+    environment:
+      PASSWORD: {{{{ derive_entropy(identifier=\"password\") }}}}
+    mounts:
+      data:
+        data: /data
+    depends_on:
+      - db
+"
+    );
+    for service in ["db", "worker", "cache"] {
+        write!(
+            template,
+            "  {service}:
+    image: ghcr.io/runcitadel/{app_id}-{service}:main
+    user: \"1000:1000\"
+    environment:
+      SECRET: {{{{ derive_entropy(identifier=\"{service}\") }}}}
+    mounts:
+      data:
+        {service}: /data
+"
+        )
+        .unwrap();
+    }
+    template
+}
+
+fn generate_corpus(root: &Path) {
+    let seed_dir = root.join("db").join("citadel-seed");
+    std::fs::create_dir_all(&seed_dir).unwrap();
+    std::fs::write(seed_dir.join("seed"), "benchmark-seed").unwrap();
+    std::fs::write(root.join(".env"), "APPS_SUBNET=10.21.0.0/16\n").unwrap();
+    for i in 0..APP_COUNT {
+        let app_id = format!("app-{}", i);
+        let app_dir = root.join("apps").join(&app_id);
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(app_dir.join("app.yml.jinja"), app_yml_template(&app_id)).unwrap();
+    }
+}
+
+fn convert(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    generate_corpus(dir.path());
+    let root = CitadelRoot::new(dir.path());
+    let mut group = c.benchmark_group(format!("convert {} apps", APP_COUNT));
+    group.sample_size(10);
+    for (name, jobs) in [("1 thread", 1), ("all CPUs", 0)] {
+        let options = ConvertOptions {
+            jobs,
+            ..Default::default()
+        };
+        // The first conversion allocates IPs and ports, later ones only regenerate the files
+        let report = convert_dir(&root, &options).unwrap();
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &options, |b, options| {
+            b.iter(|| convert_dir(&root, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, convert);
+criterion_main!(benches);
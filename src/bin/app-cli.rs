@@ -1,4 +1,6 @@
 use citadel_apps::cli;
+use citadel_apps::cli::lock::{RootLock, DEFAULT_LOCK_TIMEOUT};
+use citadel_apps::cli::root::CitadelRoot;
 #[cfg(all(feature = "umbrel", feature = "dev-tools"))]
 use citadel_apps::composegenerator::umbrel::types::Metadata as UmbrelMetadata;
 use citadel_apps::composegenerator::v4::types::AppYml;
@@ -15,13 +17,32 @@ use citadel_apps::{
 use clap::{Parser, Subcommand};
 use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 
 #[derive(Subcommand, Debug)]
 enum SubCommand {
     /// Convert a citadel app.yml to a result.yml file
+    ///
+    /// Exits with 2 if some apps failed to convert and 3 if the conversion was aborted.
     Convert {
         /// The citadel root dir
         citadel_root: String,
+        /// Skip ports that other processes on the host are already listening on
+        #[clap(long)]
+        probe_ports: bool,
+        /// Only convert this app, all other apps keep their current output
+        #[clap(long)]
+        app: Option<String>,
+        /// Print a diff of the files that would change without writing them,
+        /// exits with 1 if anything would change
+        #[clap(long)]
+        dry_run: bool,
+        /// Print a summary of converted, skipped and failed apps as JSON
+        #[clap(long)]
+        json: bool,
+        /// The number of threads to convert apps on, defaults to one per CPU
+        #[clap(short, long, default_value = "0")]
+        jobs: usize,
     },
     /// Get a JSON schema for the app.yml format
     #[cfg(feature = "dev-tools")]
@@ -87,6 +108,82 @@ enum SubCommand {
         #[clap(long)]
         citadel_root: String,
     },
+    /// Allow a client to connect to an app's hidden services that only allow authorized clients
+    AddTorClient {
+        /// The app to authorize the client for
+        app: String,
+        /// A name for the client
+        name: String,
+        /// The client's x25519 public key, in base32 or descriptor:x25519:<key> format
+        key: String,
+        /// The Citadel root directory
+        #[clap(long)]
+        citadel_root: String,
+    },
+    /// List the clients authorized for an app's hidden services
+    ListTorClients {
+        /// The app to list the clients of
+        app: String,
+        /// The Citadel root directory
+        #[clap(long)]
+        citadel_root: String,
+    },
+    /// Revoke a client's access to an app's hidden services
+    RevokeTorClient {
+        /// The app to revoke the client's access to
+        app: String,
+        /// The name of the client
+        name: String,
+        /// The Citadel root directory
+        #[clap(long)]
+        citadel_root: String,
+    },
+    /// Install a downloaded app and generate its files, with Docker support also start its containers
+    ///
+    /// Exits with 2 if the app was installed, but its containers could not be started.
+    Install {
+        /// The app to install
+        app: String,
+        /// The Citadel root directory
+        #[clap(long)]
+        citadel_root: String,
+    },
+    /// Uninstall an app and generate its files again, with Docker support also stop its containers
+    Uninstall {
+        /// The app to uninstall
+        app: String,
+        /// The Citadel root directory
+        #[clap(long)]
+        citadel_root: String,
+        /// Delete the app's data
+        #[clap(long)]
+        remove_data: bool,
+        /// Delete the app's onion addresses and authorized Tor clients
+        #[clap(long)]
+        remove_tor: bool,
+        /// Release the app's IP addresses and ports
+        #[clap(long)]
+        remove_allocations: bool,
+    },
+    /// Restore the files replaced by the last conversion
+    Rollback {
+        /// The citadel root dir
+        citadel_root: String,
+    },
+    /// Inspect the configuration from app-cli.yml and APP_CLI_* environment variables
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the configuration that is used, including defaults and settings from .env
+    Show {
+        /// The citadel root dir
+        citadel_root: String,
+    },
 }
 
 /// Manage apps on Citadel
@@ -95,6 +192,25 @@ struct Cli {
     /// The subcommand to run
     #[clap(subcommand)]
     command: SubCommand,
+    /// Seconds to wait for other app-cli processes that modify the Citadel root
+    #[clap(long, global = true, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    lock_timeout: u64,
+}
+
+/// Loads the configuration of a Citadel root, exits if it is invalid
+fn open_root(citadel_root: String) -> CitadelRoot {
+    CitadelRoot::open(citadel_root).unwrap_or_else(|error| {
+        eprintln!("Invalid configuration: {:#}", error);
+        exit(1);
+    })
+}
+
+/// Waits until no other process modifies the Citadel root, exits if it does not finish in time
+fn lock_root(root: &CitadelRoot, timeout: Duration) -> RootLock {
+    RootLock::acquire(root, timeout).unwrap_or_else(|error| {
+        eprintln!("{:#}", error);
+        exit(1);
+    })
 }
 
 #[cfg(feature = "dev-tools")]
@@ -118,11 +234,63 @@ async fn update_app_yml(path: &Path, include_prerelease: bool) {
 }
 
 fn main() {
-    tracing_subscriber::fmt::init();
+    // Logs go to stderr, so they do not mix with output like diffs
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     let args: Cli = Cli::parse();
+    let lock_timeout = Duration::from_secs(args.lock_timeout);
     match args.command {
-        SubCommand::Convert { citadel_root } => {
-            cli::convert_dir(&citadel_root);
+        SubCommand::Convert {
+            citadel_root,
+            probe_ports,
+            app,
+            dry_run,
+            json,
+            jobs,
+        } => {
+            let options = cli::ConvertOptions {
+                probe_ports,
+                app,
+                dry_run,
+                jobs,
+                ..Default::default()
+            };
+            let report = match CitadelRoot::open(citadel_root).and_then(|root| {
+                // A dry run does not write anything, so it does not need to wait for other processes
+                let _lock = if dry_run {
+                    None
+                } else {
+                    Some(RootLock::acquire(&root, lock_timeout)?)
+                };
+                cli::convert_dir(&root, &options)
+            }) {
+                Ok(report) => report,
+                Err(error) => {
+                    if json {
+                        println!("{}", serde_json::json!({ "error": format!("{:#}", error) }));
+                    } else {
+                        eprintln!("Conversion failed: {:#}", error);
+                    }
+                    exit(3);
+                }
+            };
+            if json {
+                println!("{}", serde_json::to_string(&report).unwrap());
+            } else {
+                if let Some(diff) = &report.diff {
+                    print!("{}", diff);
+                }
+                for (app_id, reason) in &report.failed {
+                    eprintln!("Failed to convert app {}: {}", app_id, reason);
+                }
+            }
+            if !report.failed.is_empty() {
+                exit(2);
+            }
+            if dry_run && report.changed {
+                exit(1);
+            }
         }
         #[cfg(feature = "dev-tools")]
         SubCommand::Schema { version } => match version.as_str() {
@@ -255,16 +423,117 @@ fn main() {
             }
         }
         SubCommand::DownloadApps { citadel_root } => {
-            cli::repos::download_apps(&citadel_root).expect("Failed to download apps");
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::repos::download_apps(&root).expect("Failed to download apps");
         }
         SubCommand::DownloadNew { citadel_root } => {
-            cli::repos::download_new_apps(&citadel_root).expect("Failed to download apps");
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::repos::download_new_apps(&root).expect("Failed to download apps");
         }
         SubCommand::CheckUpdates { citadel_root } => {
-            cli::repos::list_updates(&citadel_root).expect("Failed to check for updates");
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::repos::list_updates(&root).expect("Failed to check for updates");
         }
         SubCommand::Download { citadel_root, app } => {
-            cli::repos::download_app(&citadel_root, &app).expect("Failed to download app");
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::repos::download_app(&root, &app).expect("Failed to download app");
+        }
+        SubCommand::AddTorClient {
+            app,
+            name,
+            key,
+            citadel_root,
+        } => {
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::tor::add_client(&root, &app, &name, &key).expect("Failed to add client");
+        }
+        SubCommand::ListTorClients { app, citadel_root } => {
+            let root = open_root(citadel_root);
+            cli::tor::list_clients(&root, &app).expect("Failed to list clients");
+        }
+        SubCommand::RevokeTorClient {
+            app,
+            name,
+            citadel_root,
+        } => {
+            let root = open_root(citadel_root);
+            let _lock = lock_root(&root, lock_timeout);
+            cli::tor::revoke_client(&root, &app, &name).expect("Failed to revoke client");
+        }
+        SubCommand::Install { app, citadel_root } => {
+            let root = open_root(citadel_root);
+            let lock = lock_root(&root, lock_timeout);
+            let options = cli::install::InstallOptions {
+                start: cfg!(feature = "docker"),
+            };
+            match cli::install::install(&root, &app, &options) {
+                Ok(report) => {
+                    if let Some(error) = report.start_error {
+                        eprintln!("Installed {}, but could not start it: {:#}", app, error);
+                        drop(lock);
+                        exit(2);
+                    }
+                    println!("Installed {}", app);
+                }
+                Err(error) => {
+                    eprintln!("Failed to install {}: {:#}", app, error);
+                    drop(lock);
+                    exit(1);
+                }
+            }
+        }
+        SubCommand::Uninstall {
+            app,
+            citadel_root,
+            remove_data,
+            remove_tor,
+            remove_allocations,
+        } => {
+            let root = open_root(citadel_root);
+            let lock = lock_root(&root, lock_timeout);
+            let options = cli::install::UninstallOptions {
+                stop: cfg!(feature = "docker"),
+                remove_data,
+                remove_tor,
+                remove_allocations,
+            };
+            if let Err(error) = cli::install::uninstall(&root, &app, &options) {
+                eprintln!("Failed to uninstall {}: {:#}", app, error);
+                drop(lock);
+                exit(1);
+            }
+            println!("Uninstalled {}", app);
+        }
+        SubCommand::Rollback { citadel_root } => {
+            let root = open_root(citadel_root);
+            let lock = lock_root(&root, lock_timeout);
+            match cli::changes::rollback(&root) {
+                Ok(true) => println!("Restored the previous generation"),
+                Ok(false) => println!("Nothing to restore"),
+                Err(error) => {
+                    eprintln!("Failed to roll back: {:#}", error);
+                    // exit does not run destructors
+                    drop(lock);
+                    exit(1);
+                }
+            }
+        }
+        SubCommand::Config {
+            command: ConfigCommand::Show { citadel_root },
+        } => {
+            let root = open_root(citadel_root);
+            match cli::config::show(&root) {
+                Ok(config) => print!("{}", config),
+                Err(error) => {
+                    eprintln!("Invalid configuration: {:#}", error);
+                    exit(1);
+                }
+            }
         }
     }
 }
@@ -1,452 +1,1192 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
 };
 
+use anyhow::{bail, Context};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::composegenerator::{
-    convert_config, load_config_as_v4,
-    types::OutputMetadata,
-    v4::{
-        types::{PortMapElement, PortPriority},
-        utils::{derive_entropy, get_main_container},
+use crate::{
+    composegenerator::{
+        i2p::{public_tunnels, render_tunnels, I2pTunnel},
+        load_config_as_v4,
+        output::{
+            backend::{all_backends, get_backend, OutputBackend, OutputFiles, DEFAULT_BACKEND},
+            quadlet::QuadletBackend,
+        },
+        proxy::{render_caddy, render_nginx, ProxyRoute, ProxyRouting},
+        tor::{render_torrc, HiddenService},
+        types::{NodeConfig, OutputMetadata, ResultYml},
+        v4::{
+            convert::convert_config_with,
+            types::{AppYml, Container, PortMapElement, PortPriority},
+            utils::{app_seeds, derive_entropy, get_main_container},
+        },
     },
+    utils::parse_size,
 };
 
+use self::{
+    changes::Changeset,
+    env_file::EnvFile,
+    ips::{reserved_ips_from_env, IpAllocator},
+    ports::{PortAllocator, PortCacheMap, PortCacheMapEntry},
+    root::CitadelRoot,
+    tor::{
+        assign_tor_instance, ensure_hidden_service_keys, expected_onion_address,
+        load_authorized_clients, sync_authorized_clients,
+    },
+};
+
+pub mod changes;
+pub mod config;
+#[cfg(feature = "docker")]
+pub mod docker;
+mod env_file;
+pub mod fs;
+pub mod install;
+pub mod ips;
+pub mod lock;
+mod ports;
 mod preprocessing;
 pub mod repos;
+pub mod root;
 mod tera;
+pub mod tor;
 
-// A port map as used during creating the port map
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-struct PortCacheMapEntry {
-    app: String,
-    // Internal port
-    internal_port: u16,
-    container: String,
-    dynamic: bool,
-    implements: Option<String>,
-    priority: PortPriority,
+/// The variables an app's output can refer to
+///
+/// Docker Compose gets these from the app manager when it starts the app,
+/// other backends need them to be resolved when the files are generated.
+/// Like Docker Compose, variables that are not known yet are left empty,
+/// this is the case for the domain if none is set and for onion addresses
+/// of hidden services that do not have keys yet and can not be derived from the seed.
+fn app_env_vars(
+    root: &CitadelRoot,
+    app_id: &str,
+    hidden_services: &[HiddenService],
+    env_vars: &HashMap<String, String>,
+    citadel_seed: Option<&str>,
+) -> HashMap<String, String> {
+    let mut app_env = env_vars.clone();
+    app_env.insert(
+        "APP_DATA_DIR".to_string(),
+        root.app_data_dir(app_id).to_string_lossy().to_string(),
+    );
+    app_env.entry("APP_DOMAIN".to_string()).or_default();
+    if let Some(citadel_seed) = citadel_seed {
+        app_env.extend(app_seeds(citadel_seed, app_id));
+    }
+    let main_dir = format!("app-{}", app_id.to_lowercase().replace('_', "-"));
+    for hidden_service in hidden_services {
+        let var = match hidden_service.dir.strip_prefix(&main_dir) {
+            Some("") => "APP_HIDDEN_SERVICE".to_string(),
+            Some(name) if name.starts_with('-') => format!(
+                "APP_HIDDEN_SERVICE{}",
+                name.to_uppercase().replace('-', "_")
+            ),
+            _ => continue,
+        };
+        let service_dir = root.hidden_service_dir(&hidden_service.dir);
+        let address =
+            expected_onion_address(root.fs(), &service_dir, &hidden_service.dir, citadel_seed)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| {
+                    tracing::warn!(
+                        "The address of hidden service {} of app {} is not known yet, {} is empty",
+                        hidden_service.dir,
+                        app_id,
+                        var
+                    );
+                    String::new()
+                });
+        app_env.insert(var, address);
+    }
+    app_env
+}
+
+fn create_hidden_service_keys(
+    changeset: &mut Changeset,
+    service_dir: &Path,
+    dir: &str,
+    citadel_seed: &Option<String>,
+) -> Option<String> {
+    ensure_hidden_service_keys(changeset, service_dir, dir, citadel_seed.as_deref())
+        .map_err(|err| tracing::warn!("Error creating keys for hidden service {}: {}", dir, err))
+        .ok()
+}
+
+/// Deletes all files any output backend could have generated for an app, except the ones in keep
+fn remove_output_files(
+    root: &CitadelRoot,
+    app_dir: &Path,
+    keep: &OutputFiles,
+    changeset: &mut Changeset,
+) {
+    let Ok(entries) = root.fs().read_dir(app_dir) else {
+        return;
+    };
+    let backends = all_backends();
+    for file_name in entries {
+        if keep.contains_key(&file_name)
+            || !backends
+                .iter()
+                .any(|backend| backend.is_output_file(&file_name))
+        {
+            continue;
+        }
+        changeset.delete(app_dir.join(file_name));
+    }
 }
 
-// Outside port -> app
-type PortCacheMap = HashMap<u16, PortCacheMapEntry>;
+/// The directory a backend's files are written to, with a subdirectory for every app
+///
+/// Podman only finds quadlet units in its search paths, so they are not kept in the apps directory.
+fn output_dir(root: &CitadelRoot, backend: &dyn OutputBackend) -> PathBuf {
+    if backend.name() == QuadletBackend.name() {
+        root.units_dir()
+    } else {
+        root.apps_dir()
+    }
+}
 
-static RESERVED_PORTS: [u16; 6] = [
-    80,    // Dashboard
-    433,   // Sometimes used by nginx with some setups
-    443,   // Dashboard SSL
-    8333,  // Bitcoin Core P2P
-    10009, // LND gRPC
-    8080,  // LND REST
-];
+/// Loads the limits for apps from the .env file
+fn node_config_from_env(env_vars: &HashMap<String, String>) -> anyhow::Result<NodeConfig> {
+    let mut node_config = NodeConfig::default();
+    if let Some(max_tmpfs_size) = env_vars.get("APP_TMPFS_MAX_SIZE") {
+        node_config.max_tmpfs_size =
+            parse_size(max_tmpfs_size).context("Invalid APP_TMPFS_MAX_SIZE")?;
+    }
+    if let Some(driver) = env_vars.get("APP_LOG_DRIVER") {
+        node_config.logging.driver = driver.clone();
+    }
+    if let Some(max_size) = env_vars.get("APP_LOG_MAX_SIZE") {
+        node_config.logging.max_size = parse_size(max_size).context("Invalid APP_LOG_MAX_SIZE")?;
+    }
+    if let Some(max_files) = env_vars.get("APP_LOG_MAX_FILE") {
+        node_config.logging.max_files = max_files.parse().context("Invalid APP_LOG_MAX_FILE")?;
+    }
+    if let Some(max_size) = env_vars.get("APP_LOG_MAX_SIZE_LIMIT") {
+        node_config.max_log_size =
+            parse_size(max_size).context("Invalid APP_LOG_MAX_SIZE_LIMIT")?;
+    }
+    if let Some(max_files) = env_vars.get("APP_LOG_MAX_FILE_LIMIT") {
+        node_config.max_log_files = max_files
+            .parse()
+            .context("Invalid APP_LOG_MAX_FILE_LIMIT")?;
+    }
+    // The node's default policy is always allowed
+    node_config.max_log_size = node_config.max_log_size.max(node_config.logging.max_size);
+    node_config.max_log_files = node_config.max_log_files.max(node_config.logging.max_files);
+    Ok(node_config)
+}
 
+/// What a converted app adds to the files shared by all apps
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct UserJson {
-    #[serde(rename = "installedApps")]
-    installed_apps: Vec<String>,
-    // We ignore other properties for now because we do not need them
+#[serde(rename_all = "camelCase")]
+struct ConvertedApp {
+    metadata: OutputMetadata,
+    /// Validated hidden services that do not conflict with other apps
+    hidden_services: Vec<HiddenService>,
+    i2p_tunnels: Vec<I2pTunnel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_route: Option<ProxyRoute>,
+    /// The variables the app adds to the .env file, its IP addresses and port
+    #[serde(default)]
+    env: BTreeMap<String, String>,
 }
 
-pub fn convert_dir(citadel_root: &str) {
-    let citadel_root = Path::new(&citadel_root);
-    let apps = std::fs::read_dir(citadel_root.join("apps")).expect("Error reading apps directory!");
-    let apps = apps.filter(|entry| {
-        let entry = entry.as_ref().expect("Error reading app directory!");
-        let path = entry.path();
+// App id -> conversion result
+// Saved so a single app can be converted without converting all other apps again
+type ConvertedApps = BTreeMap<String, ConvertedApp>;
 
-        path.is_dir()
-    });
+fn load_converted_apps(root: &CitadelRoot) -> anyhow::Result<ConvertedApps> {
+    root.load_json(&root.converted_apps_file())?
+        .context("No apps have been converted yet")
+}
 
-    let mut services = Vec::<String>::new();
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
-    if let Ok(user_json) = user_json {
-        let user_json = serde_json::from_reader::<_, UserJson>(user_json);
-        if let Ok(user_json) = user_json {
-            services = user_json.installed_apps;
-        }
+/// Loads an app's app.yml, None if the app does not have one
+fn load_app(
+    root: &CitadelRoot,
+    app_id: &str,
+    services: &[String],
+) -> Option<anyhow::Result<AppYml>> {
+    parse_app(root.read(&root.app_yml(app_id)), services)
+}
+
+/// Parses an app.yml that was read, None if it does not exist
+fn parse_app(
+    app_yml: anyhow::Result<Option<String>>,
+    services: &[String],
+) -> Option<anyhow::Result<AppYml>> {
+    match app_yml {
+        Ok(Some(app_yml)) => Some(load_config_as_v4(
+            app_yml.as_bytes(),
+            &Some(&services.to_vec()),
+        )),
+        Ok(None) => None,
+        Err(err) => Some(Err(err)),
     }
-    services.append(&mut vec!["bitcoind".to_string(), "lnd".to_string()]);
+}
 
-    let mut citadel_seed = None;
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Skip ports that other processes on the host are already listening on
+    pub probe_ports: bool,
+    /// Only convert this app, the other apps keep their output,
+    /// IP addresses and ports, and their entries in shared files stay the same
+    pub app: Option<String>,
+    /// Print a diff of all files that would change instead of writing them
+    ///
+    /// No keys are created for new hidden services in this mode.
+    pub dry_run: bool,
+    /// The number of threads apps are converted on, 0 uses one per CPU
+    pub jobs: usize,
+    /// Drop the IP addresses and ports of the converted app and allocate them as for a new app,
+    /// only used when a single app is converted
+    ///
+    /// If the app is removed, they are not allocated again.
+    pub reset_allocations: bool,
+    /// Remove the app instead of converting it, its output and its entries in the shared files
+    /// are deleted, only used when a single app is converted
+    pub remove: bool,
+    /// Replace the installed apps in user.json, it is written together with the generated files,
+    /// so a rollback restores both
+    ///
+    /// If the converted app fails to convert, nothing is written.
+    pub installed_apps: Option<Vec<String>>,
+}
 
-    let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
+/// What happened to the apps during a conversion
+///
+/// In a conversion of a single app, only that app is listed.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConvertReport {
+    /// The apps that were converted
+    pub succeeded: Vec<String>,
+    /// The apps that were not converted because they do not have an app.yml (app id -> reason)
+    pub skipped: BTreeMap<String, String>,
+    /// The apps that could not be converted (app id -> reason)
+    pub failed: BTreeMap<String, String>,
+    /// Whether any file was (or in a dry run, would be) changed
+    pub changed: bool,
+    /// The changes to the files, only set in a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
 
-    if citadel_seed_file.exists() {
-        let mut citadel_seed_file = std::fs::File::open(citadel_seed_file).unwrap();
-        let mut citadel_seed_str = String::new();
-        citadel_seed_file
-            .read_to_string(&mut citadel_seed_str)
-            .unwrap();
-        citadel_seed = Some(citadel_seed_str);
-    }
-
-    let ip_addresses_map_file = citadel_root.join("apps").join("ips.yml");
-    let mut ip_map: HashMap<String, String> = HashMap::new();
-    let mut current_suffix: u8 = 20;
-    if ip_addresses_map_file.exists() {
-        let ip_addresses_map_file = std::fs::File::open(ip_addresses_map_file.clone()).unwrap();
-        let ip_addresses_map: HashMap<String, String> =
-            serde_yaml::from_reader(ip_addresses_map_file).unwrap();
-        ip_map = ip_addresses_map;
-        current_suffix += ip_map.len() as u8;
-    }
-    // Later used for port assignment
-    let mut port_map = HashMap::<String, HashMap<String, Vec<PortMapElement>>>::new();
-    let mut port_map_cache: PortCacheMap = HashMap::new();
-    let port_map_file = citadel_root.join("apps").join("ports.yml");
-    let port_cache_map_file = citadel_root.join("apps").join("ports.cache.yml");
-    if port_cache_map_file.exists() {
-        let port_cache_map_file = std::fs::File::open(port_cache_map_file.clone()).unwrap();
-        let port_cache_map_file: PortCacheMap =
-            serde_yaml::from_reader(port_cache_map_file).expect("Failed to load port map!");
-        port_map_cache = port_cache_map_file;
-    }
-
-    let mut validate_port = |app: &str,
-                             container: &str,
-                             suggested_port: u16,
-                             priority: PortPriority,
-                             dynamic: bool,
-                             implements: Option<String>| {
-        let get_new_port = |app: &str, container: &str, mut suggested_port: u16| -> u16 {
-            while RESERVED_PORTS.contains(&suggested_port)
-                || port_map_cache.contains_key(&suggested_port)
-            {
-                if let Some(cache_entry) = port_map_cache.get(&suggested_port) {
-                    if cache_entry.app == app && cache_entry.container == container {
-                        return suggested_port;
-                    }
+/// Assigns IP addresses to all containers of an app and host ports to their ports
+///
+/// Returns the variables that hold the app's IP addresses.
+/// If this fails, the addresses that were newly assigned to the app are released again.
+fn allocate_app(
+    app_id: &str,
+    app_yml: &AppYml,
+    ip_map: &mut HashMap<String, String>,
+    ip_allocator: &mut IpAllocator,
+    port_allocator: &mut PortAllocator,
+) -> anyhow::Result<Vec<String>> {
+    let mut ip_vars = Vec::new();
+    let mut new_ip_vars = Vec::new();
+    // Sorted, so services get the same addresses on every conversion
+    let services: BTreeMap<&String, &Container> = app_yml.services.iter().collect();
+    let mut result = Ok(());
+    for service_name in services.keys() {
+        let ip_name = format!(
+            "APP_{}_{}_IP",
+            app_id.to_uppercase().replace('-', "_"),
+            service_name.to_uppercase().replace('-', "_")
+        );
+        ip_vars.push(ip_name.clone());
+        if ip_map.contains_key(&ip_name) {
+            continue;
+        }
+        match ip_allocator.allocate() {
+            Ok(ip) => {
+                ip_map.insert(ip_name.clone(), ip.to_string());
+                new_ip_vars.push(ip_name);
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    let result =
+        result.and_then(|_| validate_app_ports(app_id, app_yml, &services, port_allocator));
+    if let Err(err) = result {
+        for ip_name in new_ip_vars {
+            if let Some(ip) = ip_map.remove(&ip_name).and_then(|ip| ip.parse().ok()) {
+                ip_allocator.release(ip);
+            }
+        }
+        return Err(err);
+    }
+    Ok(ip_vars)
+}
+
+/// Assigns host ports to the ports of an app's containers
+fn validate_app_ports(
+    app_id: &str,
+    app_yml: &AppYml,
+    services: &BTreeMap<&String, &Container>,
+    port_allocator: &mut PortAllocator,
+) -> anyhow::Result<()> {
+    let main_container = get_main_container(app_yml).unwrap_or_else(|_| "main".to_string());
+    for (service_name, service) in services {
+        let service_name = *service_name;
+        if let Some(main_port) = service.port {
+            port_allocator.validate_port(
+                app_id,
+                service_name,
+                main_port,
+                service.port_priority.unwrap_or(PortPriority::Optional),
+                false,
+                app_yml.metadata.implements.clone(),
+            )?;
+        } else if main_container == *service_name {
+            port_allocator.validate_port(
+                app_id,
+                service_name,
+                3000,
+                PortPriority::Optional,
+                true,
+                app_yml.metadata.implements.clone(),
+            )?;
+        }
+        if let Some(web_endpoints) = &service.web_endpoints {
+            for endpoint in web_endpoints.values() {
+                port_allocator.validate_port(
+                    app_id,
+                    service_name,
+                    endpoint.port,
+                    endpoint.port_priority.unwrap_or(PortPriority::Optional),
+                    false,
+                    app_yml.metadata.implements.clone(),
+                )?;
+            }
+        }
+        if let Some(ports) = &service.required_ports {
+            if let Some(tcp_ports) = &ports.tcp {
+                for host_port in tcp_ports.keys() {
+                    port_allocator.validate_port(
+                        app_id,
+                        service_name,
+                        *host_port,
+                        PortPriority::Required,
+                        false,
+                        app_yml.metadata.implements.clone(),
+                    )?;
                 }
-                suggested_port += 1;
             }
+            if let Some(udp_ports) = &ports.udp {
+                for host_port in udp_ports.keys() {
+                    port_allocator.validate_port(
+                        app_id,
+                        service_name,
+                        *host_port,
+                        PortPriority::Required,
+                        false,
+                        app_yml.metadata.implements.clone(),
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-            suggested_port
-        };
-        if let Some(key) = port_map_cache.get(&suggested_port) {
-            if (key.app == app && key.container == container)
-                || (key.implements == implements && container == "service")
-            {
-                return;
+/// Converts all apps in the Citadel root and generates the files shared by all apps
+///
+/// Apps that fail to convert are listed in the report, their output is removed.
+/// An error is only returned if no files could be generated at all.
+pub fn convert_dir(root: &CitadelRoot, options: &ConvertOptions) -> anyhow::Result<ConvertReport> {
+    let mut report = ConvertReport::default();
+    let mut changeset = Changeset::new(root);
+    let mut only_app = options.app.as_deref();
+    let mut converted_apps = ConvertedApps::new();
+    let env_vars = root.env_vars();
+    let output_backend = get_backend(
+        env_vars
+            .get("APP_OUTPUT_BACKEND")
+            .map_or(DEFAULT_BACKEND, |backend| backend.as_str()),
+    )
+    .context("Invalid APP_OUTPUT_BACKEND")?;
+    let previous_backend = root
+        .read(&root.output_backend_file())
+        .context("Error reading the previous output backend")?;
+    let previous_backend = previous_backend
+        .as_deref()
+        .unwrap_or(DEFAULT_BACKEND)
+        .trim();
+    if let Some(app_id) = only_app {
+        if app_id.is_empty() || app_id.starts_with('.') || app_id.contains('/') {
+            bail!("Invalid app id {}", app_id);
+        }
+        match load_converted_apps(root) {
+            // The other apps' output has to be moved to the new backend too
+            Ok(_) if previous_backend != output_backend.name() => {
+                eprintln!("Warning: Converting all apps, because the output backend changed");
+                only_app = None;
             }
-            if key.priority > priority {
-                // Move the existing app to a new port
-                let new_port = get_new_port(&key.app, &key.container, suggested_port);
-                let new_port_map = port_map_cache.remove(&suggested_port).unwrap();
-                port_map_cache.insert(new_port, new_port_map);
-                // And insert the new app
-                port_map_cache.insert(
-                    suggested_port,
-                    PortCacheMapEntry {
-                        app: app.to_string(),
-                        internal_port: suggested_port,
-                        container: container.to_string(),
-                        dynamic,
-                        implements,
-                        priority,
-                    },
+            // Results saved by older versions do not contain the apps' variables
+            Ok(apps) if apps.values().any(|app| app.env.is_empty()) => {
+                eprintln!(
+                    "Warning: Converting all apps, because the previous results are incomplete"
                 );
-            } else {
-                // Move the new app to a new port
-                let new_port = get_new_port(app, container, suggested_port);
-                port_map_cache.insert(
-                    new_port,
-                    PortCacheMapEntry {
-                        app: app.to_string(),
-                        internal_port: if dynamic { new_port } else { suggested_port },
-                        container: container.to_string(),
-                        dynamic,
-                        implements,
-                        priority,
-                    },
+                only_app = None;
+            }
+            Ok(apps) => converted_apps = apps,
+            Err(err) => {
+                eprintln!(
+                    "Warning: Converting all apps, because the previous results could not be loaded: {}",
+                    err
                 );
+                only_app = None;
             }
-        } else {
-            port_map_cache.insert(
-                suggested_port,
-                PortCacheMapEntry {
-                    app: app.to_string(),
-                    internal_port: suggested_port,
-                    container: container.to_string(),
-                    dynamic,
-                    implements,
-                    priority,
-                },
+        }
+    }
+    // An app that was removed is still converted, so its output is deleted
+    let app_ids = match only_app {
+        Some(app_id) => vec![app_id.to_string()],
+        None => root.list_apps()?,
+    };
+
+    let services = match &options.installed_apps {
+        Some(installed_apps) => {
+            changeset.write(
+                root.user_json(),
+                root.user_json_with_installed_apps(installed_apps)?,
             );
+            root.services_with(installed_apps.clone())
         }
+        None => root.installed_services(),
     };
+    let citadel_seed = root.citadel_seed()?;
+    let config = root.config();
+    let subnet = config.subnet(&env_vars)?;
+    let mut ip_allocator = IpAllocator::new(subnet, reserved_ips_from_env(&env_vars));
+    let node_config = node_config_from_env(&env_vars)?;
+    let tor_instances = config.tor_instances(&env_vars)?;
+
+    let mut ip_map: HashMap<String, String> = root
+        .load_yaml(&root.ips_file())
+        .context("Failed to load IP address map")?
+        .unwrap_or_default();
+    let mut port_map_cache: PortCacheMap = root
+        .load_yaml(&root.port_cache_file())
+        .context("Failed to load port map")?
+        .unwrap_or_default();
+    let removed_app = options.app.as_deref().filter(|_| options.remove);
+    if let (Some(app_id), true) = (options.app.as_deref(), options.reset_allocations) {
+        // If all apps are converted, the app's addresses are only listed in the previous results
+        let previous_app = match only_app {
+            Some(_) => converted_apps.get(app_id).cloned(),
+            None => load_converted_apps(root)
+                .ok()
+                .and_then(|mut apps| apps.remove(app_id)),
+        };
+        if let Some(app) = previous_app {
+            ip_map.retain(|key, _| !app.env.contains_key(key));
+        }
+        port_map_cache.retain(|_, entry| entry.app != app_id);
+    }
+    // When converting a single app, the other apps keep their addresses
+    let selected_app = only_app.and_then(|app_id| converted_apps.get(app_id));
+    ip_allocator
+        .reconcile(&mut ip_map, |key| {
+            only_app.is_none() || selected_app.is_some_and(|app| app.env.contains_key(key))
+        })
+        .context("Failed to assign IP addresses")?;
+    let mut port_allocator = PortAllocator::new(port_map_cache, options.probe_ports);
+    port_allocator.set_reserved_ports(&config.reserved_ports);
+    if let Some(app_id) = only_app {
+        port_allocator.keep_other_apps(app_id);
+    }
 
     if citadel_seed.is_none() {
         eprintln!("Warning: Citadel does not seem to be set up yet!");
     }
 
-    preprocessing::preprocess_apps(citadel_root, &citadel_root.join("apps"));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .context("Error starting conversion threads")?;
 
-    for app in apps {
-        let app = app.expect("Error reading app directory!");
-        let app_id = app.file_name();
-        let app_id = app_id.to_str().unwrap();
-        let app_yml = app.path().join("app.yml");
-        let app_yml = std::fs::File::open(app_yml).expect("Failed to open app.yml file!");
-        let app_yml = load_config_as_v4(app_yml, &Some(&services.to_vec()));
-        let Ok(app_yml) = app_yml else {
-            eprintln!("Error processing app.yml: {}", app_yml.unwrap_err());
+    // The rendered templates are part of the changes, so a dry run lists them,
+    // their contents are not shown, because templates can use secrets derived from the seed
+    let preprocessed = pool.install(|| {
+        preprocessing::preprocess_apps(root, root.fs(), &root.apps_dir(), &services, only_app)
+    })?;
+    report.failed = preprocessed.failed;
+    for (path, contents) in preprocessed.files {
+        if removed_app.is_some_and(|app_id| path.starts_with(root.app_dir(app_id))) {
             continue;
-        };
+        }
+        changeset.write_secret(path, contents);
+    }
+    if let Some(app_id) = removed_app {
+        report.failed.remove(app_id);
+    }
 
-        //Part 2: IP & Port assignment
-        {
-            let main_container =
-                get_main_container(&app_yml).unwrap_or_else(|_| "main".to_string());
-            for (service_name, service) in app_yml.services {
-                let ip_name = format!(
-                    "APP_{}_{}_IP",
-                    app_id.to_uppercase().replace('-', "_"),
-                    service_name.to_uppercase().replace('-', "_")
-                );
-                if let std::collections::hash_map::Entry::Vacant(e) = ip_map.entry(ip_name) {
-                    if current_suffix == 255 {
-                        panic!("Too many apps!");
-                    }
-                    let ip = "10.21.21.".to_owned() + current_suffix.to_string().as_str();
-                    e.insert(ip);
-                    current_suffix += 1;
-                }
-                if let Some(main_port) = service.port {
-                    validate_port(
-                        app_id,
-                        &service_name,
-                        main_port,
-                        service.port_priority.unwrap_or(PortPriority::Optional),
-                        false,
-                        app_yml.metadata.implements.clone(),
-                    );
-                } else if main_container == service_name {
-                    validate_port(
-                        app_id,
-                        &service_name,
-                        3000,
-                        PortPriority::Optional,
-                        true,
-                        app_yml.metadata.implements.clone(),
-                    );
-                }
-                if let Some(ports) = service.required_ports {
-                    if let Some(tcp_ports) = ports.tcp {
-                        for (host_port, _) in tcp_ports {
-                            validate_port(
-                                app_id,
-                                &service_name,
-                                host_port,
-                                PortPriority::Required,
-                                false,
-                                app_yml.metadata.implements.clone(),
-                            );
-                        }
-                    }
-                    if let Some(udp_ports) = ports.udp {
-                        for (host_port, _) in udp_ports {
-                            validate_port(
-                                app_id,
-                                &service_name,
-                                host_port,
-                                PortPriority::Required,
-                                false,
-                                app_yml.metadata.implements.clone(),
-                            );
-                        }
-                    }
-                }
+    // Part 1: Load all apps in parallel
+    let loaded_apps: Vec<(String, Option<anyhow::Result<AppYml>>)> = pool.install(|| {
+        app_ids
+            .par_iter()
+            .filter(|app_id| {
+                !report.failed.contains_key(*app_id) && removed_app != Some(app_id.as_str())
+            })
+            .map(|app_id| {
+                let app_yml = changeset.read(&root.app_yml(app_id));
+                (app_id.clone(), parse_app(app_yml, &services))
+            })
+            .collect()
+    });
+    let mut apps = Vec::new();
+    for (app_id, app_yml) in loaded_apps {
+        match app_yml {
+            Some(Ok(app_yml)) => apps.push((app_id, app_yml)),
+            Some(Err(err)) => {
+                report
+                    .failed
+                    .insert(app_id, format!("Error processing app.yml: {}", err));
+            }
+            None => {
+                report
+                    .skipped
+                    .insert(app_id, "app.yml does not exist".to_string());
             }
         }
     }
-    // Part 3: Convert port cache map to port map
-    for (port_number, cache_entry) in port_map_cache.clone() {
-        let mut key = cache_entry.app;
-        if cache_entry.implements.is_some() && cache_entry.container == "service" {
-            key = cache_entry.implements.unwrap();
-        }
-        if !port_map.contains_key(&key) {
-            port_map.insert(key.clone(), HashMap::new());
+
+    // Part 2: IP & Port assignment, in a fixed order so the result does not depend on timing
+    let mut app_ip_vars: HashMap<String, Vec<String>> = HashMap::new();
+    for (app_id, app_yml) in &apps {
+        match allocate_app(
+            app_id,
+            app_yml,
+            &mut ip_map,
+            &mut ip_allocator,
+            &mut port_allocator,
+        ) {
+            Ok(ip_vars) => {
+                app_ip_vars.insert(app_id.clone(), ip_vars);
+            }
+            Err(err) => {
+                report.failed.insert(app_id.clone(), err.to_string());
+            }
         }
-        let app_port_map = port_map.get_mut(&key).unwrap();
-        if !app_port_map.contains_key(&cache_entry.container) {
-            app_port_map.insert(cache_entry.container.clone(), Vec::new());
+    }
+    // Part 3: Log the allocation trace and convert port cache map to port map
+    for trace in port_allocator.trace() {
+        if trace.skipped.is_empty() {
+            tracing::debug!("Port allocation: {}", trace);
+        } else {
+            tracing::info!("Port allocation: {}", trace);
         }
-        let service_port_map = app_port_map.get_mut(&cache_entry.container).unwrap();
-        service_port_map.push(PortMapElement {
-            dynamic: cache_entry.dynamic,
-            internal_port: cache_entry.internal_port,
-            public_port: port_number,
-        });
     }
+    let port_map = port_allocator.port_map();
     // Part 4: Write port map to file
     {
-        let mut port_map_file =
-            std::fs::File::create(port_map_file).expect("Error opening port map file!");
-        port_map_file
-            .write_all(serde_yaml::to_string(&port_map).unwrap().as_bytes())
-            .expect("Error writing port map file!");
-        let mut port_cache_map_file =
-            std::fs::File::create(port_cache_map_file).expect("Error opening port cache map file!");
-        port_cache_map_file
-            .write_all(serde_yaml::to_string(&port_map_cache).unwrap().as_bytes())
-            .expect("Error writing port cache map file!");
-        let ip_map_file =
-            std::fs::File::create(ip_addresses_map_file).expect("Error opening ip map file!");
-        serde_yaml::to_writer(ip_map_file, &ip_map).expect("Error writing ip map file!");
-    }
-
-    // Part 5: Save IP addresses
-    {
-        let mut env_string = String::new();
-        // Load the existing env file
-        if let Ok(mut env_file) = std::fs::File::open(citadel_root.join(".env")) {
-            env_file
-                .read_to_string(&mut env_string)
-                .expect("Error reading env file!");
+        // Sort everything, so entries of apps that did not change stay the same
+        let sorted_port_map: BTreeMap<&String, BTreeMap<&String, &Vec<PortMapElement>>> = port_map
+            .iter()
+            .map(|(app, containers)| (app, containers.iter().collect()))
+            .collect();
+        changeset.write(root.ports_file(), serde_yaml::to_string(&sorted_port_map)?);
+        let sorted_port_cache: BTreeMap<&u16, &PortCacheMapEntry> =
+            port_allocator.cache().iter().collect();
+        changeset.write(
+            root.port_cache_file(),
+            serde_yaml::to_string(&sorted_port_cache)?,
+        );
+        let sorted_ip_map: BTreeMap<&String, &String> = ip_map.iter().collect();
+        changeset.write(root.ips_file(), serde_yaml::to_string(&sorted_ip_map)?);
+    }
+
+    // Part 5: Loop through the appps again and run the actual conversion process
+    // Containers on the host network are reached through the gateway
+    let mut conversion_ips = ip_map.clone();
+    conversion_ips.insert("GATEWAY_IP".to_string(), subnet.gateway().to_string());
+    let authorized_clients = load_authorized_clients(root).unwrap_or_else(|err| {
+        eprintln!("Error loading authorized Tor clients: {}", err);
+        Default::default()
+    });
+    let mut output_env = env_vars.clone();
+    output_env.extend(conversion_ips.clone());
+    output_env.insert("APPS_SUBNET".to_string(), subnet.to_string());
+    // Converting and rendering the apps does not depend on other apps, so it runs in parallel
+    let port_map = Some(port_map);
+    let installed_services = Some(services.clone());
+    let conversion_ips = Some(conversion_ips);
+    let mut results: HashMap<String, Result<(ResultYml, OutputFiles), String>> =
+        pool.install(|| {
+            apps.into_par_iter()
+                .filter(|(app_id, _)| !report.failed.contains_key(app_id))
+                .map(|(app_id, app_yml)| {
+                    let result = convert_config_with(
+                        &app_id,
+                        app_yml,
+                        &port_map,
+                        &installed_services,
+                        &conversion_ips,
+                        &node_config,
+                    )
+                    .map_err(|err| format!("Error converting app.yml: {}", err))
+                    .and_then(|result_data| {
+                        let app_env = app_env_vars(
+                            root,
+                            &app_id,
+                            &result_data.hidden_services,
+                            &output_env,
+                            citadel_seed.as_deref(),
+                        );
+                        let output_files = output_backend
+                            .render(&app_id, &result_data, &app_env)
+                            .map_err(|err| {
+                            format!("Error generating {} output: {}", output_backend.name(), err)
+                        })?;
+                        Ok((result_data, output_files))
+                    });
+                    (app_id, result)
+                })
+                .collect()
+        });
+    let output_dir = output_dir(root, output_backend.as_ref());
+    // Units left behind after switching from quadlet to another backend are removed as well,
+    // the units directory is not touched if quadlet was never used
+    let quadlet_used = previous_backend == QuadletBackend.name();
+    let mut output_dirs = vec![root.apps_dir()];
+    if output_backend.name() == QuadletBackend.name() || quadlet_used {
+        output_dirs.push(root.units_dir());
+    }
+    let remove_app_output = |app_id: &str, keep: &OutputFiles, changeset: &mut Changeset| {
+        for dir in &output_dirs {
+            let keep = if *dir == output_dir {
+                keep
+            } else {
+                &OutputFiles::new()
+            };
+            remove_output_files(root, &dir.join(app_id), keep, changeset);
         }
-        for (key, value) in &ip_map {
-            let to_append = format!("{}={}", key, value);
-            if !env_string.contains(&to_append) {
-                env_string.push_str(&(to_append + "\n"));
+    };
+    for app_id in &app_ids {
+        let app_id = app_id.as_str();
+        converted_apps.remove(app_id);
+        // Apps that were removed, skipped or failed before the conversion do not have a result
+        let Some(result) = results.remove(app_id) else {
+            remove_app_output(app_id, &OutputFiles::new(), &mut changeset);
+            continue;
+        };
+        let (result_data, output_files) = match result {
+            Ok(result) => result,
+            Err(reason) => {
+                remove_app_output(app_id, &OutputFiles::new(), &mut changeset);
+                report.failed.insert(app_id.to_string(), reason);
+                continue;
             }
-        }
-        let mut env_file =
-            std::fs::File::create(citadel_root.join(".env")).expect("Error opening env file!");
-        env_file
-            .write_all(env_string.as_bytes())
-            .expect("Error writing env file!");
-    }
-
-    // Part 6: Loop through the appps again and run the actual conversion process
-    let apps = std::fs::read_dir(citadel_root.join("apps")).expect("Error reading apps directory!");
-    let mut app_registry: Vec<OutputMetadata> = Vec::new();
-    let mut virtual_apps: HashMap<String, Vec<String>> = HashMap::new();
-
-    let mut tor_entries: Vec<String> = Vec::new();
-    let mut i2p_entries: Vec<String> = Vec::new();
-    for app in apps {
-        let app = app.expect("Error reading app directory!");
-        let app_id = app.file_name();
-        let app_id = app_id.to_str().unwrap();
-        let app_yml_path = app.path().join("app.yml");
-        let docker_compose_yml_path = app.path().join("docker-compose.yml");
-        // Skip if app.yml does not exist
-        if !app_yml_path.exists() {
-            // Delete docker-compose.yml if it exists
-            if docker_compose_yml_path.exists() {
-                std::fs::remove_file(docker_compose_yml_path)
-                    .expect("Error deleting docker-compose.yml!");
+        };
+        for (file_name, contents) in &output_files {
+            let path = output_dir.join(app_id).join(file_name);
+            if output_backend.is_private_file(file_name) {
+                changeset.write_private(path, contents.clone());
+            } else if output_backend.contains_secrets(file_name) {
+                changeset.write_secret(path, contents.clone());
+            } else {
+                changeset.write(path, contents.clone());
             }
-            continue;
         }
-        let app_yml = std::fs::File::open(app_yml_path).expect("Error opening app.yml!");
-        let conversion_result = convert_config(
-            app_id,
-            app_yml,
-            &Some(port_map.clone()),
-            &Some(services.clone()),
-            &Some(ip_map.clone()),
-        );
-        if let Ok(result_data) = conversion_result {
-            let mut docker_compose_yml_file = std::fs::File::create(docker_compose_yml_path)
-                .expect("Error opening docker-compose.yml!");
-            serde_yaml::to_writer(&mut docker_compose_yml_file, &result_data.spec)
-                .expect("Error writing docker-compose.yml!");
-            tor_entries.push(result_data.new_tor_entries + "\n");
-            i2p_entries.push(result_data.new_i2p_entries + "\n");
-            let mut metadata = result_data.metadata;
-            if metadata.default_password.clone().unwrap_or_default() == "$APP_SEED" {
-                if let Some(ref citadel_seed) = citadel_seed {
-                    metadata.default_password = Some(derive_entropy(
-                        citadel_seed,
-                        format!("app-{}-seed", app_id).as_str(),
-                    ));
-                } else {
-                    metadata.default_password = Some("Please reboot your node, default password does not seem to be available yet.".to_string());
-                }
+        remove_app_output(app_id, &output_files, &mut changeset);
+        let mut metadata = result_data.metadata;
+        let tor_instance = assign_tor_instance(app_id, metadata.tor_instance, tor_instances);
+        metadata.tor_instance = Some(tor_instance);
+        let mut hidden_services: Vec<HiddenService> = Vec::new();
+        for mut hidden_service in result_data.hidden_services {
+            for port in hidden_service.remove_duplicate_ports() {
+                tracing::warn!(
+                    "Skipping port {} -> {} of hidden service {} of app {}, the port is already used",
+                    port.virtual_port,
+                    port.target_port,
+                    hidden_service.dir,
+                    app_id
+                );
+            }
+            if let Err(err) = hidden_service.validate() {
+                tracing::warn!("Skipping hidden service of app {}: {}", app_id, err);
+                continue;
             }
-            if let Some(ref implements) = metadata.implements {
-                if let std::collections::hash_map::Entry::Vacant(entry) =
-                    virtual_apps.entry(implements.clone())
-                {
-                    entry.insert(vec![app_id.to_string()]);
-                } else {
-                    virtual_apps
-                        .get_mut(implements)
-                        .unwrap()
-                        .push(app_id.to_string());
+            if converted_apps
+                .values()
+                .flat_map(|app| &app.hidden_services)
+                .chain(&hidden_services)
+                .any(|other| other.dir == hidden_service.dir)
+            {
+                tracing::warn!(
+                    "Skipping hidden service {} of app {}, it is already used by another app",
+                    hidden_service.dir,
+                    app_id
+                );
+                continue;
+            }
+            let clients = if hidden_service.client_auth {
+                authorized_clients.get(app_id).cloned().unwrap_or_default()
+            } else {
+                BTreeMap::new()
+            };
+            // Without any keys, Tor would allow everyone to connect
+            if hidden_service.client_auth && clients.is_empty() {
+                tracing::warn!(
+                    "Skipping hidden service {} of app {}, it only allows authorized clients, but none were added",
+                    hidden_service.dir,
+                    app_id
+                );
+                continue;
+            }
+            let service_dir = root.hidden_service_dir(&hidden_service.dir);
+            if let Err(err) = sync_authorized_clients(&mut changeset, &service_dir, &clients) {
+                tracing::warn!(
+                    "Error updating authorized clients of hidden service {}: {}",
+                    hidden_service.dir,
+                    err
+                );
+                if hidden_service.client_auth {
+                    continue;
                 }
             }
-            app_registry.push(metadata);
-        } else {
-            // Delete docker-compose.yml if it exists
-            if docker_compose_yml_path.exists() {
-                std::fs::remove_file(docker_compose_yml_path)
-                    .expect("Error deleting docker-compose.yml!");
+            // Random keys would differ from the ones created later, so a dry run does not create any
+            let address = if options.dry_run {
+                expected_onion_address(
+                    root.fs(),
+                    &service_dir,
+                    &hidden_service.dir,
+                    citadel_seed.as_deref(),
+                )
+                .unwrap_or_default()
+            } else {
+                create_hidden_service_keys(
+                    &mut changeset,
+                    &service_dir,
+                    &hidden_service.dir,
+                    &citadel_seed,
+                )
+            };
+            if let Some(address) = address {
+                metadata
+                    .hidden_service_addresses
+                    .insert(hidden_service.dir.clone(), address);
             }
-            eprintln!(
-                "Error converting app.yml for app {}: {}",
-                app_id,
-                conversion_result.unwrap_err()
-            );
+            hidden_services.push(hidden_service);
         }
+        // Services that were skipped are not created, so they must not be listed either
+        metadata
+            .hidden_services
+            .retain(|dir| hidden_services.iter().any(|service| service.dir == *dir));
+        // Tor-only apps must not be reachable over clearnet
+        let proxy_route = (!metadata.tor_only && !result_data.proxy_route.host.starts_with('<'))
+            .then_some(result_data.proxy_route);
+        // Ports Tor does not expose must not be reachable over I2P either
+        let mut i2p_tunnels: Vec<I2pTunnel> = Vec::new();
+        for tunnel in public_tunnels(result_data.i2p_tunnels, &hidden_services) {
+            if !converted_apps
+                .values()
+                .flat_map(|app| &app.i2p_tunnels)
+                .chain(&i2p_tunnels)
+                .any(|other| other.name == tunnel.name)
+            {
+                i2p_tunnels.push(tunnel);
+            } else {
+                tracing::warn!(
+                    "Skipping I2P tunnel {} of app {}, it is already used by another app",
+                    tunnel.name,
+                    app_id
+                );
+            }
+        }
+        if metadata.default_password.clone().unwrap_or_default() == "$APP_SEED" {
+            if let Some(ref citadel_seed) = citadel_seed {
+                metadata.default_password = Some(derive_entropy(
+                    citadel_seed,
+                    format!("app-{}-seed", app_id).as_str(),
+                ));
+            } else {
+                metadata.default_password = Some(
+                    "Please reboot your node, default password does not seem to be available yet."
+                        .to_string(),
+                );
+            }
+        }
+        let mut env: BTreeMap<String, String> = app_ip_vars
+            .remove(app_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|key| ip_map.get(&key).map(|ip| (key, ip.clone())))
+            .collect();
+        env.insert(
+            format!("APP_{}_PORT", app_id.to_uppercase().replace('-', "_")),
+            metadata.port.to_string(),
+        );
+        converted_apps.insert(
+            app_id.to_string(),
+            ConvertedApp {
+                metadata,
+                hidden_services,
+                i2p_tunnels,
+                proxy_route,
+                env,
+            },
+        );
+        report.succeeded.push(app_id.to_string());
     }
 
-    // Part 7: Save registry & virtual apps
+    // Part 6: Save registry & virtual apps
     {
-        let app_registry_file = citadel_root.join("apps").join("registry.json");
-        let mut app_registry_file =
-            std::fs::File::create(app_registry_file).expect("Error opening registry.json!");
-        serde_json::to_writer(&mut app_registry_file, &app_registry)
-            .expect("Error writing registry.json!");
-        let virtual_apps_file = citadel_root.join("apps").join("virtual-apps.json");
-        let mut virtual_apps_file =
-            std::fs::File::create(virtual_apps_file).expect("Error opening virtual-apps.json!");
-        serde_json::to_writer(&mut virtual_apps_file, &virtual_apps)
-            .expect("Error writing virtual-apps.json!");
-
-        let tor_entries_file = citadel_root.join("tor").join("torrc-apps");
-        let tor_entries_file_2 = citadel_root.join("tor").join("torrc-apps-2");
-        let tor_entries_file_3 = citadel_root.join("tor").join("torrc-apps-3");
-        let mut tor_entries_file =
-            std::fs::File::create(tor_entries_file).expect("Error opening torrc-apps!");
-        let mut tor_entries_file_2 =
-            std::fs::File::create(tor_entries_file_2).expect("Error opening torrc-apps-2!");
-        let mut tor_entries_file_3 =
-            std::fs::File::create(tor_entries_file_3).expect("Error opening torrc-apps-3!");
-        // Split entries into 3 groups of the same size
-        let mut current_file = 1;
-
-        for entry in tor_entries {
-            if current_file == 1 {
-                tor_entries_file
-                    .write_all(entry.as_bytes())
-                    .expect("Error writing torrc-apps!");
-                current_file = 2;
-            } else if current_file == 2 {
-                tor_entries_file_2
-                    .write_all(entry.as_bytes())
-                    .expect("Error writing torrc-apps-2!");
-                current_file = 3;
-            } else if current_file == 3 {
-                tor_entries_file_3
-                    .write_all(entry.as_bytes())
-                    .expect("Error writing torrc-apps-3!");
-                current_file = 1;
+        changeset.write(
+            root.converted_apps_file(),
+            serde_json::to_string(&converted_apps)?,
+        );
+
+        let app_registry: Vec<&OutputMetadata> =
+            converted_apps.values().map(|app| &app.metadata).collect();
+        let mut virtual_apps: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+        for (app_id, app) in &converted_apps {
+            if let Some(implements) = &app.metadata.implements {
+                virtual_apps.entry(implements).or_default().push(app_id);
             }
         }
-        let i2p_entries_dir = citadel_root.join("i2p").join("tunnels.d");
-        std::fs::create_dir_all(i2p_entries_dir.clone())
-            .expect("Error creating i2p tunnels.d directory!");
-        let i2p_entries_file = i2p_entries_dir.join("apps.conf");
-        let mut i2p_entries_file =
-            std::fs::File::create(i2p_entries_file).expect("Error opening apps.conf!");
-        i2p_entries_file
-            .write_all(i2p_entries.join("\n").as_bytes())
-            .expect("Error writing apps.conf!");
+        changeset.write(root.registry_file(), serde_json::to_string(&app_registry)?);
+        changeset.write(
+            root.virtual_apps_file(),
+            serde_json::to_string(&virtual_apps)?,
+        );
+
+        for (app_id, app) in &converted_apps {
+            if app
+                .metadata
+                .tor_instance
+                .is_some_and(|instance| instance > tor_instances)
+            {
+                tracing::warn!(
+                    "Hidden services of {} use a Tor instance that does not exist anymore, convert all apps to reassign them",
+                    app_id
+                );
+            }
+        }
+        // Write every instance's file, so instances without apps do not keep old entries
+        for instance in 1..=tor_instances {
+            let tor_entries: Vec<HiddenService> = converted_apps
+                .values()
+                .filter(|app| app.metadata.tor_instance == Some(instance))
+                .flat_map(|app| app.hidden_services.clone())
+                .collect();
+            changeset.write(root.torrc_file(instance), render_torrc(&tor_entries));
+        }
+        let proxy_routes: Vec<ProxyRoute> = converted_apps
+            .values()
+            .filter_map(|app| app.proxy_route.clone())
+            .collect();
+        let caddy_file = root.caddy_file();
+        let nginx_file = root.nginx_file();
+        if let Some(domain) = env_vars.get("APP_DOMAIN") {
+            let routing: ProxyRouting = match env_vars.get("APP_PROXY_ROUTING") {
+                Some(routing) => routing.parse().context("Invalid APP_PROXY_ROUTING")?,
+                None => ProxyRouting::default(),
+            };
+            changeset.write(caddy_file, render_caddy(&proxy_routes, domain, routing));
+            changeset.write(nginx_file, render_nginx(&proxy_routes, domain, routing));
+        } else {
+            // Without a domain, apps should not stay reachable through an old config
+            changeset.delete(caddy_file);
+            changeset.delete(nginx_file);
+        }
+
+        let i2p_entries: Vec<I2pTunnel> = converted_apps
+            .values()
+            .flat_map(|app| app.i2p_tunnels.clone())
+            .collect();
+        changeset.write(root.i2p_tunnels_file(), render_tunnels(&i2p_entries));
+    }
+
+    // Part 7: Save the files all apps share
+    {
+        let shared_files = output_backend
+            .render_shared(&output_env)
+            .with_context(|| format!("Error generating {} output", output_backend.name()))?;
+        for (file_name, contents) in &shared_files {
+            changeset.write(output_dir.join(file_name), contents.clone());
+        }
+        if output_dir != root.units_dir() && quadlet_used {
+            remove_output_files(root, &root.units_dir(), &OutputFiles::new(), &mut changeset);
+        }
+        changeset.write(root.output_backend_file(), output_backend.name());
+    }
+
+    // Part 8: Save the variables of all apps to .env
+    {
+        let env_string = root
+            .read(&root.env_file())
+            .context("Error reading env file")?
+            .unwrap_or_default();
+        let mut env_file = EnvFile::parse(&env_string);
+        let managed = converted_apps
+            .values()
+            .flat_map(|app| app.env.clone())
+            .collect();
+        // Older versions appended IP addresses to the end of the file
+        env_file.set_managed(managed, |key| ip_map.contains_key(key));
+        changeset.write(root.env_file(), env_file.render());
+    }
+
+    if let (Some(app_id), Some(_)) = (options.app.as_deref(), &options.installed_apps) {
+        if let Some(reason) = report.failed.get(app_id) {
+            bail!("Failed to convert app {}: {}", app_id, reason);
+        }
+    }
+    if options.dry_run {
+        report.changed = !changeset.changed_files().is_empty();
+        report.diff = Some(changeset.diff());
+    } else {
+        report.changed = changeset.apply().context("Error writing files")?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{
+        allocate_app, app_env_vars, convert_dir, fs::MemoryFilesystem, ips::IpAllocator,
+        ports::PortAllocator, root::CitadelRoot, tor::expected_onion_address, ConvertOptions,
+    };
+    use crate::composegenerator::{
+        tor::HiddenService,
+        v4::{types::AppYml, utils::app_seeds},
+    };
+
+    #[test]
+    fn reports_failed_apps() {
+        let root = CitadelRoot::new("/citadel").with_filesystem(Arc::new(MemoryFilesystem::new()));
+        root.fs().create_dir_all(&root.app_dir("empty")).unwrap();
+        root.save(
+            &root.app_yml("good"),
+            "citadel_version: 4
+metadata:
+  name: Good
+  version: 1.0.0
+  category: Test
+  tagline: A working app
+  developers: {Citadel: https://runcitadel.space}
+  permissions: []
+  repo: {Public: https://github.com/runcitadel/apps}
+  support: https://runcitadel.space
+  description: A working app
+services:
+  main:
+    image: ghcr.io/runcitadel/example:main
+    port: 3000
+",
+        )
+        .unwrap();
+        root.save(&root.app_yml("broken"), "citadel_version: 4\n")
+            .unwrap();
+
+        let report = convert_dir(&root, &ConvertOptions::default()).unwrap();
+        assert_eq!(report.succeeded, ["good"]);
+        assert_eq!(report.skipped.keys().collect::<Vec<_>>(), ["empty"]);
+        assert_eq!(report.failed.keys().collect::<Vec<_>>(), ["broken"]);
+        assert!(report.changed);
+        let fs = root.fs();
+        assert!(fs.exists(&root.app_dir("good").join("docker-compose.yml")));
+        assert!(!fs.exists(&root.app_dir("broken").join("docker-compose.yml")));
+        let env_file = fs.read_to_string(&root.path().join(".env")).unwrap();
+        assert!(env_file.contains("\nAPP_GOOD_MAIN_IP=10.21.21.20\nAPP_GOOD_PORT=3000\n"));
+        assert!(!env_file.contains("APP_BROKEN"));
+
+        let options = ConvertOptions {
+            app: Some("../good".to_string()),
+            ..Default::default()
+        };
+        assert!(convert_dir(&root, &options).is_err());
+    }
+
+    #[test]
+    fn resolves_runtime_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        let hidden_services = [
+            HiddenService::new("app-my-app".to_string()),
+            HiddenService::new("app-my-app-rpc".to_string()),
+        ];
+        let env = HashMap::new();
+
+        let app_env = app_env_vars(&root, "my_app", &hidden_services, &env, Some("seed"));
+        let expected = |dir: &str| {
+            expected_onion_address(root.fs(), &root.hidden_service_dir(dir), dir, Some("seed"))
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(app_env["APP_HIDDEN_SERVICE"], expected("app-my-app"));
+        assert_eq!(
+            app_env["APP_HIDDEN_SERVICE_RPC"],
+            expected("app-my-app-rpc")
+        );
+        assert_eq!(app_env["APP_DOMAIN"], "");
+        assert_eq!(app_env["APP_SEED"].len(), 64);
+        assert!(app_env.contains_key("APP_SEED_5"));
+
+        // Without the seed, neither the seeds nor the addresses are known yet
+        let app_env = app_env_vars(&root, "my_app", &hidden_services, &env, None);
+        assert_eq!(app_env["APP_HIDDEN_SERVICE"], "");
+        assert!(!app_env.contains_key("APP_SEED"));
+    }
+
+    #[test]
+    fn installs_quadlet_units() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        root.save(
+            &root.app_yml("example"),
+            "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Test
+  tagline: A test app
+  developers: {Citadel: https://runcitadel.space}
+  permissions: []
+  repo: {Public: https://github.com/runcitadel/apps}
+  support: https://runcitadel.space
+  description: A test app
+services:
+  main:
+    image: ghcr.io/runcitadel/example:main
+    port: 3000
+",
+        )
+        .unwrap();
+        // Units that were not generated by app-cli are left alone if quadlet was never used
+        let units_dir = dir.path().join("units");
+        let unit = units_dir.join("example").join("example-main.container");
+        root.save(&unit, "[Container]\n").unwrap();
+        convert_dir(&root, &ConvertOptions::default()).unwrap();
+        assert_eq!(root.read(&unit).unwrap().as_deref(), Some("[Container]\n"));
+
+        root.save(&root.env_file(), "APP_OUTPUT_BACKEND=quadlet\n")
+            .unwrap();
+        convert_dir(&root, &ConvertOptions::default()).unwrap();
+        assert!(units_dir.join("citadel.network").exists());
+        assert!(units_dir
+            .join("example")
+            .join("example-main.container")
+            .exists());
+        assert!(!root.app_dir("example").join("docker-compose.yml").exists());
+
+        // Switching back to Docker Compose removes the units
+        root.save(&root.env_file(), "").unwrap();
+        convert_dir(&root, &ConvertOptions::default()).unwrap();
+        assert!(!units_dir.join("citadel.network").exists());
+        assert!(!units_dir
+            .join("example")
+            .join("example-main.container")
+            .exists());
+        assert!(root.app_dir("example").join("docker-compose.yml").exists());
+    }
+
+    #[test]
+    fn releases_addresses_of_failed_apps() {
+        let app_yml: AppYml = serde_yaml::from_str(
+            "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Test
+  tagline: A test app
+  developers: {Citadel: https://runcitadel.space}
+  permissions: []
+  repo: {Public: https://github.com/runcitadel/apps}
+  support: https://runcitadel.space
+  description: A test app
+services:
+  main:
+    image: ghcr.io/runcitadel/example:main
+    port: 3000
+  db:
+    image: ghcr.io/runcitadel/example-db:main
+",
+        )
+        .unwrap();
+        // Only one address is left, so the second container does not get one
+        let reserved = (21..31).map(|host| [10, 21, 21, host].into());
+        let mut ip_allocator = IpAllocator::new("10.21.21.0/27".parse().unwrap(), reserved);
+        let mut port_allocator = PortAllocator::new(Default::default(), false);
+        let mut ip_map = HashMap::new();
+
+        assert!(allocate_app(
+            "example",
+            &app_yml,
+            &mut ip_map,
+            &mut ip_allocator,
+            &mut port_allocator,
+        )
+        .is_err());
+        assert!(ip_map.is_empty());
+        assert_eq!(ip_allocator.allocate().unwrap().to_string(), "10.21.21.20");
+    }
+
+    #[test]
+    fn renders_templates_in_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        root.save(&root.seed_file(), "seed").unwrap();
+        root.save(
+            &root.app_dir("example").join("app.yml.jinja"),
+            "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Test
+  tagline: The {{ app_name }} app
+  developers: {Citadel: https://runcitadel.space}
+  permissions: []
+  repo: {Public: https://github.com/runcitadel/apps}
+  support: https://runcitadel.space
+  description: A test app
+services:
+  main:
+    image: ghcr.io/runcitadel/example:main
+    port: 3000
+",
+        )
+        .unwrap();
+        root.save(
+            &root.app_dir("example").join("config.toml.jinja"),
+            "version = \"{{ APP_VERSION }}\"\npassword = \"{{ APP_SEED }}\"\n",
+        )
+        .unwrap();
+
+        let options = ConvertOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = convert_dir(&root, &options).unwrap();
+        assert_eq!(report.succeeded, ["example"]);
+        let diff = report.diff.unwrap();
+        // Templates can contain secrets, so only the rendered files are listed
+        assert!(diff.contains("Files /dev/null and b/apps/example/app.yml differ\n"));
+        assert!(diff.contains("Files /dev/null and b/apps/example/config.toml differ\n"));
+        assert!(!diff.contains(&app_seeds("seed", "example")[0].1));
+        assert!(diff.contains("+++ b/apps/example/docker-compose.yml\n"));
+        assert!(!root.app_yml("example").exists());
+
+        convert_dir(&root, &ConvertOptions::default()).unwrap();
+        assert!(root.app_yml("example").exists());
+        let config = root.read(&root.app_dir("example").join("config.toml"));
+        assert!(config
+            .unwrap()
+            .unwrap()
+            .starts_with("version = \"1.0.0\"\n"));
     }
 }
@@ -0,0 +1,409 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+use super::root::CitadelRoot;
+
+/// The files that were replaced by the last applied changeset
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Manifest {
+    // Path relative to the Citadel root, or absolute if it is outside of it -> true if the file existed
+    files: BTreeMap<PathBuf, bool>,
+    // The files only their owner may access, like hidden service keys
+    #[serde(default)]
+    private: BTreeSet<PathBuf>,
+}
+
+/// Permissions of private files and the directories they are in, Tor refuses to use others
+const PRIVATE_FILE_MODE: u32 = 0o600;
+const PRIVATE_DIR_MODE: u32 = 0o700;
+
+/// Where the previous version of a file from the manifest is kept in a generation
+fn backup_path(generation_dir: &Path, manifest_path: &Path) -> PathBuf {
+    match manifest_path.strip_prefix("/") {
+        Ok(path) => generation_dir.join("external").join(path),
+        Err(_) => generation_dir.join("files").join(manifest_path),
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.tmp", file_name))
+}
+
+/// Copies the current version of the given files into a new previous generation
+fn save_previous_generation(
+    root: &CitadelRoot,
+    files: &[&Path],
+    private: &BTreeSet<PathBuf>,
+) -> Result<()> {
+    let fs = root.fs();
+    let generation_dir = root.previous_generation_dir();
+    let new_generation_dir = generation_dir.with_extension("new");
+    if fs.exists(&new_generation_dir) {
+        fs.remove_dir_all(&new_generation_dir)?;
+    }
+    let mut manifest = Manifest::default();
+    for path in files {
+        let relative_path = path.strip_prefix(root.path()).unwrap_or(path);
+        let existed = fs.exists(path);
+        if existed {
+            let backup = backup_path(&new_generation_dir, relative_path);
+            fs.create_dir_all(backup.parent().unwrap())?;
+            fs.write(&backup, &fs.read(path)?)?;
+            if private.contains(*path) {
+                fs.set_mode(&backup, PRIVATE_FILE_MODE)?;
+            }
+        }
+        if private.contains(*path) {
+            manifest.private.insert(relative_path.to_path_buf());
+        }
+        manifest.files.insert(relative_path.to_path_buf(), existed);
+    }
+    fs.create_dir_all(&new_generation_dir)?;
+    fs.write(
+        &new_generation_dir.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?.as_bytes(),
+    )?;
+    if fs.exists(&generation_dir) {
+        fs.remove_dir_all(&generation_dir)?;
+    }
+    fs.rename(&new_generation_dir, &generation_dir)?;
+    fs.sync_dir(generation_dir.parent().unwrap())?;
+    Ok(())
+}
+
+fn load_previous_generation(root: &CitadelRoot) -> Result<Changeset> {
+    let generation_dir = root.previous_generation_dir();
+    let manifest: Manifest = root
+        .load_json(&generation_dir.join("manifest.json"))?
+        .context("No previous generation to roll back to")?;
+    let mut changeset = Changeset::new(root);
+    for (relative_path, existed) in manifest.files {
+        let path = root.path().join(&relative_path);
+        // Files that are deleted stay private, so undoing the rollback restores them as private files
+        if manifest.private.contains(&relative_path) {
+            changeset.private.insert(path.clone());
+        }
+        if existed {
+            let backup = backup_path(&generation_dir, &relative_path);
+            let contents = root.fs().read(&backup)?;
+            if manifest.private.contains(&relative_path) {
+                changeset.write_private(path, contents);
+            } else {
+                changeset.write(path, String::from_utf8(contents)?);
+            }
+        } else {
+            changeset.delete(path);
+        }
+    }
+    Ok(changeset)
+}
+
+/// Restores the files replaced by the last conversion
+///
+/// The current files become the previous generation, so running this again undoes the rollback.
+/// Returns true if any file was changed.
+pub fn rollback(root: &CitadelRoot) -> Result<bool> {
+    load_previous_generation(root)?.apply()
+}
+
+/// The files a conversion writes or deletes
+///
+/// Changes are collected first, so they can be shown as a diff instead of being applied.
+#[derive(Debug)]
+pub struct Changeset {
+    root: CitadelRoot,
+    // Path -> new contents, None if the file should be deleted
+    files: BTreeMap<PathBuf, Option<Vec<u8>>>,
+    // Files that are written with private permissions and left out of the diff
+    private: BTreeSet<PathBuf>,
+    // Files that keep their permissions, but are left out of the diff
+    secret: BTreeSet<PathBuf>,
+}
+
+impl Changeset {
+    pub fn new(root: &CitadelRoot) -> Self {
+        Self {
+            root: root.clone(),
+            files: BTreeMap::new(),
+            private: BTreeSet::new(),
+            secret: BTreeSet::new(),
+        }
+    }
+
+    pub fn root(&self) -> &CitadelRoot {
+        &self.root
+    }
+
+    pub fn write(&mut self, path: impl Into<PathBuf>, contents: impl Into<String>) {
+        self.files
+            .insert(path.into(), Some(contents.into().into_bytes()));
+    }
+
+    /// Writes a file only its owner can access, its directory is made private as well
+    pub fn write_private(&mut self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        let path = path.into();
+        self.private.insert(path.clone());
+        self.files.insert(path, Some(contents.into()));
+    }
+
+    /// Writes a file that can contain secrets, it is left out of the diff,
+    /// but other users, like the app's containers, can still read it
+    pub fn write_secret(&mut self, path: impl Into<PathBuf>, contents: impl Into<String>) {
+        let path = path.into();
+        self.secret.insert(path.clone());
+        self.files.insert(path, Some(contents.into().into_bytes()));
+    }
+
+    pub fn delete(&mut self, path: impl Into<PathBuf>) {
+        self.files.insert(path.into(), None);
+    }
+
+    /// Reads a file as it will be after the changes are applied, None if it will not exist
+    pub fn read(&self, path: &Path) -> Result<Option<String>> {
+        match self.files.get(path) {
+            Some(Some(contents)) => Ok(Some(String::from_utf8(contents.clone())?)),
+            Some(None) => Ok(None),
+            None => self.root.read(path),
+        }
+    }
+
+    fn current_contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.root.fs().read(path).ok()
+    }
+
+    /// The files that would be different after applying the changes
+    pub fn changed_files(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(path, contents)| self.current_contents(path) != **contents)
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// Renders a unified diff of all files that would change, with paths relative to the Citadel root
+    pub fn diff(&self) -> String {
+        let mut diff = String::new();
+        for (path, contents) in &self.files {
+            let current = self.current_contents(path);
+            if current == *contents {
+                continue;
+            }
+            let relative_path = path.strip_prefix(self.root.path()).unwrap_or(path);
+            let old_name = match current {
+                Some(_) => format!("a/{}", relative_path.display()),
+                None => "/dev/null".to_string(),
+            };
+            let new_name = match contents {
+                Some(_) => format!("b/{}", relative_path.display()),
+                None => "/dev/null".to_string(),
+            };
+            // Private files contain keys, which must not end up in logs
+            if self.private.contains(path) || self.secret.contains(path) {
+                diff += &format!("Files {} and {} differ\n", old_name, new_name);
+                continue;
+            }
+            let current = String::from_utf8_lossy(&current.unwrap_or_default()).to_string();
+            let contents = String::from_utf8_lossy(contents.as_deref().unwrap_or_default());
+            diff += &TextDiff::from_lines(current.as_str(), &contents)
+                .unified_diff()
+                .missing_newline_hint(false)
+                .header(&old_name, &new_name)
+                .to_string();
+        }
+        diff
+    }
+
+    /// Writes all files that changed and deletes the removed ones as one transaction
+    ///
+    /// New contents are written to temporary files and synced to disk first,
+    /// then the current files are saved as the previous generation
+    /// and the temporary files are renamed into place.
+    /// If that fails halfway, the files that were already replaced are restored.
+    /// Returns true if any file was changed.
+    pub fn apply(&self) -> Result<bool> {
+        let changed_files = self.changed_files();
+        if changed_files.is_empty() {
+            return Ok(false);
+        }
+        if let Err(error) = self.stage(&changed_files) {
+            self.remove_staged(&changed_files);
+            return Err(error);
+        }
+        if let Err(error) = save_previous_generation(&self.root, &changed_files, &self.private) {
+            self.remove_staged(&changed_files);
+            return Err(error);
+        }
+        if let Err(error) = self.commit(&changed_files) {
+            self.remove_staged(&changed_files);
+            tracing::error!("Failed to apply changes, restoring previous generation");
+            load_previous_generation(&self.root)?.commit_all()?;
+            return Err(error);
+        }
+        Ok(true)
+    }
+
+    fn stage(&self, changed_files: &[&Path]) -> Result<()> {
+        let fs = self.root.fs();
+        for path in changed_files {
+            if let Some(contents) = &self.files[*path] {
+                let private = self.private.contains(*path);
+                if let Some(parent) = path.parent() {
+                    fs.create_dir_all(parent)?;
+                    if private {
+                        fs.set_mode(parent, PRIVATE_DIR_MODE)?;
+                    }
+                }
+                let temp_file = temp_path(path);
+                fs.write(&temp_file, contents)?;
+                if private {
+                    fs.set_mode(&temp_file, PRIVATE_FILE_MODE)?;
+                } else {
+                    fs.copy_permissions(path, &temp_file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_staged(&self, changed_files: &[&Path]) {
+        for path in changed_files {
+            let _ = self.root.fs().remove_file(&temp_path(path));
+        }
+    }
+
+    fn commit(&self, changed_files: &[&Path]) -> Result<()> {
+        let fs = self.root.fs();
+        for path in changed_files {
+            match &self.files[*path] {
+                Some(_) => fs.rename(&temp_path(path), path)?,
+                None => fs.remove_file(path)?,
+            }
+        }
+        for path in changed_files {
+            if let Some(parent) = path.parent() {
+                fs.sync_dir(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores all files, without saving a new previous generation
+    fn commit_all(&self) -> Result<()> {
+        let changed_files = self.changed_files();
+        if let Err(error) = self
+            .stage(&changed_files)
+            .and_then(|_| self.commit(&changed_files))
+        {
+            self.remove_staged(&changed_files);
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use super::{rollback, Changeset};
+    use crate::cli::{
+        fs::{Filesystem, MemoryFilesystem},
+        root::CitadelRoot,
+    };
+
+    #[test]
+    fn diffs_and_applies_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        let unchanged = dir.path().join("unchanged.yml");
+        let changed = dir.path().join("changed.yml");
+        let removed = dir.path().join("removed.yml");
+        std::fs::write(&unchanged, "a: 1\n").unwrap();
+        std::fs::write(&changed, "a: 1\nb: 2\n").unwrap();
+        std::fs::write(&removed, "a: 1\n").unwrap();
+
+        let mut changeset = Changeset::new(&root);
+        changeset.write(&unchanged, "a: 1\n");
+        changeset.write(&changed, "a: 1\nb: 3\n");
+        changeset.delete(&removed);
+        changeset.delete(dir.path().join("missing.yml"));
+        assert_eq!(
+            changeset.changed_files(),
+            [changed.as_path(), removed.as_path()]
+        );
+        let diff = changeset.diff();
+        assert!(diff.contains("--- a/changed.yml\n+++ b/changed.yml\n"));
+        assert!(diff.contains("-b: 2\n+b: 3\n"));
+        assert!(diff.contains("+++ /dev/null"));
+
+        assert!(changeset.apply().unwrap());
+        assert_eq!(std::fs::read_to_string(&changed).unwrap(), "a: 1\nb: 3\n");
+        assert!(!removed.exists());
+
+        let private = dir.path().join("keys").join("secret_key");
+        let mut changeset = Changeset::new(&root);
+        changeset.write_private(&private, b"secret".to_vec());
+        let secret = dir.path().join("app").join("config");
+        changeset.write_secret(&secret, "password=secret\n");
+        let diff = changeset.diff();
+        assert!(!diff.contains("secret\n"));
+        assert!(diff.contains("Files /dev/null and b/app/config differ\n"));
+        assert!(changeset.apply().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&private), 0o600);
+            assert_eq!(mode(private.parent().unwrap()), 0o700);
+            assert_ne!(mode(&secret), 0o600);
+        }
+        assert!(!dir.path().join(".changed.yml.tmp").exists());
+        assert!(!changeset.apply().unwrap());
+    }
+
+    #[test]
+    fn rolls_back_to_previous_generation() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        let root = CitadelRoot::new("/citadel").with_filesystem(fs.clone());
+        let changed = Path::new("/citadel/changed.yml");
+        let created = Path::new("/citadel/app/created.yml");
+        let external = Path::new("/etc/citadel/external.yml");
+        let key = Path::new("/citadel/keys/created.key");
+        root.save(changed, "a: 1\n").unwrap();
+        root.save(external, "c: 1\n").unwrap();
+        assert!(rollback(&root).is_err());
+
+        let mut changeset = Changeset::new(&root);
+        changeset.write(changed, "a: 2\n");
+        changeset.write(created, "b: 1\n");
+        changeset.write(external, "c: 2\n");
+        changeset.write_private(key, vec![0xff, 0x00]);
+        assert!(changeset.apply().unwrap());
+        assert!(fs.exists(
+            &root
+                .previous_generation_dir()
+                .join("files")
+                .join("changed.yml")
+        ));
+        assert!(!fs.exists(Path::new("/citadel/app/.created.yml.tmp")));
+
+        assert!(rollback(&root).unwrap());
+        assert_eq!(fs.read_to_string(changed).unwrap(), "a: 1\n");
+        assert_eq!(fs.read_to_string(external).unwrap(), "c: 1\n");
+        assert!(!fs.exists(created));
+        assert!(!fs.exists(key));
+
+        // Rolling back again restores the generated files
+        assert!(rollback(&root).unwrap());
+        assert_eq!(fs.read_to_string(changed).unwrap(), "a: 2\n");
+        assert_eq!(fs.read_to_string(created).unwrap(), "b: 1\n");
+        assert_eq!(fs.read(key).unwrap(), [0xff, 0x00]);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ips::{subnet_from_env, Ipv4Subnet},
+    ports::RESERVED_PORTS,
+    repos::AppSrc,
+    root::{CitadelRoot, Layout},
+    tor::tor_instances_from_env,
+};
+
+/// The config file, always directly in the Citadel root
+pub const CONFIG_FILE: &str = "app-cli.yml";
+
+/// Variables starting with this override settings from the config file
+const OVERRIDE_PREFIX: &str = "APP_CLI_";
+
+/// Node-level settings, loaded from app-cli.yml in the Citadel root
+///
+/// Every setting can also be set with an APP_CLI_* environment variable, which takes precedence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    /// Host ports that are skipped when an app has to move to another port
+    pub reserved_ports: Vec<u16>,
+    /// The app store sources.yml is created with
+    pub default_store: AppSrc,
+    /// Services that are always available to apps, in addition to the installed apps
+    pub builtin_services: Vec<String>,
+    /// The subnet for app containers, if not set, APPS_SUBNET or NETWORK_IP from .env are used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<Ipv4Subnet>,
+    /// The number of Tor instances for apps, if not set, TOR_APP_INSTANCES from .env is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tor_instances: Option<u8>,
+    pub layout: Layout,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            reserved_ports: RESERVED_PORTS.to_vec(),
+            default_store: AppSrc {
+                repo: "https://github.com/citadel-core/apps".to_string(),
+                branch: "main".to_string(),
+            },
+            builtin_services: vec!["bitcoind".to_string(), "lnd".to_string()],
+            subnet: None,
+            tor_instances: None,
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// Splits a comma-separated list, ignoring empty entries
+fn parse_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+impl Config {
+    /// Loads app-cli.yml, the defaults are used if it does not exist
+    pub fn load(root: &CitadelRoot) -> Result<Self> {
+        Ok(root.load_yaml(&root.config_file())?.unwrap_or_default())
+    }
+
+    /// Applies the APP_CLI_* variables, other variables are ignored
+    pub fn apply_overrides(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        for (key, value) in vars {
+            let Some(setting) = key.strip_prefix(OVERRIDE_PREFIX) else {
+                continue;
+            };
+            let path = || PathBuf::from(&value);
+            match setting {
+                "RESERVED_PORTS" => {
+                    self.reserved_ports = parse_list(&value)
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .with_context(|| format!("Invalid {}", key))?;
+                }
+                "DEFAULT_STORE" => self.default_store.repo = value,
+                "DEFAULT_STORE_BRANCH" => self.default_store.branch = value,
+                "BUILTIN_SERVICES" => {
+                    self.builtin_services = parse_list(&value).map(str::to_string).collect();
+                }
+                "SUBNET" => {
+                    self.subnet = Some(value.parse().with_context(|| format!("Invalid {}", key))?);
+                }
+                "TOR_INSTANCES" => {
+                    self.tor_instances =
+                        Some(value.parse().with_context(|| format!("Invalid {}", key))?);
+                }
+                "APPS_DIR" => self.layout.apps = path(),
+                "APP_DATA_DIR" => self.layout.app_data = path(),
+                "DB_DIR" => self.layout.db = path(),
+                "TOR_DIR" => self.layout.tor = path(),
+                "I2P_TUNNELS_FILE" => self.layout.i2p_tunnels_file = path(),
+                "ENV_FILE" => self.layout.env_file = path(),
+                "UNITS_DIR" => self.layout.units = path(),
+                _ => tracing::warn!("Ignoring unknown setting {}", key),
+            }
+        }
+        Ok(())
+    }
+
+    /// The subnet for app containers, the one set in app-cli.yml or else the one from .env
+    pub fn subnet(&self, env_vars: &HashMap<String, String>) -> Result<Ipv4Subnet> {
+        match self.subnet {
+            Some(subnet) => Ok(subnet),
+            None => subnet_from_env(env_vars).context("Invalid app subnet"),
+        }
+    }
+
+    /// The number of Tor instances, the one set in app-cli.yml or else the one from .env
+    pub fn tor_instances(&self, env_vars: &HashMap<String, String>) -> Result<u8> {
+        match self.tor_instances {
+            Some(0) => bail!("At least one Tor instance is required"),
+            Some(instances) => Ok(instances),
+            None => tor_instances_from_env(env_vars).context("Invalid number of Tor instances"),
+        }
+    }
+
+    /// The configuration that is used, with the settings that are not set taken from .env
+    pub fn effective(&self, env_vars: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            subnet: Some(self.subnet(env_vars)?),
+            tor_instances: Some(self.tor_instances(env_vars)?),
+            ..self.clone()
+        })
+    }
+}
+
+/// Renders the effective configuration of a Citadel root as YAML
+pub fn show(root: &CitadelRoot) -> Result<String> {
+    let config = root.config().effective(&root.env_vars())?;
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use super::Config;
+    use crate::{
+        cli::{fs::MemoryFilesystem, root::CitadelRoot},
+        map,
+    };
+
+    #[test]
+    fn loads_config_with_overrides() {
+        let root = CitadelRoot::new("/citadel").with_filesystem(Arc::new(MemoryFilesystem::new()));
+        assert_eq!(Config::load(&root).unwrap(), Config::default());
+
+        root.save(
+            &root.config_file(),
+            "reservedPorts: [80, 443]
+builtinServices: [bitcoind]
+layout:
+  appData: /mnt/app-data
+",
+        )
+        .unwrap();
+        let mut config = Config::load(&root).unwrap();
+        assert_eq!(config.reserved_ports, [80, 443]);
+        assert_eq!(config.layout.app_data, Path::new("/mnt/app-data"));
+        // Settings that are not in the file keep their defaults
+        assert_eq!(config.layout.apps, Path::new("apps"));
+        assert_eq!(config.default_store, Config::default().default_store);
+
+        config
+            .apply_overrides(map! {
+                "APP_CLI_BUILTIN_SERVICES" => "bitcoind, lnd, electrs".to_string(),
+                "APP_CLI_DB_DIR" => "state".to_string(),
+                "APP_DOMAIN" => "example.com".to_string()
+            })
+            .unwrap();
+        assert_eq!(config.builtin_services, ["bitcoind", "lnd", "electrs"]);
+        assert_eq!(config.layout.db, Path::new("state"));
+        assert!(config
+            .apply_overrides(map! { "APP_CLI_RESERVED_PORTS" => "80,http".to_string() })
+            .is_err());
+
+        // .env is only used for settings app-cli.yml does not set
+        let env_vars = map! {
+            "APPS_SUBNET" => "10.21.0.0/16".to_string(),
+            "TOR_APP_INSTANCES" => "2".to_string()
+        };
+        config
+            .apply_overrides(map! { "APP_CLI_TOR_INSTANCES" => "4".to_string() })
+            .unwrap();
+        let effective = config.effective(&env_vars).unwrap();
+        assert_eq!(effective.subnet.unwrap().to_string(), "10.21.0.0/16");
+        assert_eq!(effective.tor_instances, Some(4));
+        let mut invalid = config.clone();
+        invalid.tor_instances = Some(0);
+        assert!(invalid.effective(&env_vars).is_err());
+
+        let root = root.with_config(config);
+        assert_eq!(
+            root.seed_file(),
+            Path::new("/citadel/state/citadel-seed/seed")
+        );
+        assert_eq!(root.installed_services(), ["bitcoind", "lnd", "electrs"]);
+    }
+}
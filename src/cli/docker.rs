@@ -0,0 +1,669 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+};
+
+use anyhow::{bail, Context, Result};
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, ListContainersOptions, NetworkingConfig,
+        RemoveContainerOptions, StopContainerOptions,
+    },
+    image::CreateImageOptions,
+    models::{
+        EndpointIpamConfig, EndpointSettings, HostConfig, HostConfigLogConfig, Ipam, IpamConfig,
+        PortBinding, RestartPolicy, RestartPolicyNameEnum,
+    },
+    network::{
+        ConnectNetworkOptions, CreateNetworkOptions, InspectNetworkOptions, ListNetworksOptions,
+    },
+    volume::CreateVolumeOptions,
+    Docker,
+};
+use futures_util::stream::TryStreamExt;
+
+use super::{app_env_vars, load_converted_apps, root::CitadelRoot};
+use crate::{
+    composegenerator::{
+        compose::types::{Command, StringOrIntOrBool},
+        output::{
+            compose::COMPOSE_FILE,
+            quadlet::NETWORK_NAME,
+            types::{ComposeSpecification, Service},
+        },
+    },
+    utils::replace_env_vars,
+};
+
+// The labels Docker Compose uses, so the containers can still be managed with it
+const PROJECT_LABEL: &str = "com.docker.compose.project";
+const SERVICE_LABEL: &str = "com.docker.compose.service";
+const NETWORK_LABEL: &str = "com.docker.compose.network";
+const VOLUME_LABEL: &str = "com.docker.compose.volume";
+
+fn project_filter(app_id: &str) -> HashMap<String, Vec<String>> {
+    HashMap::from([(
+        "label".to_string(),
+        vec![format!("{}={}", PROJECT_LABEL, app_id)],
+    )])
+}
+
+/// The ids of an app's containers, they are labeled with the app id as project name
+async fn app_containers(docker: &Docker, app_id: &str) -> Result<Vec<String>> {
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters: project_filter(app_id),
+            ..Default::default()
+        }))
+        .await
+        .context("Error listing containers")?;
+    Ok(containers
+        .into_iter()
+        .filter_map(|container| container.id)
+        .collect())
+}
+
+fn block_on<F: Future<Output = Result<usize>>>(future: F) -> Result<usize> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Error starting async runtime")?
+        .block_on(future)
+}
+
+/// The variables an app's docker-compose.yml refers to
+fn compose_env(root: &CitadelRoot, app_id: &str) -> Result<HashMap<String, String>> {
+    let app = load_converted_apps(root)?
+        .remove(app_id)
+        .with_context(|| format!("App {} has not been converted", app_id))?;
+    Ok(app_env_vars(
+        root,
+        app_id,
+        &app.hidden_services,
+        &root.env_vars(),
+        root.citadel_seed()?.as_deref(),
+    ))
+}
+
+fn resolve(value: &str, env: &HashMap<String, String>) -> Result<String> {
+    replace_env_vars(value, |var| match env.get(var) {
+        Some(value) => Ok(value.clone()),
+        None => bail!("Variable {} is not set", var),
+    })
+}
+
+fn command_args(command: &Command, env: &HashMap<String, String>) -> Result<Vec<String>> {
+    let args = match command {
+        Command::SimpleCommand(command) => shell_words::split(command)?,
+        Command::ArrayCommand(args) => args.clone(),
+    };
+    args.iter().map(|arg| resolve(arg, env)).collect()
+}
+
+/// Parses a duration like 1m30s into seconds
+fn parse_duration(duration: &str) -> Result<i64> {
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            _ => bail!("Invalid duration {}", duration),
+        };
+        let Ok(value) = number.parse::<i64>() else {
+            bail!("Invalid duration {}", duration);
+        };
+        seconds += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        bail!("Invalid duration {}, it needs a unit", duration);
+    }
+    Ok(seconds)
+}
+
+/// Turns a port mapping like 3000:80/udp into the container port and its binding
+fn port_binding(port: &str) -> Result<(String, PortBinding)> {
+    let (ports, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    let parts: Vec<&str> = ports.split(':').collect();
+    let (host_ip, host_port, container_port) = match parts.as_slice() {
+        [container_port] => (None, None, container_port),
+        [host_port, container_port] => (None, Some(host_port), container_port),
+        [host_ip, host_port, container_port] => (Some(host_ip), Some(host_port), container_port),
+        _ => bail!("Invalid port {}", port),
+    };
+    Ok((
+        format!("{}/{}", container_port, protocol),
+        PortBinding {
+            host_ip: host_ip.map(|ip| ip.to_string()),
+            host_port: host_port.map(|port| port.to_string()),
+        },
+    ))
+}
+
+/// The name of the Docker network a network of an app's docker-compose.yml refers to
+fn network_name(spec: &ComposeSpecification, network: &str) -> String {
+    if network == "default" {
+        return NETWORK_NAME.to_string();
+    }
+    match spec
+        .networks
+        .as_ref()
+        .and_then(|networks| networks.get(network))
+    {
+        Some(definition) => definition
+            .name
+            .clone()
+            .unwrap_or_else(|| network.to_string()),
+        None => network.to_string(),
+    }
+}
+
+/// A container of an app, ready to be created
+struct ContainerSpec {
+    name: String,
+    config: Config<String>,
+    /// The networks the container joins after it was created
+    networks: Vec<(String, EndpointSettings)>,
+}
+
+fn container_spec(
+    app_id: &str,
+    spec: &ComposeSpecification,
+    service_name: &str,
+    service: &Service,
+    env: &HashMap<String, String>,
+) -> Result<ContainerSpec> {
+    let Some(image) = &service.image else {
+        bail!("Container {} does not have an image", service_name);
+    };
+    let mut environment = Vec::new();
+    for (key, value) in service.environment.iter().flatten() {
+        let value = match value {
+            StringOrIntOrBool::String(value) => resolve(value, env)?,
+            StringOrIntOrBool::Int(value) => value.to_string(),
+            StringOrIntOrBool::Bool(value) => value.to_string(),
+        };
+        environment.push(format!("{}={}", key, value));
+    }
+
+    let mut exposed_ports = HashMap::new();
+    let mut port_bindings: HashMap<String, Option<Vec<PortBinding>>> = HashMap::new();
+    for port in &service.ports {
+        let (container_port, binding) = port_binding(&resolve(port, env)?)?;
+        exposed_ports.insert(container_port.clone(), HashMap::new());
+        port_bindings
+            .entry(container_port)
+            .or_default()
+            .get_or_insert_with(Vec::new)
+            .push(binding);
+    }
+    let mut binds = Vec::new();
+    for volume in &service.volumes {
+        let Some((source, target)) = volume.split_once(':') else {
+            bail!("Invalid volume {}", volume);
+        };
+        let named_volume = spec
+            .volumes
+            .as_ref()
+            .and_then(|volumes| volumes.get(source));
+        let source = match named_volume {
+            Some(definition) => definition
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{}_{}", app_id, source)),
+            None => resolve(source, env)?,
+        };
+        binds.push(format!("{}:{}", source, resolve(target, env)?));
+    }
+    let tmpfs = service
+        .tmpfs
+        .iter()
+        .map(|tmpfs| match tmpfs.split_once(':') {
+            Some((path, options)) => (path.to_string(), options.to_string()),
+            None => (tmpfs.to_string(), String::new()),
+        })
+        .collect();
+    let restart = match service.restart.as_deref() {
+        Some("always") => RestartPolicyNameEnum::ALWAYS,
+        Some("unless-stopped") => RestartPolicyNameEnum::UNLESS_STOPPED,
+        Some("on-failure") => RestartPolicyNameEnum::ON_FAILURE,
+        _ => RestartPolicyNameEnum::NO,
+    };
+    let log_config = service.logging.as_ref().map(|logging| HostConfigLogConfig {
+        typ: logging.driver.clone(),
+        config: logging.options.as_ref().map(|options| {
+            options
+                .iter()
+                .map(|(key, value)| match value {
+                    serde_json::Value::String(value) => (key.clone(), value.clone()),
+                    _ => (key.clone(), value.to_string()),
+                })
+                .collect()
+        }),
+    });
+    let extra_hosts = service
+        .extra_hosts
+        .iter()
+        .flatten()
+        .map(|host| resolve(host, env))
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut networks = Vec::new();
+    let mut networking_config = None;
+    let network_mode = match service.network_mode.as_deref() {
+        Some(network_mode) => network_mode.to_string(),
+        None => {
+            let mut entries: BTreeMap<&str, Option<&String>> = service
+                .networks
+                .iter()
+                .flatten()
+                .map(|(name, entry)| (name.as_str(), entry.ipv4_address.as_ref()))
+                .collect();
+            // Like Docker Compose, containers without networks join the default network
+            entries.entry("default").or_default();
+            for (network, address) in entries {
+                let endpoint = EndpointSettings {
+                    ipam_config: address
+                        .map(|address| -> Result<EndpointIpamConfig> {
+                            Ok(EndpointIpamConfig {
+                                ipv4_address: Some(resolve(address, env)?),
+                                ..Default::default()
+                            })
+                        })
+                        .transpose()?,
+                    // Other apps reach the container by its IP address on the network all apps share
+                    aliases: (network != "default").then(|| vec![service_name.to_string()]),
+                    ..Default::default()
+                };
+                if network == "default" {
+                    networking_config = Some(NetworkingConfig {
+                        endpoints_config: HashMap::from([(NETWORK_NAME.to_string(), endpoint)]),
+                    });
+                } else {
+                    networks.push((network_name(spec, network), endpoint));
+                }
+            }
+            NETWORK_NAME.to_string()
+        }
+    };
+
+    let host_config = HostConfig {
+        binds: Some(binds),
+        port_bindings: Some(port_bindings),
+        tmpfs: Some(tmpfs),
+        cap_add: service.cap_add.clone(),
+        init: service.init,
+        extra_hosts: Some(extra_hosts),
+        restart_policy: Some(RestartPolicy {
+            name: Some(restart),
+            ..Default::default()
+        }),
+        log_config,
+        network_mode: Some(network_mode),
+        ..Default::default()
+    };
+    let config = Config {
+        image: Some(resolve(image, env)?),
+        hostname: service.hostname.clone(),
+        user: service
+            .user
+            .as_deref()
+            .map(|user| resolve(user, env))
+            .transpose()?,
+        env: Some(environment),
+        entrypoint: service
+            .entrypoint
+            .as_ref()
+            .map(|entrypoint| command_args(entrypoint, env))
+            .transpose()?,
+        cmd: service
+            .command
+            .as_ref()
+            .map(|command| command_args(command, env))
+            .transpose()?,
+        working_dir: service.working_dir.clone(),
+        exposed_ports: Some(exposed_ports),
+        labels: Some(HashMap::from([
+            (PROJECT_LABEL.to_string(), app_id.to_string()),
+            (SERVICE_LABEL.to_string(), service_name.to_string()),
+        ])),
+        stop_signal: service.stop_signal.clone(),
+        stop_timeout: service
+            .stop_grace_period
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?,
+        host_config: Some(host_config),
+        networking_config,
+        ..Default::default()
+    };
+    Ok(ContainerSpec {
+        name: format!("{}-{}-1", app_id, service_name),
+        config,
+        networks,
+    })
+}
+
+/// The services of an app, every service comes after the services it depends on
+fn start_order(spec: &ComposeSpecification) -> Result<Vec<(&String, &Service)>> {
+    let mut ordered: Vec<(&String, &Service)> = Vec::new();
+    let mut remaining: Vec<(&String, &Service)> = spec.services.iter().flatten().collect();
+    while !remaining.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) = remaining.into_iter().partition(|(_, service)| {
+            service
+                .depends_on
+                .iter()
+                .flatten()
+                .all(|dependency| ordered.iter().any(|(name, _)| *name == dependency))
+        });
+        if ready.is_empty() {
+            let names: Vec<&str> = waiting.iter().map(|(name, _)| name.as_str()).collect();
+            bail!(
+                "The containers {} depend on each other or on missing containers",
+                names.join(", ")
+            );
+        }
+        ordered.extend(ready);
+        remaining = waiting;
+    }
+    Ok(ordered)
+}
+
+/// Removes the containers of an app, returns how many there were
+async fn remove_containers(docker: &Docker, app_id: &str) -> Result<usize> {
+    let containers = app_containers(docker, app_id).await?;
+    for container in &containers {
+        docker
+            .stop_container(container, None::<StopContainerOptions>)
+            .await
+            .with_context(|| format!("Error stopping container {}", container))?;
+        docker
+            .remove_container(container, None::<RemoveContainerOptions>)
+            .await
+            .with_context(|| format!("Error removing container {}", container))?;
+    }
+    Ok(containers.len())
+}
+
+/// Creates a network if it does not exist yet
+async fn ensure_network(
+    docker: &Docker,
+    name: &str,
+    labels: HashMap<&str, &str>,
+    ipam: Ipam,
+) -> Result<()> {
+    if docker
+        .inspect_network(name, None::<InspectNetworkOptions<&str>>)
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+    docker
+        .create_network(CreateNetworkOptions {
+            name,
+            check_duplicate: true,
+            driver: "bridge",
+            ipam,
+            labels,
+            ..Default::default()
+        })
+        .await
+        .with_context(|| format!("Error creating network {}", name))?;
+    Ok(())
+}
+
+/// Creates and starts the containers of an app from its docker-compose.yml, returns how many there are
+///
+/// Existing containers of the app are replaced.
+/// The default network of every app is the network all apps share, it is created with the apps' subnet.
+pub fn start_app(root: &CitadelRoot, app_id: &str) -> Result<usize> {
+    let compose_file = root.app_dir(app_id).join(COMPOSE_FILE);
+    let Some(compose_yml) = root.read(&compose_file)? else {
+        bail!(
+            "App {} does not have a {}, it is only generated by the compose output backend",
+            app_id,
+            COMPOSE_FILE
+        );
+    };
+    let spec: ComposeSpecification = serde_yaml::from_str(&compose_yml)
+        .with_context(|| format!("Error parsing {}", compose_file.display()))?;
+    let env = compose_env(root, app_id)?;
+    let containers = start_order(&spec)?
+        .into_iter()
+        .map(|(name, service)| container_spec(app_id, &spec, name, service, &env))
+        .collect::<Result<Vec<ContainerSpec>>>()?;
+    if containers.is_empty() {
+        bail!("App {} does not have any containers", app_id);
+    }
+    let env_vars = root.env_vars();
+    let subnet = root.config().subnet(&env_vars)?;
+
+    block_on(async {
+        let docker = Docker::connect_with_local_defaults().context("Error connecting to Docker")?;
+        remove_containers(&docker, app_id).await?;
+
+        let shared_ipam = Ipam {
+            config: Some(vec![IpamConfig {
+                subnet: Some(subnet.to_string()),
+                gateway: Some(subnet.gateway().to_string()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        ensure_network(&docker, NETWORK_NAME, HashMap::new(), shared_ipam).await?;
+        for (network, definition) in spec.networks.iter().flatten() {
+            let name = network_name(&spec, network);
+            if definition.external.is_some() {
+                docker
+                    .inspect_network(&name, None::<InspectNetworkOptions<&str>>)
+                    .await
+                    .with_context(|| format!("External network {} does not exist", name))?;
+                continue;
+            }
+            let labels =
+                HashMap::from([(PROJECT_LABEL, app_id), (NETWORK_LABEL, network.as_str())]);
+            ensure_network(&docker, &name, labels, Ipam::default()).await?;
+        }
+        for (volume, definition) in spec.volumes.iter().flatten() {
+            let name = definition
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{}_{}", app_id, volume));
+            docker
+                .create_volume(CreateVolumeOptions {
+                    name: name.as_str(),
+                    labels: HashMap::from([
+                        (PROJECT_LABEL, app_id),
+                        (VOLUME_LABEL, volume.as_str()),
+                    ]),
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("Error creating volume {}", name))?;
+        }
+
+        for container in &containers {
+            let image = container.config.image.clone().unwrap_or_default();
+            if docker.inspect_image(&image).await.is_err() {
+                docker
+                    .create_image(
+                        Some(CreateImageOptions {
+                            from_image: image.as_str(),
+                            ..Default::default()
+                        }),
+                        None,
+                        None,
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .with_context(|| format!("Error pulling {}", image))?;
+            }
+            docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: container.name.as_str(),
+                    }),
+                    container.config.clone(),
+                )
+                .await
+                .with_context(|| format!("Error creating container {}", container.name))?;
+            // Docker only attaches a container to one network when creating it
+            for (network, endpoint_config) in &container.networks {
+                docker
+                    .connect_network(
+                        network,
+                        ConnectNetworkOptions {
+                            container: container.name.as_str(),
+                            endpoint_config: endpoint_config.clone(),
+                        },
+                    )
+                    .await
+                    .with_context(|| {
+                        format!("Error connecting {} to {}", container.name, network)
+                    })?;
+            }
+            docker
+                .start_container::<String>(&container.name, None)
+                .await
+                .with_context(|| format!("Error starting container {}", container.name))?;
+        }
+        Ok(containers.len())
+    })
+}
+
+/// Stops and removes the containers and networks of an app, returns how many containers there were
+///
+/// Volumes and the network all apps share are kept.
+pub fn stop_app(app_id: &str) -> Result<usize> {
+    block_on(async {
+        let docker = Docker::connect_with_local_defaults().context("Error connecting to Docker")?;
+        let removed = remove_containers(&docker, app_id).await?;
+        let networks = docker
+            .list_networks(Some(ListNetworksOptions {
+                filters: project_filter(app_id),
+            }))
+            .await
+            .context("Error listing networks")?;
+        for network in networks.into_iter().filter_map(|network| network.name) {
+            docker
+                .remove_network(&network)
+                .await
+                .with_context(|| format!("Error removing network {}", network))?;
+        }
+        Ok(removed)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{container_spec, parse_duration, start_order};
+    use crate::{
+        bmap,
+        composegenerator::{
+            compose::types::{Command, Network},
+            output::types::{ComposeSpecification, NetworkEntry, Service},
+        },
+        map,
+    };
+
+    #[test]
+    fn creates_containers_like_docker_compose() {
+        let spec = ComposeSpecification {
+            services: Some(bmap! {
+                "main" => Service {
+                    image: Some("ghcr.io/runcitadel/example:main".to_string()),
+                    command: Some(Command::SimpleCommand("serve --seed=$APP_SEED".to_string())),
+                    depends_on: Some(vec!["db".to_string()]),
+                    ports: vec!["3000:3000".to_string(), "3001:3001/udp".to_string()],
+                    networks: Some(bmap! {
+                        "backend" => NetworkEntry::default(),
+                        "default" => NetworkEntry {
+                            ipv4_address: Some("$APP_EXAMPLE_MAIN_IP".to_string())
+                        }
+                    }),
+                    stop_grace_period: Some("1m30s".to_string()),
+                    ..Default::default()
+                },
+                "db" => Service {
+                    image: Some("ghcr.io/runcitadel/example-db:main".to_string()),
+                    network_mode: Some("host".to_string()),
+                    ..Default::default()
+                }
+            }),
+            networks: Some(bmap! {
+                "backend" => Network {
+                    name: Some("example_backend".to_string()),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        let env: HashMap<String, String> = map! {
+            "APP_SEED" => "0123456789abcdef".to_string(),
+            "APP_EXAMPLE_MAIN_IP" => "10.21.21.20".to_string()
+        };
+        let order: Vec<&String> = start_order(&spec)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(order, ["db", "main"]);
+
+        let main = container_spec(
+            "example",
+            &spec,
+            "main",
+            &spec.services.as_ref().unwrap()["main"],
+            &env,
+        )
+        .unwrap();
+        assert_eq!(main.name, "example-main-1");
+        assert_eq!(
+            main.config.cmd.unwrap(),
+            ["serve", "--seed=0123456789abcdef"]
+        );
+        assert_eq!(main.config.stop_timeout, Some(90));
+        let host_config = main.config.host_config.unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("citadel"));
+        let port_bindings = host_config.port_bindings.unwrap();
+        assert_eq!(
+            port_bindings["3001/udp"].as_ref().unwrap()[0]
+                .host_port
+                .as_deref(),
+            Some("3001")
+        );
+        let endpoints = main.config.networking_config.unwrap().endpoints_config;
+        assert_eq!(
+            endpoints["citadel"]
+                .ipam_config
+                .as_ref()
+                .unwrap()
+                .ipv4_address
+                .as_deref(),
+            Some("10.21.21.20")
+        );
+        assert_eq!(main.networks.len(), 1);
+        assert_eq!(main.networks[0].0, "example_backend");
+        assert_eq!(
+            main.networks[0].1.aliases.as_deref(),
+            Some(&["main".to_string()][..])
+        );
+
+        let mut cyclic = spec.clone();
+        let services = cyclic.services.as_mut().unwrap();
+        services.get_mut("db").unwrap().depends_on = Some(vec!["main".to_string()]);
+        assert!(start_order(&cyclic).is_err());
+        assert!(parse_duration("10").is_err());
+        assert_eq!(parse_duration("1h").unwrap(), 3600);
+    }
+}
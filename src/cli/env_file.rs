@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, HashMap};
+
+const MANAGED_SECTION_START: &str =
+    "# BEGIN app-cli managed section, changes here will be overwritten";
+const MANAGED_SECTION_END: &str = "# END app-cli managed section";
+
+/// The key a line sets, None for comments and empty lines
+fn line_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.starts_with('#') {
+        return None;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, _) = line.split_once('=')?;
+    Some(key.trim())
+}
+
+/// Gets the value of $name from a variable set earlier in the file
+///
+/// The process environment is not used, so the result only depends on the file.
+/// A $ that is not followed by a name is kept.
+fn substitute(name: &str, vars: &HashMap<String, String>, output: &mut String) {
+    if name.is_empty() {
+        output.push('$');
+        return;
+    }
+    output.push_str(vars.get(name).map_or("", |value| value.as_str()));
+}
+
+/// Parses the value of a variable the same way dotenv does
+fn parse_value(input: &str, vars: &HashMap<String, String>) -> Option<String> {
+    let mut output = String::new();
+    // Inside '...', nothing is escaped or substituted
+    let mut single_quoted = false;
+    let mut double_quoted = false;
+    let mut escaped = false;
+    // After unquoted whitespace, only a comment may follow
+    let mut expecting_end = false;
+    // The name of the variable that is substituted, and whether it is in braces
+    let mut substitution: Option<(String, bool)> = None;
+    for c in input.chars() {
+        if expecting_end {
+            match c {
+                ' ' | '\t' => continue,
+                '#' => break,
+                _ => return None,
+            }
+        } else if escaped {
+            match c {
+                '\\' | '\'' | '"' | '$' | ' ' => output.push(c),
+                'n' => output.push('\n'),
+                _ => return None,
+            }
+            escaped = false;
+        } else if single_quoted {
+            if c == '\'' {
+                single_quoted = false;
+            } else {
+                output.push(c);
+            }
+        } else if let Some((name, braces)) = &mut substitution {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                name.push(c);
+            } else if *braces {
+                if c == '}' {
+                    substitute(name, vars, &mut output);
+                    substitution = None;
+                } else {
+                    name.push(c);
+                }
+            } else if c == '{' && name.is_empty() {
+                *braces = true;
+            } else {
+                substitute(name, vars, &mut output);
+                if c == '$' {
+                    substitution = Some((String::new(), false));
+                } else {
+                    substitution = None;
+                    output.push(c);
+                }
+            }
+        } else if c == '$' {
+            substitution = Some((String::new(), false));
+        } else if double_quoted {
+            match c {
+                '"' => double_quoted = false,
+                '\\' => escaped = true,
+                _ => output.push(c),
+            }
+        } else {
+            match c {
+                '\'' => single_quoted = true,
+                '"' => double_quoted = true,
+                '\\' => escaped = true,
+                ' ' | '\t' => expecting_end = true,
+                _ => output.push(c),
+            }
+        }
+    }
+    match substitution {
+        Some((_, true)) => return None,
+        Some((name, false)) => substitute(&name, vars, &mut output),
+        None => {}
+    }
+    (!single_quoted && !double_quoted).then_some(output)
+}
+
+/// Parses the variables a .env file sets, like dotenv does
+///
+/// Lines that can not be parsed are skipped with a warning.
+pub fn parse_vars(contents: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let parsed = (|| {
+            let (key, value) = trimmed.split_once('=')?;
+            let key = key.trim_end();
+            // export can be both a prefix and a key
+            let key = match key.strip_prefix("export") {
+                Some(exported) if exported.starts_with([' ', '\t']) => exported.trim_start(),
+                _ => key,
+            };
+            if !key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+            {
+                return None;
+            }
+            let value = value.trim_start();
+            if value.is_empty() || value.starts_with('#') {
+                return Some((key.to_string(), String::new()));
+            }
+            Some((key.to_string(), parse_value(value, &vars)?))
+        })();
+        match parsed {
+            Some((key, value)) => {
+                vars.insert(key, value);
+            }
+            None => tracing::warn!("Failed to parse env var: {:?}", line),
+        }
+    }
+    vars
+}
+
+/// A .env file with a section that is generated by app-cli
+///
+/// Everything outside of the managed section is kept as it is, including comments.
+#[derive(Debug, Default)]
+pub struct EnvFile {
+    /// Lines outside of the managed section
+    lines: Vec<String>,
+    /// The index in lines the managed section is at, None if the file does not have one yet
+    managed_position: Option<usize>,
+    managed: BTreeMap<String, String>,
+}
+
+impl EnvFile {
+    pub fn parse(contents: &str) -> Self {
+        let mut env_file = Self::default();
+        // The lines after a start marker, they are only managed once the end marker follows
+        let mut section: Option<Vec<&str>> = None;
+        for line in contents.lines() {
+            if line.trim() == MANAGED_SECTION_START {
+                if let Some(lines) = section.replace(Vec::new()) {
+                    env_file.keep_unterminated(lines);
+                }
+            } else if section.is_some() && line.trim() == MANAGED_SECTION_END {
+                env_file
+                    .managed_position
+                    .get_or_insert(env_file.lines.len());
+                for line in section.take().unwrap_or_default() {
+                    if let Some((key, value)) = line.split_once('=') {
+                        env_file
+                            .managed
+                            .insert(key.trim().to_string(), value.to_string());
+                    }
+                }
+            } else if let Some(lines) = &mut section {
+                lines.push(line);
+            } else {
+                env_file.lines.push(line.to_string());
+            }
+        }
+        if let Some(lines) = section {
+            env_file.keep_unterminated(lines);
+        }
+        env_file
+    }
+
+    /// Keeps the lines after a start marker without an end marker, they were not written by app-cli
+    fn keep_unterminated(&mut self, lines: Vec<&str>) {
+        tracing::warn!("The env file has an app-cli managed section without an end, ignoring it");
+        self.lines.extend(lines.into_iter().map(str::to_string));
+    }
+
+    /// Replaces the contents of the managed section
+    ///
+    /// Lines outside of it that set one of the managed keys, or a key for which is_stale returns true,
+    /// are removed, so older versions that appended to the file do not leave duplicates.
+    pub fn set_managed(
+        &mut self,
+        values: BTreeMap<String, String>,
+        is_stale: impl Fn(&str) -> bool,
+    ) {
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut managed_position = None;
+        for (i, line) in self.lines.drain(..).enumerate() {
+            if self.managed_position == Some(i) {
+                managed_position = Some(lines.len());
+            }
+            if line_key(&line).is_some_and(|key| values.contains_key(key) || is_stale(key)) {
+                continue;
+            }
+            lines.push(line);
+        }
+        self.managed_position = managed_position.or(self.managed_position.map(|_| lines.len()));
+        self.lines = lines;
+        self.managed = values;
+    }
+
+    pub fn render(&self) -> String {
+        let position = self.managed_position.unwrap_or(self.lines.len());
+        let mut contents = String::new();
+        for line in &self.lines[..position] {
+            contents += line;
+            contents.push('\n');
+        }
+        if self.managed_position.is_some() || !self.managed.is_empty() {
+            contents += MANAGED_SECTION_START;
+            contents.push('\n');
+            for (key, value) in &self.managed {
+                contents += &format!("{}={}\n", key, value);
+            }
+            contents += MANAGED_SECTION_END;
+            contents.push('\n');
+        }
+        for line in &self.lines[position..] {
+            contents += line;
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_vars, EnvFile};
+    use crate::{bmap, map};
+
+    #[test]
+    fn updates_managed_section() {
+        let mut env_file = EnvFile::parse(
+            "# Set by the user
+APP_DOMAIN=example.com
+APP_A_MAIN_IP=10.21.21.20
+APP_OLD_MAIN_IP=10.21.21.21
+",
+        );
+        assert!(env_file.managed.is_empty());
+        env_file.set_managed(
+            bmap! {
+                "APP_A_MAIN_IP" => "10.21.21.22".to_string(),
+                "APP_A_PORT" => "3000".to_string()
+            },
+            |key| key == "APP_OLD_MAIN_IP",
+        );
+        let contents = env_file.render();
+        assert_eq!(
+            contents,
+            "# Set by the user
+APP_DOMAIN=example.com
+# BEGIN app-cli managed section, changes here will be overwritten
+APP_A_MAIN_IP=10.21.21.22
+APP_A_PORT=3000
+# END app-cli managed section
+"
+        );
+
+        // The section stays where it is, lines after it are kept
+        let mut env_file = EnvFile::parse(&(contents + "APP_PROXY_ROUTING=path\n"));
+        assert_eq!(env_file.managed["APP_A_PORT"], "3000");
+        env_file.set_managed(bmap! { "APP_A_PORT" => "3001".to_string() }, |_| false);
+        assert_eq!(
+            env_file.render(),
+            "# Set by the user
+APP_DOMAIN=example.com
+# BEGIN app-cli managed section, changes here will be overwritten
+APP_A_PORT=3001
+# END app-cli managed section
+APP_PROXY_ROUTING=path
+"
+        );
+    }
+
+    #[test]
+    fn keeps_lines_after_unterminated_section() {
+        let mut env_file = EnvFile::parse(
+            "APP_DOMAIN=example.com
+# BEGIN app-cli managed section, changes here will be overwritten
+APP_A_PORT=3000
+APP_PROXY_ROUTING=path
+",
+        );
+        assert!(env_file.managed.is_empty());
+        env_file.set_managed(bmap! { "APP_A_PORT" => "3001".to_string() }, |_| false);
+        assert_eq!(
+            env_file.render(),
+            "APP_DOMAIN=example.com
+APP_PROXY_ROUTING=path
+# BEGIN app-cli managed section, changes here will be overwritten
+APP_A_PORT=3001
+# END app-cli managed section
+"
+        );
+    }
+
+    #[test]
+    fn parses_vars() {
+        let vars = parse_vars(
+            r#"# Comment
+APP_DOMAIN=example.com
+export APP_OUTPUT_BACKEND="docker-compose" # A comment
+QUOTED='$APP_DOMAIN'
+ESCAPED="a \"b\"\nc"
+SUBSTITUTED=app.${APP_DOMAIN}
+EMPTY=
+INVALID=a b
+"#,
+        );
+        assert_eq!(
+            vars,
+            map! {
+                "APP_DOMAIN" => "example.com".to_string(),
+                "APP_OUTPUT_BACKEND" => "docker-compose".to_string(),
+                "QUOTED" => "$APP_DOMAIN".to_string(),
+                "ESCAPED" => "a \"b\"\nc".to_string(),
+                "SUBSTITUTED" => "app.example.com".to_string(),
+                "EMPTY" => String::new()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_edge_cases() {
+        let vars = parse_vars(
+            r#"APP_DOMAIN=example.com
+ESCAPES="a\\b \$APP_DOMAIN \' \n"
+UNQUOTED_ESCAPE=a\ b
+INVALID_ESCAPE="\q"
+UNCLOSED_BRACE=${APP_DOMAIN
+UNCLOSED_QUOTE="abc
+UNBRACED=$APP_DOMAIN/$APP_DOMAIN
+DOLLAR=5$
+UNDEFINED=a${MISSING}b
+FROM_PROCESS=$PATH
+export EXPORTED=1
+export	TABBED=2
+export=3
+COMMENTED=value # comment
+COMMENT_ONLY= # comment
+HASH=a#b
+QUOTED_HASH="a # b"  # comment
+"#,
+        );
+        assert_eq!(
+            vars,
+            map! {
+                "APP_DOMAIN" => "example.com".to_string(),
+                "ESCAPES" => "a\\b $APP_DOMAIN ' \n".to_string(),
+                "UNQUOTED_ESCAPE" => "a b".to_string(),
+                "UNBRACED" => "example.com/example.com".to_string(),
+                "DOLLAR" => "5$".to_string(),
+                "UNDEFINED" => "ab".to_string(),
+                "FROM_PROCESS" => String::new(),
+                "EXPORTED" => "1".to_string(),
+                "TABBED" => "2".to_string(),
+                "export" => "3".to_string(),
+                "COMMENTED" => "value".to_string(),
+                "COMMENT_ONLY" => String::new(),
+                "HASH" => "a#b".to_string(),
+                "QUOTED_HASH" => "a # b".to_string()
+            }
+        );
+    }
+}
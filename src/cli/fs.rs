@@ -0,0 +1,263 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The operations app-cli uses to read and write the state files in the Citadel root
+pub trait Filesystem: Debug + Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    /// Creates or replaces a file, the contents are on disk when this returns
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// The names of the entries in a directory, in no particular order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>>;
+    /// Makes renames and removals in a directory durable
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    /// Gives a file the permissions of another one
+    fn copy_permissions(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+    /// Sets the Unix permissions of a file or directory
+    fn set_mode(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The real filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFilesystem;
+
+impl Filesystem for OsFilesystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect()
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn copy_permissions(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match std::fs::metadata(from) {
+            Ok(metadata) => std::fs::set_permissions(to, metadata.permissions()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    File(Vec<u8>),
+    Dir,
+}
+
+/// A filesystem that only exists in memory, for tests
+///
+/// Paths are used as they are, so they should all be absolute.
+#[derive(Debug, Default)]
+pub struct MemoryFilesystem {
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn check_parent(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if parent.parent().is_some() => match entries.get(parent) {
+                Some(Entry::Dir) => Ok(()),
+                _ => Err(not_found(parent)),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Entry::File(contents)) => Ok(contents.clone()),
+            Some(Entry::Dir) => Err(io::Error::other(format!(
+                "{} is a directory",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        Self::check_parent(&entries, path)?;
+        if let Some(Entry::Dir) = entries.get(path) {
+            return Err(io::Error::other(format!(
+                "{} is a directory",
+                path.display()
+            )));
+        }
+        entries.insert(path.to_path_buf(), Entry::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(from) {
+            return Err(not_found(from));
+        }
+        Self::check_parent(&entries, to)?;
+        // Directories are moved with everything in them
+        let moved: Vec<PathBuf> = entries
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let entry = entries.remove(&path).unwrap();
+            let relative_path = path.strip_prefix(from).unwrap();
+            if relative_path.as_os_str().is_empty() {
+                entries.insert(to.to_path_buf(), entry);
+            } else {
+                entries.insert(to.join(relative_path), entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(Entry::File(_)) => {
+                entries.remove(path);
+                Ok(())
+            }
+            Some(Entry::Dir) => Err(io::Error::other(format!(
+                "{} is a directory",
+                path.display()
+            ))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(path) {
+            return Err(not_found(path));
+        }
+        entries.retain(|entry_path, _| !entry_path.starts_with(path));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for dir in path.ancestors() {
+            if dir.parent().is_none() {
+                break;
+            }
+            match entries.get(dir) {
+                Some(Entry::Dir) => {}
+                Some(Entry::File(_)) => {
+                    return Err(io::Error::other(format!("{} is a file", dir.display())));
+                }
+                None => {
+                    entries.insert(dir.to_path_buf(), Entry::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.parent().is_none() || self.entries.lock().unwrap().contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none()
+            || matches!(self.entries.lock().unwrap().get(path), Some(Entry::Dir))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        if !self.is_dir(path) {
+            return Err(not_found(path));
+        }
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|entry_path| entry_path.parent() == Some(path))
+            .filter_map(|entry_path| entry_path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::{bail, Context, Result};
+
+use super::{
+    convert_dir, load_app, load_converted_apps,
+    root::CitadelRoot,
+    tor::{load_authorized_clients, save_authorized_clients},
+    ConvertOptions, ConvertReport,
+};
+use crate::composegenerator::{types::Permissions, v4::convert::get_missing_dependencies};
+
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    /// Start the app's containers after converting it, requires the docker feature
+    pub start: bool,
+}
+
+/// What happened when an app was installed
+#[derive(Debug, Default)]
+pub struct InstallReport {
+    /// Why the app's containers could not be started, the app stays installed
+    pub start_error: Option<anyhow::Error>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UninstallOptions {
+    /// Stop the app's containers before removing it, requires the docker feature
+    pub stop: bool,
+    /// Delete the app's data directory
+    pub remove_data: bool,
+    /// Delete the keys of the app's hidden services and its authorized Tor clients,
+    /// the app gets new onion addresses if it is installed again
+    pub remove_tor: bool,
+    /// Drop the app's IP addresses and ports, so other apps can take them,
+    /// otherwise they stay reserved for the app
+    pub remove_allocations: bool,
+}
+
+fn describe_dependency(dependency: &Permissions) -> String {
+    match dependency {
+        Permissions::OneDependency(dep) => dep.clone(),
+        Permissions::AlternativeDependency(deps) => deps.join(" or "),
+    }
+}
+
+#[cfg(feature = "docker")]
+fn start_containers(root: &CitadelRoot, app_id: &str) -> Result<()> {
+    super::docker::start_app(root, app_id).map(|_| ())
+}
+
+#[cfg(not(feature = "docker"))]
+fn start_containers(_root: &CitadelRoot, _app_id: &str) -> Result<()> {
+    bail!("app-cli was built without Docker support")
+}
+
+#[cfg(feature = "docker")]
+fn stop_containers(app_id: &str) -> Result<()> {
+    super::docker::stop_app(app_id).map(|_| ())
+}
+
+#[cfg(not(feature = "docker"))]
+fn stop_containers(_app_id: &str) -> Result<()> {
+    bail!("app-cli was built without Docker support")
+}
+
+/// Converts only the given app, so its output reflects the change to the installed apps
+fn convert_app(root: &CitadelRoot, app_id: &str, options: ConvertOptions) -> Result<ConvertReport> {
+    let options = ConvertOptions {
+        app: Some(app_id.to_string()),
+        ..options
+    };
+    convert_dir(root, &options)
+}
+
+/// Installs a downloaded app and generates its files
+///
+/// user.json is written together with the app's files, so if the app can not be converted, nothing changes.
+pub fn install(
+    root: &CitadelRoot,
+    app_id: &str,
+    options: &InstallOptions,
+) -> Result<InstallReport> {
+    let mut installed_apps = root.installed_apps()?;
+    if installed_apps.iter().any(|app| app == app_id) {
+        bail!("App {} is already installed", app_id);
+    }
+    let services = root.installed_services();
+    let app_yml = match load_app(root, app_id, &services) {
+        Some(app_yml) => app_yml.with_context(|| format!("App {} is invalid", app_id))?,
+        None => bail!("App {} is not downloaded", app_id),
+    };
+    let missing = get_missing_dependencies(&app_yml.metadata.permissions, &services);
+    if !missing.is_empty() {
+        let missing: Vec<String> = missing.iter().map(describe_dependency).collect();
+        bail!(
+            "App {} requires {}, install them first",
+            app_id,
+            missing.join(", ")
+        );
+    }
+
+    installed_apps.push(app_id.to_string());
+    let convert_options = ConvertOptions {
+        installed_apps: Some(installed_apps),
+        ..Default::default()
+    };
+    convert_app(root, app_id, convert_options)?;
+    let mut report = InstallReport::default();
+    if options.start {
+        report.start_error = start_containers(root, app_id).err();
+    }
+    Ok(report)
+}
+
+/// The installed apps that would be missing a dependency if an app was uninstalled
+fn dependents(root: &CitadelRoot, app_id: &str) -> Vec<String> {
+    let services = root.installed_services();
+    let remaining: Vec<String> = services
+        .iter()
+        .filter(|service| *service != app_id)
+        .cloned()
+        .collect();
+    root.installed_apps()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|app| app != app_id)
+        .filter(|app| match load_app(root, app, &services) {
+            // Apps that already miss dependencies do not depend on this app
+            Some(Ok(app_yml)) => {
+                let permissions = &app_yml.metadata.permissions;
+                get_missing_dependencies(permissions, &remaining).len()
+                    > get_missing_dependencies(permissions, &services).len()
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+/// Uninstalls an app and regenerates its files, optionally removing what the app leaves behind
+///
+/// Apps other installed apps depend on can not be uninstalled.
+pub fn uninstall(root: &CitadelRoot, app_id: &str, options: &UninstallOptions) -> Result<()> {
+    let installed_apps = root.installed_apps()?;
+    if !installed_apps.iter().any(|app| app == app_id) {
+        bail!("App {} is not installed", app_id);
+    }
+    let dependents = dependents(root, app_id);
+    if !dependents.is_empty() {
+        bail!(
+            "App {} is required by {}, uninstall them first",
+            app_id,
+            dependents.join(", ")
+        );
+    }
+    // The hidden services have to be known before the app is converted again
+    let hidden_service_dirs: Vec<String> = load_converted_apps(root)
+        .ok()
+        .and_then(|mut apps| apps.remove(app_id))
+        .map(|app| {
+            app.hidden_services
+                .into_iter()
+                .map(|service| service.dir)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if options.stop {
+        stop_containers(app_id)?;
+    }
+    let remaining_apps: Vec<String> = installed_apps
+        .iter()
+        .filter(|app| *app != app_id)
+        .cloned()
+        .collect();
+    // Removed before the conversion, so they are no longer in the generated Tor files
+    let mut previous_clients = None;
+    if options.remove_tor {
+        let mut clients = load_authorized_clients(root)?;
+        if clients.remove(app_id).is_some() {
+            previous_clients = Some(load_authorized_clients(root)?);
+            save_authorized_clients(root, &clients)?;
+        }
+    }
+    // The app stays in the apps directory, but its output and its entries in the shared files are removed
+    let convert_options = ConvertOptions {
+        remove: true,
+        reset_allocations: options.remove_allocations,
+        installed_apps: Some(remaining_apps),
+        ..Default::default()
+    };
+    if let Err(error) = convert_app(root, app_id, convert_options) {
+        if let Some(clients) = previous_clients {
+            save_authorized_clients(root, &clients)?;
+        }
+        return Err(error);
+    }
+
+    if options.remove_data {
+        let data_dir = root.app_data_dir(app_id);
+        if root.fs().exists(&data_dir) {
+            root.fs()
+                .remove_dir_all(&data_dir)
+                .with_context(|| format!("Error removing {}", data_dir.display()))?;
+        }
+    }
+    if options.remove_tor {
+        for dir in hidden_service_dirs {
+            let dir = root.hidden_service_dir(&dir);
+            if root.fs().exists(&dir) {
+                root.fs()
+                    .remove_dir_all(&dir)
+                    .with_context(|| format!("Error removing {}", dir.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use super::{install, uninstall, InstallOptions, UninstallOptions};
+    use crate::cli::{changes::rollback, fs::MemoryFilesystem, root::CitadelRoot};
+
+    fn app_yml(name: &str, permissions: &str) -> String {
+        format!(
+            "citadel_version: 4
+metadata:
+  name: {name}
+  version: 1.0.0
+  category: Test
+  tagline: A test app
+  developers: {{Citadel: https://runcitadel.space}}
+  permissions: {permissions}
+  repo: {{Public: https://github.com/runcitadel/apps}}
+  support: https://runcitadel.space
+  description: A test app
+services:
+  main:
+    image: ghcr.io/runcitadel/example:main
+    port: 3000
+"
+        )
+    }
+
+    #[test]
+    fn installs_and_uninstalls_apps() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        root.save(&root.app_yml("base"), &app_yml("Base", "[]"))
+            .unwrap();
+        root.save(&root.app_yml("addon"), &app_yml("Addon", "[base]"))
+            .unwrap();
+        root.save(
+            &root.user_json(),
+            r#"{"installedApps":[],"name":"Satoshi"}"#,
+        )
+        .unwrap();
+
+        assert!(install(&root, "addon", &InstallOptions::default()).is_err());
+        assert!(install(&root, "missing", &InstallOptions::default()).is_err());
+        install(&root, "base", &InstallOptions::default()).unwrap();
+        install(&root, "addon", &InstallOptions::default()).unwrap();
+        assert!(install(&root, "base", &InstallOptions::default()).is_err());
+        assert_eq!(root.installed_apps().unwrap(), ["base", "addon"]);
+        assert!(root.app_dir("addon").join("docker-compose.yml").exists());
+
+        // addon still needs base
+        assert!(uninstall(&root, "base", &UninstallOptions::default()).is_err());
+        root.save(&root.app_data_dir("addon").join("data"), "data")
+            .unwrap();
+        let options = UninstallOptions {
+            remove_data: true,
+            remove_allocations: true,
+            ..Default::default()
+        };
+        uninstall(&root, "addon", &options).unwrap();
+        assert!(uninstall(&root, "addon", &options).is_err());
+        assert_eq!(root.installed_apps().unwrap(), ["base"]);
+        assert!(!root.app_data_dir("addon").exists());
+        let user_json: serde_json::Value = root.load_json(&root.user_json()).unwrap().unwrap();
+        assert_eq!(user_json["name"], "Satoshi");
+    }
+
+    #[test]
+    fn uninstall_removes_app_from_shared_files() {
+        let root = CitadelRoot::new("/citadel").with_filesystem(Arc::new(MemoryFilesystem::new()));
+        root.save(&root.app_yml("example"), &app_yml("Example", "[]"))
+            .unwrap();
+        root.save(&root.user_json(), r#"{"installedApps":[]}"#)
+            .unwrap();
+        let read = |path: &Path| root.read(path).unwrap().unwrap_or_default();
+        let compose_yml = root.app_dir("example").join("docker-compose.yml");
+
+        // Templates already see the app as installed
+        root.save(&root.seed_file(), "seed").unwrap();
+        root.save(
+            &root.app_dir("example").join("installed.jinja"),
+            r#"{{ "example" in services }}"#,
+        )
+        .unwrap();
+        install(&root, "example", &InstallOptions::default()).unwrap();
+        assert_eq!(read(&root.app_dir("example").join("installed")), "true");
+        let ips = read(&root.ips_file());
+        assert!(ips.contains("APP_EXAMPLE_MAIN_IP"));
+        assert!(read(&root.torrc_file(1)).contains("app-example"));
+        assert!(root.fs().exists(&compose_yml));
+
+        // user.json is part of the same generation as the app's files
+        assert!(rollback(&root).unwrap());
+        assert!(root.installed_apps().unwrap().is_empty());
+        assert!(!root.fs().exists(&compose_yml));
+        assert!(rollback(&root).unwrap());
+        assert_eq!(root.installed_apps().unwrap(), ["example"]);
+
+        // The addresses stay reserved for the app
+        uninstall(&root, "example", &UninstallOptions::default()).unwrap();
+        assert_eq!(read(&root.ips_file()), ips);
+        assert!(!read(&root.torrc_file(1)).contains("app-example"));
+        assert!(!read(&root.converted_apps_file()).contains("example"));
+        assert!(!root.fs().exists(&compose_yml));
+        assert!(root.fs().exists(&root.hidden_service_dir("app-example")));
+
+        install(&root, "example", &InstallOptions::default()).unwrap();
+        assert_eq!(read(&root.ips_file()), ips);
+        let options = UninstallOptions {
+            remove_tor: true,
+            remove_allocations: true,
+            ..Default::default()
+        };
+        uninstall(&root, "example", &options).unwrap();
+        assert!(!read(&root.ips_file()).contains("APP_EXAMPLE"));
+        assert!(!read(&root.port_cache_file()).contains("example"));
+        assert!(!read(&root.torrc_file(1)).contains("app-example"));
+        assert!(!root.fs().exists(&compose_yml));
+        assert!(!root.fs().exists(&root.hidden_service_dir("app-example")));
+    }
+}
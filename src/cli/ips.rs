@@ -0,0 +1,269 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub const DEFAULT_SUBNET: &str = "10.21.21.0/24";
+
+// The first addresses of the subnet are used by Citadel's own services
+const FIRST_APP_HOST: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Subnet {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Subnet {
+    pub fn new(address: Ipv4Addr, prefix: u8) -> Result<Self> {
+        if !(8..=32).contains(&prefix) {
+            bail!("Subnet prefix /{} is not supported", prefix);
+        }
+        let mask = u32::MAX << (32 - prefix);
+        let subnet = Ipv4Subnet {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix,
+        };
+        // Apps get the addresses after the ones reserved for Citadel and before the broadcast address
+        if subnet.host_count() <= FIRST_APP_HOST + 1 {
+            bail!("Subnet prefix /{} leaves no addresses for apps", prefix);
+        }
+        Ok(subnet)
+    }
+
+    fn host_count(&self) -> u32 {
+        1 << (32 - self.prefix)
+    }
+
+    /// The nth address in this subnet
+    fn nth(&self, n: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + n)
+    }
+
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.nth(1)
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let offset = u32::from(*ip).wrapping_sub(u32::from(self.network));
+        // The network and broadcast addresses can not be used by containers
+        offset > 0 && offset < self.host_count() - 1
+    }
+}
+
+impl FromStr for Ipv4Subnet {
+    type Err = anyhow::Error;
+
+    fn from_str(subnet: &str) -> Result<Self> {
+        let (address, prefix) = subnet.split_once('/').unwrap_or((subnet, "24"));
+        Ipv4Subnet::new(address.trim().parse()?, prefix.trim().parse()?)
+    }
+}
+
+impl fmt::Display for Ipv4Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Ipv4Subnet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Subnet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Reads the app subnet from the .env file
+///
+/// APPS_SUBNET can be set to a subnet in CIDR notation,
+/// otherwise the /24 network of NETWORK_IP is used.
+pub fn subnet_from_env(env_vars: &HashMap<String, String>) -> Result<Ipv4Subnet> {
+    if let Some(subnet) = env_vars.get("APPS_SUBNET") {
+        subnet.parse()
+    } else if let Some(network_ip) = env_vars.get("NETWORK_IP") {
+        Ipv4Subnet::new(network_ip.parse()?, 24)
+    } else {
+        DEFAULT_SUBNET.parse()
+    }
+}
+
+/// Gets the addresses of the built-in services from the .env file
+pub fn reserved_ips_from_env(env_vars: &HashMap<String, String>) -> Vec<Ipv4Addr> {
+    env_vars
+        .iter()
+        .filter(|(key, _)| key.ends_with("_IP") && !key.starts_with("APP_"))
+        .filter_map(|(_, value)| value.parse().ok())
+        .collect()
+}
+
+pub struct IpAllocator {
+    subnet: Ipv4Subnet,
+    reserved: HashSet<Ipv4Addr>,
+    used: HashSet<Ipv4Addr>,
+}
+
+impl IpAllocator {
+    pub fn new(subnet: Ipv4Subnet, reserved: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        let mut reserved: HashSet<Ipv4Addr> = reserved.into_iter().collect();
+        reserved.insert(subnet.gateway());
+        IpAllocator {
+            subnet,
+            reserved,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Marks an existing assignment as used
+    ///
+    /// Returns false if the address can not be used, because it is outside the subnet,
+    /// belongs to a built-in service or was already assigned to another container.
+    pub fn claim(&mut self, ip: Ipv4Addr) -> bool {
+        if !self.subnet.contains(&ip) || self.reserved.contains(&ip) {
+            return false;
+        }
+        self.used.insert(ip)
+    }
+
+    /// Makes an address available again
+    pub fn release(&mut self, ip: Ipv4Addr) {
+        self.used.remove(&ip);
+    }
+
+    pub fn allocate(&mut self) -> Result<Ipv4Addr> {
+        for n in FIRST_APP_HOST..self.subnet.host_count() {
+            let ip = self.subnet.nth(n);
+            if !self.subnet.contains(&ip) {
+                break;
+            }
+            if self.reserved.contains(&ip) || self.used.contains(&ip) {
+                continue;
+            }
+            self.used.insert(ip);
+            return Ok(ip);
+        }
+        bail!("No free IP addresses left in {}", self.subnet);
+    }
+
+    /// Claims all valid addresses in an existing IP map and assigns new ones to all others
+    ///
+    /// Only entries can_reassign returns true for get new addresses, the others keep theirs
+    /// even if they are invalid, because the output that uses them is not regenerated.
+    pub fn reconcile(
+        &mut self,
+        ip_map: &mut HashMap<String, String>,
+        can_reassign: impl Fn(&str) -> bool,
+    ) -> Result<()> {
+        // Sort the keys so the same entry keeps its address if there are duplicates
+        let (entries, fixed): (BTreeMap<String, String>, BTreeMap<String, String>) = ip_map
+            .clone()
+            .into_iter()
+            .partition(|(key, _)| can_reassign(key));
+        for value in fixed.values() {
+            if let Ok(ip) = value.parse() {
+                self.claim(ip);
+            }
+        }
+        for (key, value) in entries {
+            if value.parse().map(|ip| self.claim(ip)).unwrap_or(false) {
+                continue;
+            }
+            let ip = self.allocate()?;
+            tracing::info!("Reassigning {} from {} to {}", key, value, ip);
+            ip_map.insert(key, ip.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use super::{IpAllocator, Ipv4Subnet};
+    use crate::map;
+
+    #[test]
+    fn parse_subnet() {
+        let subnet: Ipv4Subnet = "10.21.0.5/16".parse().unwrap();
+        assert_eq!(subnet.to_string(), "10.21.0.0/16");
+        assert_eq!(subnet.gateway(), Ipv4Addr::new(10, 21, 0, 1));
+        assert!(subnet.contains(&Ipv4Addr::new(10, 21, 200, 3)));
+        assert!(!subnet.contains(&Ipv4Addr::new(10, 21, 255, 255)));
+        assert!("10.21.21.0/33".parse::<Ipv4Subnet>().is_err());
+    }
+
+    #[test]
+    fn rejects_subnets_without_app_addresses() {
+        let subnet: Ipv4Subnet = "10.21.21.0/27".parse().unwrap();
+        let mut allocator = IpAllocator::new(subnet, []);
+        assert_eq!(allocator.allocate().unwrap(), Ipv4Addr::new(10, 21, 21, 20));
+        for prefix in 28..=32 {
+            assert!(format!("10.21.21.0/{}", prefix)
+                .parse::<Ipv4Subnet>()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn skips_reserved_and_used() {
+        let subnet: Ipv4Subnet = "10.21.21.0/24".parse().unwrap();
+        let mut allocator = IpAllocator::new(subnet, [Ipv4Addr::new(10, 21, 21, 21)]);
+        assert!(allocator.claim(Ipv4Addr::new(10, 21, 21, 20)));
+        assert_eq!(allocator.allocate().unwrap(), Ipv4Addr::new(10, 21, 21, 22));
+    }
+
+    #[test]
+    fn grows_beyond_255() {
+        let subnet: Ipv4Subnet = "10.21.0.0/16".parse().unwrap();
+        let mut allocator = IpAllocator::new(subnet, []);
+        for _ in 0..300 {
+            allocator.allocate().unwrap();
+        }
+        assert_eq!(allocator.allocate().unwrap(), Ipv4Addr::new(10, 21, 1, 64));
+    }
+
+    #[test]
+    fn reassigns_duplicates() {
+        let subnet: Ipv4Subnet = "10.21.21.0/24".parse().unwrap();
+        let mut allocator = IpAllocator::new(subnet, []);
+        let mut ip_map: HashMap<String, String> = map! {
+            "APP_A_MAIN_IP" => "10.21.21.20".to_string(),
+            "APP_B_MAIN_IP" => "10.21.21.20".to_string(),
+            "APP_C_MAIN_IP" => "192.168.0.1".to_string()
+        };
+        allocator.reconcile(&mut ip_map, |_| true).unwrap();
+        assert_eq!(ip_map.get("APP_A_MAIN_IP").unwrap(), "10.21.21.20");
+        assert_eq!(ip_map.get("APP_B_MAIN_IP").unwrap(), "10.21.21.21");
+        assert_eq!(ip_map.get("APP_C_MAIN_IP").unwrap(), "10.21.21.22");
+    }
+
+    #[test]
+    fn only_reassigns_selected_entries() {
+        let subnet: Ipv4Subnet = "10.21.21.0/24".parse().unwrap();
+        let mut allocator = IpAllocator::new(subnet, []);
+        let mut ip_map: HashMap<String, String> = map! {
+            "APP_A_MAIN_IP" => "10.21.21.20".to_string(),
+            "APP_B_MAIN_IP" => "10.21.21.20".to_string(),
+            "APP_C_MAIN_IP" => "192.168.0.1".to_string()
+        };
+        allocator
+            .reconcile(&mut ip_map, |key| key.starts_with("APP_A_"))
+            .unwrap();
+        // A would keep its address if all entries were reconciled
+        assert_eq!(ip_map.get("APP_A_MAIN_IP").unwrap(), "10.21.21.21");
+        assert_eq!(ip_map.get("APP_B_MAIN_IP").unwrap(), "10.21.21.20");
+        assert_eq!(ip_map.get("APP_C_MAIN_IP").unwrap(), "192.168.0.1");
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::PathBuf,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::root::CitadelRoot;
+
+const LOCK_FILE: &str = ".app-cli.lock";
+
+/// How long to wait for other processes by default
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The process holding the lock, written to the lock file so others can show who they are waiting for
+#[derive(Serialize, Deserialize, Debug)]
+struct LockHolder {
+    pid: u32,
+    command: String,
+    /// Unix timestamp of when the lock was acquired
+    since: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+fn read_holder(file: &mut File) -> Option<LockHolder> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// An advisory lock on the apps directory, so only one process modifies the Citadel root at a time
+///
+/// This always uses the real filesystem, other processes could not see the lock otherwise.
+///
+/// The lock is released when this is dropped, or by the OS if the process exits.
+#[derive(Debug)]
+pub struct RootLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl RootLock {
+    /// Waits until no other process holds the lock, for at most timeout
+    pub fn acquire(root: &CitadelRoot, timeout: Duration) -> Result<Self> {
+        let apps_dir = root.apps_dir();
+        std::fs::create_dir_all(&apps_dir).context("Error creating apps directory")?;
+        let path = apps_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Error opening lock file {}", path.display()))?;
+        let start = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {
+                    if start.elapsed() >= timeout {
+                        let holder = match read_holder(&mut file) {
+                            Some(holder) => format!(
+                                "process {} ({}) since {}s",
+                                holder.pid,
+                                holder.command,
+                                now().saturating_sub(holder.since)
+                            ),
+                            None => "another process".to_string(),
+                        };
+                        bail!(
+                            "{} is locked by {}, gave up after waiting {}s. If that process is stuck, stop it and try again",
+                            root.path().display(),
+                            holder,
+                            timeout.as_secs()
+                        );
+                    }
+                    sleep(Duration::from_millis(100));
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(err).with_context(|| format!("Error locking {}", path.display()));
+                }
+            }
+        }
+        // The file is emptied when the lock is released, so a holder that is still listed crashed
+        if let Some(holder) = read_holder(&mut file) {
+            tracing::warn!(
+                "Taking over stale lock of process {} ({}), it did not exit cleanly",
+                holder.pid,
+                holder.command
+            );
+        }
+        let holder = LockHolder {
+            pid: std::process::id(),
+            command: std::env::args().collect::<Vec<String>>().join(" "),
+            since: now(),
+        };
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for RootLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            tracing::warn!("Error clearing lock file {}: {}", self.path.display(), err);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{RootLock, LOCK_FILE};
+    use crate::cli::root::CitadelRoot;
+
+    #[test]
+    fn locks_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CitadelRoot::new(dir.path());
+        let lock = RootLock::acquire(&root, Duration::ZERO).unwrap();
+        let err = RootLock::acquire(&root, Duration::from_millis(200)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("process {}", std::process::id())));
+        drop(lock);
+
+        // A lock file left behind by a crashed process does not block others
+        let lock_file = dir.path().join("apps").join(LOCK_FILE);
+        std::fs::write(
+            &lock_file,
+            r#"{"pid":1,"command":"app-cli convert","since":0}"#,
+        )
+        .unwrap();
+        let _lock = RootLock::acquire(&root, Duration::ZERO).unwrap();
+        assert!(std::fs::read_to_string(&lock_file)
+            .unwrap()
+            .contains(&format!("\"pid\":{}", std::process::id())));
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, TcpListener, UdpSocket},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::v4::types::{PortMapElement, PortPriority};
+
+// A port map as used during creating the port map
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PortCacheMapEntry {
+    pub app: String,
+    // Internal port
+    pub internal_port: u16,
+    pub container: String,
+    pub dynamic: bool,
+    pub implements: Option<String>,
+    pub priority: PortPriority,
+}
+
+// Outside port -> app
+pub type PortCacheMap = HashMap<u16, PortCacheMapEntry>;
+
+// App -> container -> ports
+pub type PortMap = HashMap<String, HashMap<String, Vec<PortMapElement>>>;
+
+pub static RESERVED_PORTS: [u16; 6] = [
+    80,    // Dashboard
+    433,   // Sometimes used by nginx with some setups
+    443,   // Dashboard SSL
+    8333,  // Bitcoin Core P2P
+    10009, // LND gRPC
+    8080,  // LND REST
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The port is reserved for Citadel itself
+    Reserved,
+    /// The port is already assigned to another app
+    UsedByApp(String),
+    /// Something on the host is already listening on the port
+    InUseOnHost,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Reserved => write!(f, "reserved"),
+            SkipReason::UsedByApp(app) => write!(f, "used by {}", app),
+            SkipReason::InUseOnHost => write!(f, "in use on the host"),
+        }
+    }
+}
+
+/// A record of how a port was assigned to a container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationTrace {
+    pub app: String,
+    pub container: String,
+    pub requested_port: u16,
+    pub assigned_port: u16,
+    /// Ports that were tried and skipped before the assigned port
+    pub skipped: Vec<(u16, SkipReason)>,
+}
+
+impl fmt::Display for AllocationTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}: requested {}, assigned {}",
+            self.app, self.container, self.requested_port, self.assigned_port
+        )?;
+        for (port, reason) in &self.skipped {
+            write!(f, ", skipped {} ({})", port, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks if anything on the host listens on a port by trying to bind it
+pub fn is_port_in_use(port: u16) -> bool {
+    let in_use = |result: std::io::Result<()>| matches!(result, Err(err) if err.kind() == std::io::ErrorKind::AddrInUse);
+    in_use(TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).map(|_| ()))
+        || in_use(TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).map(|_| ()))
+        || in_use(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).map(|_| ()))
+        || in_use(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).map(|_| ()))
+}
+
+pub struct PortAllocator {
+    cache: PortCacheMap,
+    reserved_ports: Vec<u16>,
+    probe_host: bool,
+    trace: Vec<AllocationTrace>,
+    /// If set, only ports of this app can be moved
+    only_app: Option<String>,
+}
+
+impl PortAllocator {
+    pub fn new(cache: PortCacheMap, probe_host: bool) -> Self {
+        PortAllocator {
+            cache,
+            reserved_ports: RESERVED_PORTS.to_vec(),
+            probe_host,
+            trace: Vec::new(),
+            only_app: None,
+        }
+    }
+
+    /// Replaces the ports that are never assigned to apps
+    pub fn set_reserved_ports(&mut self, ports: &[u16]) {
+        self.reserved_ports = ports.to_vec();
+    }
+
+    /// Prevents moving ports of any app except the given one
+    ///
+    /// This is used when only one app is converted, the other apps would keep using their old ports.
+    pub fn keep_other_apps(&mut self, app: &str) {
+        self.only_app = Some(app.to_string());
+    }
+
+    pub fn cache(&self) -> &PortCacheMap {
+        &self.cache
+    }
+
+    pub fn trace(&self) -> &[AllocationTrace] {
+        &self.trace
+    }
+
+    // A container can have multiple ports, so also compare the internal port
+    // Dynamic ports change their internal port when they are moved, so they match any dynamic entry
+    fn is_owned_by(
+        cache_entry: &PortCacheMapEntry,
+        app: &str,
+        container: &str,
+        internal_port: u16,
+        dynamic: bool,
+    ) -> bool {
+        cache_entry.app == app
+            && cache_entry.container == container
+            && (cache_entry.internal_port == internal_port || (cache_entry.dynamic && dynamic))
+    }
+
+    fn skip_reason(&self, port: u16) -> Option<SkipReason> {
+        if self.reserved_ports.contains(&port) {
+            Some(SkipReason::Reserved)
+        } else if let Some(cache_entry) = self.cache.get(&port) {
+            Some(SkipReason::UsedByApp(cache_entry.app.clone()))
+        } else if self.probe_host && is_port_in_use(port) {
+            Some(SkipReason::InUseOnHost)
+        } else {
+            None
+        }
+    }
+
+    /// The first free port starting at the suggested one, None if all ports above it are taken
+    fn get_new_port(
+        &self,
+        app: &str,
+        container: &str,
+        internal_port: u16,
+        mut suggested_port: u16,
+        dynamic: bool,
+        skipped: &mut Vec<(u16, SkipReason)>,
+    ) -> Option<u16> {
+        while let Some(reason) = self.skip_reason(suggested_port) {
+            if let Some(cache_entry) = self.cache.get(&suggested_port) {
+                if Self::is_owned_by(cache_entry, app, container, internal_port, dynamic) {
+                    return Some(suggested_port);
+                }
+            }
+            skipped.push((suggested_port, reason));
+            suggested_port = suggested_port.checked_add(1)?;
+        }
+
+        Some(suggested_port)
+    }
+
+    pub fn validate_port(
+        &mut self,
+        app: &str,
+        container: &str,
+        suggested_port: u16,
+        priority: PortPriority,
+        dynamic: bool,
+        implements: Option<String>,
+    ) -> Result<()> {
+        let mut skipped = Vec::new();
+        let assigned_port = if let Some(key) = self.cache.get(&suggested_port) {
+            if Self::is_owned_by(key, app, container, suggested_port, dynamic)
+                || (key.implements == implements && container == "service")
+            {
+                return Ok(());
+            }
+            let can_move = self
+                .only_app
+                .as_ref()
+                .is_none_or(|only_app| *only_app == key.app);
+            if key.priority > priority && !can_move {
+                tracing::warn!(
+                    "Port {} of {}/{} is used by {}, which can not be moved without converting all apps",
+                    suggested_port,
+                    app,
+                    container,
+                    key.app
+                );
+            }
+            let mut displaced_skipped = Vec::new();
+            // The existing app can only be moved if there is a free port above the contested one
+            let displaced_port = if key.priority > priority && can_move {
+                suggested_port.checked_add(1).and_then(|start| {
+                    self.get_new_port(
+                        &key.app,
+                        &key.container,
+                        key.internal_port,
+                        start,
+                        key.dynamic,
+                        &mut displaced_skipped,
+                    )
+                })
+            } else {
+                None
+            };
+            if let Some(new_port) = displaced_port {
+                // Move the existing app to a new port
+                let mut new_port_map = self.cache.remove(&suggested_port).unwrap();
+                if new_port_map.dynamic {
+                    new_port_map.internal_port = new_port;
+                }
+                self.trace.push(AllocationTrace {
+                    app: new_port_map.app.clone(),
+                    container: new_port_map.container.clone(),
+                    requested_port: suggested_port,
+                    assigned_port: new_port,
+                    skipped: displaced_skipped,
+                });
+                self.cache.insert(new_port, new_port_map);
+                // And insert the new app
+                self.cache.insert(
+                    suggested_port,
+                    PortCacheMapEntry {
+                        app: app.to_string(),
+                        internal_port: suggested_port,
+                        container: container.to_string(),
+                        dynamic,
+                        implements,
+                        priority,
+                    },
+                );
+                suggested_port
+            } else {
+                // Move the new app to a new port
+                let Some(new_port) = self.get_new_port(
+                    app,
+                    container,
+                    suggested_port,
+                    suggested_port,
+                    dynamic,
+                    &mut skipped,
+                ) else {
+                    bail!("No free port left for {}/{}", app, container);
+                };
+                self.cache.insert(
+                    new_port,
+                    PortCacheMapEntry {
+                        app: app.to_string(),
+                        internal_port: if dynamic { new_port } else { suggested_port },
+                        container: container.to_string(),
+                        dynamic,
+                        implements,
+                        priority,
+                    },
+                );
+                new_port
+            }
+        } else {
+            // Reserved ports are only avoided if another app already uses them
+            // But ports that are taken on the host would break the app
+            let new_port = if self.probe_host && is_port_in_use(suggested_port) {
+                let Some(new_port) = self.get_new_port(
+                    app,
+                    container,
+                    suggested_port,
+                    suggested_port,
+                    dynamic,
+                    &mut skipped,
+                ) else {
+                    bail!("No free port left for {}/{}", app, container);
+                };
+                new_port
+            } else {
+                suggested_port
+            };
+            self.cache.insert(
+                new_port,
+                PortCacheMapEntry {
+                    app: app.to_string(),
+                    internal_port: if dynamic { new_port } else { suggested_port },
+                    container: container.to_string(),
+                    dynamic,
+                    implements,
+                    priority,
+                },
+            );
+            new_port
+        };
+        self.trace.push(AllocationTrace {
+            app: app.to_string(),
+            container: container.to_string(),
+            requested_port: suggested_port,
+            assigned_port,
+            skipped,
+        });
+        Ok(())
+    }
+
+    /// Converts the port cache map into the port map used during conversion
+    pub fn port_map(&self) -> PortMap {
+        let mut port_map = PortMap::new();
+        for (port_number, cache_entry) in &self.cache {
+            let key = match &cache_entry.implements {
+                Some(implements) if cache_entry.container == "service" => implements.clone(),
+                _ => cache_entry.app.clone(),
+            };
+            port_map
+                .entry(key)
+                .or_default()
+                .entry(cache_entry.container.clone())
+                .or_default()
+                .push(PortMapElement {
+                    dynamic: cache_entry.dynamic,
+                    internal_port: cache_entry.internal_port,
+                    public_port: *port_number,
+                });
+        }
+        // The cache is not ordered, but the port map should be the same on every run
+        for containers in port_map.values_mut() {
+            for ports in containers.values_mut() {
+                ports.sort_by_key(|port| port.public_port);
+            }
+        }
+        port_map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PortAllocator, PortCacheMap, SkipReason};
+    use crate::composegenerator::v4::types::PortPriority;
+
+    #[test]
+    fn keeps_existing_assignment() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        allocator
+            .validate_port("app", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        assert_eq!(allocator.cache().len(), 1);
+        assert_eq!(allocator.cache().get(&3000).unwrap().app, "app");
+    }
+
+    #[test]
+    fn moves_conflicting_app() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app-1", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        allocator
+            .validate_port("app-2", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        let entry = allocator.cache().get(&3001).unwrap();
+        assert_eq!(entry.app, "app-2");
+        assert_eq!(entry.internal_port, 3000);
+        let trace = allocator.trace().last().unwrap();
+        assert_eq!(trace.assigned_port, 3001);
+        assert_eq!(
+            trace.skipped,
+            vec![(3000, SkipReason::UsedByApp("app-1".to_string()))]
+        );
+    }
+
+    #[test]
+    fn displaced_app_gets_a_new_port() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app-1", "main", 3000, PortPriority::Required, false, None)
+            .unwrap();
+        allocator
+            .validate_port("app-2", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        assert_eq!(allocator.cache().get(&3000).unwrap().app, "app-2");
+        assert_eq!(allocator.cache().get(&3001).unwrap().app, "app-1");
+    }
+
+    #[test]
+    fn does_not_overflow_at_last_port() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app-1", "main", 65535, PortPriority::Optional, false, None)
+            .unwrap();
+        // app-1 can not move up, and app-2 can not either
+        assert!(allocator
+            .validate_port("app-2", "main", 65535, PortPriority::Required, false, None)
+            .is_err());
+        assert_eq!(allocator.cache().get(&65535).unwrap().app, "app-1");
+    }
+
+    #[test]
+    fn keeps_other_apps() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app-1", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        allocator.keep_other_apps("app-2");
+        allocator
+            .validate_port("app-2", "main", 3000, PortPriority::Required, false, None)
+            .unwrap();
+        assert_eq!(allocator.cache().get(&3000).unwrap().app, "app-1");
+        assert_eq!(allocator.cache().get(&3001).unwrap().app, "app-2");
+    }
+
+    #[test]
+    fn multiple_ports_per_container() {
+        let mut allocator = PortAllocator::new(PortCacheMap::new(), false);
+        allocator
+            .validate_port("app", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        allocator
+            .validate_port("app", "main", 3001, PortPriority::Optional, false, None)
+            .unwrap();
+        allocator
+            .validate_port("other", "main", 3000, PortPriority::Optional, false, None)
+            .unwrap();
+        assert_eq!(allocator.cache().get(&3002).unwrap().app, "other");
+        let port_map = allocator.port_map();
+        assert_eq!(port_map.get("app").unwrap().get("main").unwrap().len(), 2);
+    }
+}
@@ -1,112 +1,119 @@
+#[cfg(feature = "umbrel")]
 use crate::composegenerator::compose::types::ComposeSpecification;
-use std::{collections::HashMap, io::Read, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use super::{tera, UserJson};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
 
-pub fn preprocess_apps(citadel_root: &Path, app_dir: &Path) {
-    let mut citadel_seed = None;
+use super::{fs::Filesystem, root::CitadelRoot, tera};
 
-    let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
+#[cfg(feature = "umbrel")]
+fn convert_umbrel_app(fs: &dyn Filesystem, app_dir: &Path) -> Result<String> {
+    let compose_yml = fs
+        .read_to_string(&app_dir.join("docker-compose.yml"))
+        .context("Error opening docker-compose.yml")?;
+    let umbrel_app_yml = fs
+        .read_to_string(&app_dir.join("umbrel-app.yml"))
+        .context("Error opening umbrel-app.yml")?;
+    let umbrel_app_yml: crate::composegenerator::umbrel::types::Metadata =
+        serde_yaml::from_str(&umbrel_app_yml).context("Error parsing umbrel-app.yml")?;
+    let compose_yml_parsed: ComposeSpecification =
+        serde_yaml::from_str(&compose_yml).context("Error parsing docker-compose.yml")?;
+    let result = crate::composegenerator::umbrel::convert::convert_compose(
+        compose_yml_parsed,
+        umbrel_app_yml,
+    );
+    serde_yaml::to_string(&result).context("Error saving app.yml")
+}
+
+/// Renders an app's templates and converts it if it is an Umbrel app
+///
+/// Returns the generated files (path -> contents) or the reason if the app can not be converted.
+fn preprocess_app(
+    fs: &dyn Filesystem,
+    app_dir: &Path,
+    app_id: &str,
+    services: &[String],
+    citadel_seed: &Option<String>,
+    env_vars: &HashMap<String, String>,
+) -> Result<BTreeMap<PathBuf, String>, String> {
+    let files =
+        tera::convert_app_jinja_files(fs, app_dir, services, citadel_seed, &Some(env_vars.clone()))
+            .map_err(|tera_error| format!("Error rendering templates: {}", tera_error))?;
 
-    if citadel_seed_file.exists() {
-        let mut citadel_seed_file = std::fs::File::open(citadel_seed_file).unwrap();
-        let mut citadel_seed_str = String::new();
-        citadel_seed_file
-            .read_to_string(&mut citadel_seed_str)
-            .unwrap();
-        citadel_seed = Some(citadel_seed_str);
+    let app_yml = app_dir.join("app.yml");
+    if !fs.exists(&app_yml) && !files.contains_key(&app_yml) {
+        #[cfg(feature = "umbrel")]
+        {
+            if fs.exists(&app_dir.join("umbrel-app.yml")) {
+                let contents =
+                    convert_umbrel_app(fs, app_dir).map_err(|err| format!("{:#}", err))?;
+                let mut files = files;
+                files.insert(app_yml, contents);
+                return Ok(files);
+            }
+        }
+        eprintln!("Warning: App {} does not have an app.yml file!", app_id);
     }
+    Ok(files)
+}
 
-    let apps = std::fs::read_dir(app_dir).expect("Error reading apps directory!");
-    let apps = apps.filter(|entry| {
-        let entry = entry.as_ref().expect("Error reading app directory!");
-        let path = entry.path();
+/// The result of preprocessing apps
+#[derive(Debug, Default)]
+pub struct PreprocessedApps {
+    /// The rendered templates and converted app.yml files (path -> contents), they are not written yet
+    pub files: BTreeMap<PathBuf, String>,
+    /// The apps that could not be preprocessed (app id -> reason)
+    pub failed: BTreeMap<String, String>,
+}
 
-        path.is_dir()
-    });
+/// Renders templates and converts Umbrel apps, if only_app is set, all other apps are skipped
+///
+/// app_dir is read from fs, it does not have to be in the Citadel root.
+/// services are the installed apps and built-in services the templates can check for.
+pub fn preprocess_apps(
+    root: &CitadelRoot,
+    fs: &dyn Filesystem,
+    app_dir: &Path,
+    services: &[String],
+    only_app: Option<&str>,
+) -> Result<PreprocessedApps> {
+    let citadel_seed = root.citadel_seed()?;
 
-    let mut env_vars = Vec::new();
+    let apps: Vec<String> = fs
+        .read_dir(app_dir)
+        .context("Error reading apps directory")?
+        .into_iter()
+        .filter(|app_id| fs.is_dir(&app_dir.join(app_id)))
+        .collect();
 
-    #[allow(deprecated)]
-    if let Ok(dot_env) = dotenv::from_filename_iter(citadel_root.join(".env")) {
-        env_vars = dot_env.collect();
-    }
+    let env_vars = root.env_vars();
 
     if env_vars.is_empty() && citadel_seed.is_none() {
         eprintln!("Warning: Citadel does not seem to be set up yet!");
     }
 
-    let mut services = Vec::<String>::new();
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
-    if let Ok(user_json) = user_json {
-        let user_json = serde_json::from_reader::<_, UserJson>(user_json);
-        if let Ok(user_json) = user_json {
-            services = user_json.installed_apps;
-        }
-    }
-    services.append(&mut vec!["bitcoind".to_string(), "lnd".to_string()]);
-
-    // Collect the env vars into an hashmap, logging errors
-    let env_vars: HashMap<String, String> = env_vars
-        .into_iter()
-        .filter_map(|result| {
-            if let Ok((key, value)) = result {
-                Some((key, value))
-            } else {
-                eprintln!("Warning: Failed to parse env var: {:?}", result);
-                None
-            }
+    // Apps do not depend on each other, so they are preprocessed in parallel
+    let results: Vec<_> = apps
+        .par_iter()
+        .filter(|app_id| only_app.is_none_or(|only_app| *app_id == only_app))
+        .map(|app_id| {
+            let app_dir = app_dir.join(app_id);
+            let result = preprocess_app(fs, &app_dir, app_id, services, &citadel_seed, &env_vars);
+            (app_id.clone(), result)
         })
         .collect();
-
-    for app in apps {
-        let app = app.expect("Error reading app directory!");
-        let app_id = app.file_name();
-        let app_id = app_id.to_str().unwrap();
-
-        if let Err(tera_error) = tera::convert_app_jinja_files(
-            &app.path(),
-            &services,
-            &citadel_seed,
-            &Some(env_vars.clone()),
-        ) {
-            eprintln!("Error converting app jinja files: {:?}", tera_error);
-            continue;
-        }
-
-        let app_yml = app.path().join("app.yml");
-        if !app_yml.exists() {
-            #[cfg(feature = "umbrel")]
-            {
-                let umbrel_app_yml = app.path().join("umbrel-app.yml");
-                if umbrel_app_yml.exists() {
-                    let compose_yml = std::fs::File::open(app.path().join("docker-compose.yml"))
-                        .expect("Error opening docker-compose.yml!");
-                    let umbrel_app_yml =
-                        std::fs::File::open(umbrel_app_yml).expect("Error opening umbrel-app.yml!");
-                    let umbrel_app_yml: crate::composegenerator::umbrel::types::Metadata =
-                        serde_yaml::from_reader(umbrel_app_yml)
-                            .expect("Error parsing umbrel-app.yml!");
-                    let compose_yml_parsed: ComposeSpecification =
-                        serde_yaml::from_reader(compose_yml)
-                            .expect("Error parsing docker-compose.yml!");
-                    let result = crate::composegenerator::umbrel::convert::convert_compose(
-                        compose_yml_parsed,
-                        umbrel_app_yml,
-                    );
-                    let writer =
-                        std::fs::File::create(&app_yml).expect("Error creating output file");
-                    serde_yaml::to_writer(writer, &result).expect("Error saving file!");
-                } else {
-                    eprintln!("Warning: App {} does not have an app.yml file!", app_id);
-                    continue;
-                }
-            }
-            #[cfg(not(feature = "umbrel"))]
-            {
-                eprintln!("Warning: App {} does not have an app.yml file!", app_id);
-                continue;
+    let mut preprocessed = PreprocessedApps::default();
+    for (app_id, result) in results {
+        match result {
+            Ok(files) => preprocessed.files.extend(files),
+            Err(reason) => {
+                preprocessed.failed.insert(app_id, reason);
             }
         }
     }
+    Ok(preprocessed)
 }
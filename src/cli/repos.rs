@@ -4,8 +4,12 @@ use std::{
     path::Path,
 };
 
-use super::{preprocessing::preprocess_apps, UserJson};
-use anyhow::Result;
+use super::{
+    fs::{Filesystem, OsFilesystem},
+    preprocessing::preprocess_apps,
+    root::CitadelRoot,
+};
+use anyhow::{Context, Result};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use tempdir::TempDir;
@@ -14,10 +18,11 @@ use crate::{composegenerator::load_config_as_v4, constants::MINIMUM_COMPATIBLE_A
 
 mod git;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AppSrc {
-    repo: String,
-    branch: String,
+/// A repository apps are downloaded from, as listed in sources.yml
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppSrc {
+    pub repo: String,
+    pub branch: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -104,19 +109,47 @@ fn get_subdir(app_store: &AppStoreV1) -> Option<String> {
     subdir
 }
 
-pub fn download_apps(citadel_root: &str) -> Result<()> {
-    let citadel_root = Path::new(citadel_root);
-    let sources_yml = citadel_root.join("apps").join("sources.yml");
-    if !sources_yml.exists() {
-        let default_passwords = vec![AppSrc {
-            repo: "https://github.com/citadel-core/apps".to_string(),
-            branch: "main".to_string(),
-        }];
-        let mut file = File::create(&sources_yml)?;
-        serde_yaml::to_writer(&mut file, &default_passwords)?;
+/// Loads apps/sources.yml, which is created with the default app store if it does not exist
+fn load_sources(root: &CitadelRoot) -> Result<Vec<AppSrc>> {
+    if let Some(sources) = root.load_yaml(&root.sources_file())? {
+        return Ok(sources);
     }
-    let sources_yml = std::fs::File::open(sources_yml)?;
-    let sources: Vec<AppSrc> = serde_yaml::from_reader(sources_yml)?;
+    let default_sources = vec![root.config().default_store.clone()];
+    root.save_yaml(&root.sources_file(), &default_sources)?;
+    Ok(default_sources)
+}
+
+fn load_stores(root: &CitadelRoot) -> Result<Vec<AppStoreInfo>> {
+    root.load_yaml(&root.stores_file())?
+        .context("No app stores have been downloaded yet")
+}
+
+/// Copies a directory of a cloned repository, existing files are replaced
+///
+/// Clones are always on disk, the copy is written through fs.
+fn copy_dir(fs: &dyn Filesystem, from: &Path, to: &Path) -> Result<()> {
+    fs.create_dir_all(to)
+        .with_context(|| format!("Error creating {}", to.display()))?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(fs, &entry.path(), &target)?;
+            continue;
+        }
+        fs.write(&target, &std::fs::read(entry.path())?)
+            .with_context(|| format!("Error writing {}", target.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs.set_mode(&target, entry.metadata()?.permissions().mode())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn download_apps(root: &CitadelRoot) -> Result<()> {
+    let sources = load_sources(root)?;
     let mut installed_apps: Vec<String> = vec![];
     let mut stores = vec![];
     // For each AppSrc, clone the repo into a tempdir
@@ -149,9 +182,9 @@ pub fn download_apps(citadel_root: &str) -> Result<()> {
                     continue;
                 };
                 let Some(subdir) = get_subdir(&app_store) else {
-                        eprintln!("No compatible version found for {}", source.repo);
-                        continue;
-                    };
+                    eprintln!("No compatible version found for {}", source.repo);
+                    continue;
+                };
                 let mut out_app_store = AppStoreInfo {
                     id: app_store.id,
                     name: app_store.name,
@@ -177,18 +210,7 @@ pub fn download_apps(citadel_root: &str) -> Result<()> {
                         eprintln!("App store {} tries to install app {} which is already installed by another store.", out_app_store.id, app_id);
                         continue;
                     }
-                    fs_extra::dir::copy(
-                        entry.path(),
-                        citadel_root.join("apps"),
-                        &fs_extra::dir::CopyOptions {
-                            overwrite: true,
-                            skip_exist: true,
-                            buffer_size: 64000,
-                            copy_inside: true,
-                            depth: 0,
-                            content_only: false,
-                        },
-                    )?;
+                    copy_dir(root.fs(), &entry.path(), &root.app_dir(&app_id))?;
                     installed_apps.push(app_id.clone());
                     store_apps.push(app_id);
                 }
@@ -204,31 +226,17 @@ pub fn download_apps(citadel_root: &str) -> Result<()> {
     }
 
     // Save stores to apps/stores.yml
-    let stores_yml = citadel_root.join("apps").join("stores.yml");
-    let mut file = File::create(&stores_yml)?;
-    serde_yaml::to_writer(&mut file, &stores)?;
+    root.save_yaml(&root.stores_file(), &stores)?;
 
     Ok(())
 }
 
-pub fn list_updates(citadel_root: &str) -> Result<()> {
-    let citadel_root = Path::new(citadel_root);
-
-    let mut services = Vec::<String>::new();
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
-    if let Ok(user_json) = user_json {
-        let user_json = serde_json::from_reader::<_, UserJson>(user_json);
-        if let Ok(user_json) = user_json {
-            services = user_json.installed_apps;
-        }
-    }
-    services.append(&mut vec!["bitcoind".to_string(), "lnd".to_string()]);
+pub fn list_updates(root: &CitadelRoot) -> Result<()> {
+    let services = root.installed_services();
 
     let mut updatable_apps = vec![];
 
-    let stores_yml = citadel_root.join("apps").join("stores.yml");
-    let stores_yml = std::fs::File::open(stores_yml)?;
-    let stores = serde_yaml::from_reader::<File, Vec<AppStoreInfo>>(stores_yml)?;
+    let stores = load_stores(root)?;
 
     for store in stores {
         let tmp_dir = TempDir::new("citadel")?;
@@ -261,9 +269,9 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
                         continue;
                     };
                     let Some(subdir) = get_subdir(&app_store) else {
-                            eprintln!("No compatible version found for {}", store.repo);
-                            continue;
-                        };
+                        eprintln!("No compatible version found for {}", store.repo);
+                        continue;
+                    };
                     let mut all_store_updatable_apps: Vec<String>;
                     if subdir != store.subdir {
                         all_store_updatable_apps = store.apps.clone().into_keys().collect();
@@ -285,16 +293,37 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
                     }
                     let subdir_path = tmp_dir.path().join(subdir);
                     all_store_updatable_apps.retain(|v| subdir_path.join(v).exists());
-                    preprocess_apps(citadel_root, &subdir_path);
+                    let preprocessed = match preprocess_apps(
+                        root,
+                        &OsFilesystem,
+                        &subdir_path,
+                        &root.installed_services(),
+                        None,
+                    ) {
+                        Ok(preprocessed) => {
+                            for (app_id, reason) in &preprocessed.failed {
+                                eprintln!("Failed to preprocess app {}: {}", app_id, reason);
+                            }
+                            preprocessed
+                        }
+                        Err(err) => {
+                            eprintln!("Failed to preprocess apps in {}: {:#}", store.repo, err);
+                            continue;
+                        }
+                    };
                     for app_id in all_store_updatable_apps {
                         let app_dir = subdir_path.join(&app_id);
                         let app_yml = app_dir.join("app.yml");
-                        let app_yml = std::fs::File::open(app_yml);
+                        // Rendered templates are not written to the downloaded repository
+                        let app_yml = match preprocessed.files.get(&app_yml) {
+                            Some(app_yml) => Ok(app_yml.clone()),
+                            None => std::fs::read_to_string(app_yml),
+                        };
                         let Ok(app_yml) = app_yml else {
                             eprintln!("No app.yml found for app {}", app_id);
                             continue;
                         };
-                        let app_config = load_config_as_v4(app_yml, &Some(&services));
+                        let app_config = load_config_as_v4(app_yml.as_bytes(), &Some(&services));
                         let Ok(app_config) = app_config else {
                             eprintln!("Failed to load app.yml for app {}", app_id);
                             continue;
@@ -314,18 +343,13 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
         }
     }
 
-    let updates_yml = citadel_root.join("apps").join("updates.yml");
-    let mut file = File::create(&updates_yml)?;
-    serde_yaml::to_writer(&mut file, &updatable_apps)?;
+    root.save_yaml(&root.updates_file(), &updatable_apps)?;
 
     Ok(())
 }
 
-pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
-    let citadel_root = Path::new(citadel_root);
-    let stores_yml = citadel_root.join("apps").join("stores.yml");
-    let stores_yml = std::fs::File::open(stores_yml)?;
-    let stores = serde_yaml::from_reader::<File, Vec<AppStoreInfo>>(stores_yml)?;
+pub fn download_app(root: &CitadelRoot, app: &str) -> Result<()> {
+    let stores = load_stores(root)?;
     let app_src = stores.iter().find(|store| store.apps.contains_key(app));
     let app_src = app_src.expect("App not found in any store");
     let tmp_dir = TempDir::new("citadel")?;
@@ -355,9 +379,9 @@ pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
                 return Ok(());
             };
             let Some(subdir) = get_subdir(&app_store) else {
-                    eprintln!("No compatible version found for {}", app_src.repo);
-                    return Ok(());
-                };
+                eprintln!("No compatible version found for {}", app_src.repo);
+                return Ok(());
+            };
             // Check if app exists in store
             let app_dir = tmp_dir.path().join(subdir).join(app);
             if !app_dir.exists() {
@@ -366,20 +390,11 @@ pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
             }
 
             // Overwrite app
-            let citadel_app_dir = citadel_root.join("apps").join(app);
-            if citadel_app_dir.exists() {
-                std::fs::remove_dir_all(&citadel_app_dir)?;
+            let citadel_app_dir = root.app_dir(app);
+            if root.fs().exists(&citadel_app_dir) {
+                root.fs().remove_dir_all(&citadel_app_dir)?;
             }
-            std::fs::create_dir_all(&citadel_app_dir)?;
-
-            fs_extra::dir::copy(
-                &app_dir,
-                &citadel_root.join("apps"),
-                &fs_extra::dir::CopyOptions {
-                    overwrite: true,
-                    ..Default::default()
-                },
-            )?;
+            copy_dir(root.fs(), &app_dir, &citadel_app_dir)?;
         }
         _ => {
             eprintln!("Unknown app store version: {}", app_store_version);
@@ -390,24 +405,10 @@ pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn download_new_apps(citadel_root: &str) -> Result<()> {
-    let citadel_root = Path::new(citadel_root);
-    let sources_yml = citadel_root.join("apps").join("sources.yml");
-    if !sources_yml.exists() {
-        let default_passwords = vec![AppSrc {
-            repo: "https://github.com/citadel-core/apps".to_string(),
-            branch: "main".to_string(),
-        }];
-        let mut file = File::create(&sources_yml)?;
-        serde_yaml::to_writer(&mut file, &default_passwords)?;
-    }
-    let sources_yml = std::fs::File::open(sources_yml)?;
-    let sources: Vec<AppSrc> = serde_yaml::from_reader(sources_yml)?;
+pub fn download_new_apps(root: &CitadelRoot) -> Result<()> {
+    let sources = load_sources(root)?;
     let mut installed_apps: Vec<String> = vec![];
-    let citadel_root = Path::new(citadel_root);
-    let stores_yml = citadel_root.join("apps").join("stores.yml");
-    let stores_yml = std::fs::File::open(stores_yml)?;
-    let mut stores = serde_yaml::from_reader::<File, Vec<AppStoreInfo>>(stores_yml)?;
+    let mut stores = load_stores(root)?;
     // For each AppSrc, clone the repo into a tempdir
     for source in sources {
         let tmp_dir = TempDir::new("citadel_app")?;
@@ -467,9 +468,9 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
                     continue;
                 };
                 let Some(subdir) = get_subdir(&app_store) else {
-                        eprintln!("No compatible version found for {}", source.repo);
-                        continue;
-                    };
+                    eprintln!("No compatible version found for {}", source.repo);
+                    continue;
+                };
                 let mut out_app_store = stores
                     .iter_mut()
                     .find(|s| s.repo == source.repo && s.branch == source.branch);
@@ -500,21 +501,10 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
                 for entry in std::fs::read_dir(tmp_dir.path().join(subdir_path))? {
                     let entry = entry?;
                     let app_id = entry.file_name().to_str().unwrap().to_string();
-                    if citadel_root.join("apps").join(&app_id).exists() {
+                    if root.fs().exists(&root.app_dir(&app_id)) {
                         continue;
                     }
-                    fs_extra::dir::copy(
-                        entry.path(),
-                        citadel_root.join("apps"),
-                        &fs_extra::dir::CopyOptions {
-                            overwrite: true,
-                            skip_exist: true,
-                            buffer_size: 64000,
-                            copy_inside: true,
-                            depth: 0,
-                            content_only: false,
-                        },
-                    )?;
+                    copy_dir(root.fs(), &entry.path(), &root.app_dir(&app_id))?;
                     installed_apps.push(app_id.clone());
                     store_apps.push(app_id);
                 }
@@ -532,9 +522,7 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
     }
 
     // Save stores to apps/stores.yml
-    let stores_yml = citadel_root.join("apps").join("stores.yml");
-    let mut file = File::create(&stores_yml)?;
-    serde_yaml::to_writer(&mut file, &stores)?;
+    root.save_yaml(&root.stores_file(), &stores)?;
 
     Ok(())
 }
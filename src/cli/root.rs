@@ -0,0 +1,402 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    config::{Config, CONFIG_FILE},
+    env_file::parse_vars,
+    fs::{Filesystem, OsFilesystem},
+    tor::torrc_file_name,
+};
+
+/// Where the directories and files app-cli uses are, relative to the Citadel root
+///
+/// Absolute paths can be used to move them out of the Citadel root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Layout {
+    /// The installed apps and the files generated for all of them
+    pub apps: PathBuf,
+    /// The apps' data, mounted into their containers
+    pub app_data: PathBuf,
+    /// Citadel's state, like the seed and the installed apps
+    pub db: PathBuf,
+    /// The torrc files and the reverse proxy includes of all apps
+    pub tor: PathBuf,
+    /// The I2P tunnels of all apps
+    pub i2p_tunnels_file: PathBuf,
+    pub env_file: PathBuf,
+    /// Where the quadlet output backend writes the apps' units
+    ///
+    /// Podman only finds them in its search paths, like ~/.config/containers/systemd for rootless Podman.
+    pub units: PathBuf,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            apps: "apps".into(),
+            app_data: "app-data".into(),
+            db: "db".into(),
+            tor: "tor".into(),
+            i2p_tunnels_file: "i2p/tunnels.d/apps.conf".into(),
+            env_file: ".env".into(),
+            units: "units".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserJson {
+    #[serde(rename = "installedApps")]
+    installed_apps: Vec<String>,
+    // We ignore other properties for now because we do not need them
+}
+
+/// A Citadel installation, knows where each state file is and how to load it
+#[derive(Debug, Clone)]
+pub struct CitadelRoot {
+    path: PathBuf,
+    config: Config,
+    fs: Arc<dyn Filesystem>,
+}
+
+impl CitadelRoot {
+    /// A Citadel root on the real filesystem, with the default configuration
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            config: Config::default(),
+            fs: Arc::new(OsFilesystem),
+        }
+    }
+
+    /// Opens a Citadel root on the real filesystem, configured by its app-cli.yml and the environment
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let root = Self::new(path);
+        let mut config = Config::load(&root)?;
+        config.apply_overrides(std::env::vars())?;
+        Ok(root.with_config(config))
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.config.layout = layout;
+        self
+    }
+
+    pub fn with_filesystem(mut self, fs: Arc<dyn Filesystem>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.config.layout
+    }
+
+    pub fn fs(&self) -> &dyn Filesystem {
+        self.fs.as_ref()
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.path.join(CONFIG_FILE)
+    }
+
+    pub fn apps_dir(&self) -> PathBuf {
+        self.path.join(&self.config.layout.apps)
+    }
+
+    pub fn app_dir(&self, app_id: &str) -> PathBuf {
+        self.apps_dir().join(app_id)
+    }
+
+    pub fn app_yml(&self, app_id: &str) -> PathBuf {
+        self.app_dir(app_id).join("app.yml")
+    }
+
+    pub fn units_dir(&self) -> PathBuf {
+        self.path.join(&self.config.layout.units)
+    }
+
+    pub fn app_data_dir(&self, app_id: &str) -> PathBuf {
+        self.path.join(&self.config.layout.app_data).join(app_id)
+    }
+
+    fn db_dir(&self) -> PathBuf {
+        self.path.join(&self.config.layout.db)
+    }
+
+    pub fn user_json(&self) -> PathBuf {
+        self.db_dir().join("user.json")
+    }
+
+    pub fn seed_file(&self) -> PathBuf {
+        self.db_dir().join("citadel-seed").join("seed")
+    }
+
+    /// The directory the previous generation of the generated files is kept in
+    pub fn previous_generation_dir(&self) -> PathBuf {
+        self.db_dir().join("app-cli").join("previous")
+    }
+
+    pub fn env_file(&self) -> PathBuf {
+        self.path.join(&self.config.layout.env_file)
+    }
+
+    pub fn ips_file(&self) -> PathBuf {
+        self.apps_dir().join("ips.yml")
+    }
+
+    pub fn ports_file(&self) -> PathBuf {
+        self.apps_dir().join("ports.yml")
+    }
+
+    pub fn port_cache_file(&self) -> PathBuf {
+        self.apps_dir().join("ports.cache.yml")
+    }
+
+    /// The results of the last conversion, see ConvertedApps
+    pub fn converted_apps_file(&self) -> PathBuf {
+        self.apps_dir().join("converted.json")
+    }
+
+    /// The output backend the apps were last converted with
+    pub fn output_backend_file(&self) -> PathBuf {
+        self.apps_dir().join("output-backend")
+    }
+
+    pub fn registry_file(&self) -> PathBuf {
+        self.apps_dir().join("registry.json")
+    }
+
+    pub fn virtual_apps_file(&self) -> PathBuf {
+        self.apps_dir().join("virtual-apps.json")
+    }
+
+    pub fn sources_file(&self) -> PathBuf {
+        self.apps_dir().join("sources.yml")
+    }
+
+    pub fn stores_file(&self) -> PathBuf {
+        self.apps_dir().join("stores.yml")
+    }
+
+    pub fn updates_file(&self) -> PathBuf {
+        self.apps_dir().join("updates.yml")
+    }
+
+    /// The torrc file for a Tor instance
+    pub fn torrc_file(&self, instance: u8) -> PathBuf {
+        self.path
+            .join(&self.config.layout.tor)
+            .join(torrc_file_name(instance))
+    }
+
+    /// The directory Tor keeps a hidden service's keys in (mounted as /var/lib/tor in the Tor containers)
+    pub fn hidden_service_dir(&self, dir: &str) -> PathBuf {
+        self.path
+            .join(&self.config.layout.tor)
+            .join("data")
+            .join(dir)
+    }
+
+    pub fn authorized_clients_file(&self) -> PathBuf {
+        self.path
+            .join(&self.config.layout.tor)
+            .join("authorized-clients.yml")
+    }
+
+    pub fn i2p_tunnels_file(&self) -> PathBuf {
+        self.path.join(&self.config.layout.i2p_tunnels_file)
+    }
+
+    /// The Caddyfile snippets of all apps, next to the torrc files
+    pub fn caddy_file(&self) -> PathBuf {
+        self.path.join(&self.config.layout.tor).join("apps.caddy")
+    }
+
+    /// The nginx server blocks of all apps, next to the torrc files
+    pub fn nginx_file(&self) -> PathBuf {
+        self.path
+            .join(&self.config.layout.tor)
+            .join("apps.nginx.conf")
+    }
+
+    /// Reads a file, None if it does not exist
+    pub fn read(&self, path: &Path) -> Result<Option<String>> {
+        match self.fs.read_to_string(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Error reading {}", path.display())),
+        }
+    }
+
+    /// Loads a YAML file, None if it does not exist
+    pub fn load_yaml<T: DeserializeOwned>(&self, path: &Path) -> Result<Option<T>> {
+        self.read(path)?
+            .map(|contents| serde_yaml::from_str(&contents))
+            .transpose()
+            .with_context(|| format!("Error parsing {}", path.display()))
+    }
+
+    /// Loads a JSON file, None if it does not exist
+    pub fn load_json<T: DeserializeOwned>(&self, path: &Path) -> Result<Option<T>> {
+        self.read(path)?
+            .map(|contents| serde_json::from_str(&contents))
+            .transpose()
+            .with_context(|| format!("Error parsing {}", path.display()))
+    }
+
+    /// Writes a file directly, for files that are not part of a conversion
+    pub fn save(&self, path: &Path, contents: &str) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            self.fs.create_dir_all(parent)?;
+        }
+        self.fs
+            .write(path, contents.as_bytes())
+            .with_context(|| format!("Error writing {}", path.display()))
+    }
+
+    pub fn save_yaml<T: Serialize>(&self, path: &Path, value: &T) -> Result<()> {
+        self.save(path, &serde_yaml::to_string(value)?)
+    }
+
+    /// The apps the user installed, without the built-in services
+    pub fn installed_apps(&self) -> Result<Vec<String>> {
+        Ok(self
+            .load_json::<UserJson>(&self.user_json())?
+            .map(|user_json| user_json.installed_apps)
+            .unwrap_or_default())
+    }
+
+    /// The contents of user.json with the installed apps replaced, keeping its other properties
+    pub fn user_json_with_installed_apps(&self, apps: &[String]) -> Result<String> {
+        let path = self.user_json();
+        let mut user_json = self
+            .load_json::<serde_json::Value>(&path)?
+            .unwrap_or_else(|| serde_json::json!({}));
+        let Some(properties) = user_json.as_object_mut() else {
+            bail!("{} is not a JSON object", path.display());
+        };
+        properties.insert("installedApps".to_string(), serde_json::json!(apps));
+        Ok(serde_json::to_string(&user_json)?)
+    }
+
+    /// The installed apps and the built-in services, an unreadable user.json counts as no apps
+    pub fn installed_services(&self) -> Vec<String> {
+        self.services_with(self.installed_apps().unwrap_or_default())
+    }
+
+    /// The given installed apps and the built-in services
+    pub fn services_with(&self, installed_apps: Vec<String>) -> Vec<String> {
+        let mut services = installed_apps;
+        services.extend(self.config.builtin_services.iter().cloned());
+        services
+    }
+
+    /// The Citadel seed, None if Citadel is not set up yet
+    pub fn citadel_seed(&self) -> Result<Option<String>> {
+        self.read(&self.seed_file())
+            .context("Error reading Citadel seed")
+    }
+
+    /// Loads the env vars from the .env file, logging errors
+    pub fn env_vars(&self) -> HashMap<String, String> {
+        match self.read(&self.env_file()) {
+            Ok(contents) => parse_vars(&contents.unwrap_or_default()),
+            Err(err) => {
+                eprintln!("Warning: {:#}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// The ids of all apps in the apps directory, sorted so every conversion processes them in the same order
+    pub fn list_apps(&self) -> Result<Vec<String>> {
+        let apps_dir = self.apps_dir();
+        let mut app_ids: Vec<String> = self
+            .fs
+            .read_dir(&apps_dir)
+            .context("Error reading apps directory")?
+            .into_iter()
+            .filter(|name| self.fs.is_dir(&apps_dir.join(name)))
+            .collect();
+        app_ids.sort();
+        Ok(app_ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+    use super::{CitadelRoot, Layout};
+    use crate::cli::fs::{Filesystem, MemoryFilesystem};
+
+    #[test]
+    fn loads_state_files_from_layout() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        let root = CitadelRoot::new("/citadel")
+            .with_layout(Layout {
+                app_data: "/mnt/data/app-data".into(),
+                db: "state".into(),
+                ..Default::default()
+            })
+            .with_filesystem(fs.clone());
+        assert_eq!(
+            root.app_data_dir("example"),
+            Path::new("/mnt/data/app-data/example")
+        );
+        assert_eq!(
+            root.seed_file(),
+            Path::new("/citadel/state/citadel-seed/seed")
+        );
+
+        // Nothing is set up yet
+        assert_eq!(root.installed_services(), ["bitcoind", "lnd"]);
+        assert_eq!(root.citadel_seed().unwrap(), None);
+        assert!(root.env_vars().is_empty());
+        assert!(root.list_apps().is_err());
+
+        root.save(&root.user_json(), r#"{"installedApps":["example"]}"#)
+            .unwrap();
+        root.save(&root.seed_file(), "seed").unwrap();
+        root.save(&root.env_file(), "APP_DOMAIN=example.com\n")
+            .unwrap();
+        for app_id in ["b", "a"] {
+            root.save(&root.app_yml(app_id), "citadel_version: 4\n")
+                .unwrap();
+        }
+        root.save_yaml(
+            &root.ips_file(),
+            &BTreeMap::from([("APP_A_MAIN_IP", "10.21.21.20")]),
+        )
+        .unwrap();
+        assert!(fs.exists(Path::new("/citadel/state/user.json")));
+        assert_eq!(root.installed_services(), ["example", "bitcoind", "lnd"]);
+        assert_eq!(root.citadel_seed().unwrap().as_deref(), Some("seed"));
+        assert_eq!(root.env_vars()["APP_DOMAIN"], "example.com");
+        // Files in the apps directory are not apps
+        assert_eq!(root.list_apps().unwrap(), ["a", "b"]);
+        let ips: BTreeMap<String, String> = root.load_yaml(&root.ips_file()).unwrap().unwrap();
+        assert_eq!(ips["APP_A_MAIN_IP"], "10.21.21.20");
+    }
+}
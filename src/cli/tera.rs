@@ -1,25 +1,34 @@
 use std::{
-    collections::HashMap,
-    io::{Error, Read, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    io::Error,
+    path::{Path, PathBuf},
 };
 
 use tera::Tera;
 
+use super::fs::Filesystem;
 use crate::{
     composegenerator::{
         load_config_as_v4,
-        v4::{permissions::is_allowed_by_permissions, utils::derive_entropy},
+        v4::{
+            permissions::is_allowed_by_permissions,
+            utils::{app_seeds, derive_entropy},
+        },
     },
     utils::flatten,
 };
 
-fn convert_app_yml(jinja_file: &Path, app_id: &str, services: &[String], citadel_seed: &str) -> Result<(), Error> {
+fn convert_app_yml(
+    fs: &dyn Filesystem,
+    jinja_file: &Path,
+    app_id: &str,
+    services: &[String],
+    citadel_seed: &str,
+) -> Result<String, Error> {
     let mut context = tera::Context::new();
     context.insert("services", services);
     context.insert("app_name", app_id);
-    let mut tmpl = String::new();
-    std::fs::File::open(jinja_file)?.read_to_string(&mut tmpl)?;
+    let tmpl = fs.read_to_string(jinja_file)?;
     let mut tera = Tera::default();
     let citadel_seed = citadel_seed.to_string();
     let app_id = app_id.to_string();
@@ -54,20 +63,18 @@ fn convert_app_yml(jinja_file: &Path, app_id: &str, services: &[String], citadel
             "Error parsing template",
         ));
     }
-    let mut writer = std::fs::File::create(jinja_file.to_path_buf().with_extension(""))?;
-    writer.write_all(tmpl_result.unwrap().as_bytes())
+    Ok(tmpl_result.unwrap())
 }
 
 fn convert_config_template(
-    jinja_file: &Path,
+    tmpl: &str,
     app_id: &str,
     app_version: &str,
     permissions: &[String],
     services: &[String],
     env_vars: &HashMap<String, String>,
     citadel_seed: &str,
-) -> Result<(), Error> {
-    let output_file = jinja_file.with_extension("");
+) -> Result<String, Error> {
     let mut context = tera::Context::new();
     context.insert("services", &services);
     context.insert("app_name", app_id);
@@ -77,20 +84,11 @@ fn convert_config_template(
             context.insert(key, &val);
         }
     }
-    context.insert(
-        "APP_SEED",
-        &derive_entropy(citadel_seed, format!("app-{}-seed", app_id).as_str()),
-    );
-    for i in 1..6 {
-        context.insert(
-            format!("APP_SEED_{}", i),
-            &derive_entropy(citadel_seed, format!("app-{}-seed{}", app_id, i).as_str()),
-        );
+    for (key, seed) in app_seeds(citadel_seed, app_id) {
+        context.insert(key, &seed);
     }
     context.insert("APP_VERSION", app_version);
 
-    let mut tmpl = String::new();
-    std::fs::File::open(jinja_file)?.read_to_string(&mut tmpl)?;
     let mut tera = Tera::default();
     let citadel_seed = citadel_seed.to_string();
     let app_id = app_id.to_string();
@@ -117,7 +115,7 @@ fn convert_config_template(
             .expect("Failed to serialize value"))
         },
     );
-    let tmpl_result = tera.render_str(tmpl.as_str(), &context);
+    let tmpl_result = tera.render_str(tmpl, &context);
     if let Err(e) = tmpl_result {
         eprintln!("Error processing template: {}", e);
         return Err(Error::new(
@@ -125,36 +123,43 @@ fn convert_config_template(
             "Error parsing template",
         ));
     }
-    let mut writer = std::fs::File::create(output_file)?;
-    writer.write_all(tmpl_result.unwrap().as_bytes())
+    Ok(tmpl_result.unwrap())
 }
 
+/// Renders an app's templates
+///
+/// The rendered files are returned (path -> contents) instead of being written.
 pub fn convert_app_jinja_files(
+    fs: &dyn Filesystem,
     app_path: &Path,
     services: &[String],
     citadel_seed: &Option<String>,
     env_vars: &Option<HashMap<String, String>>,
-) -> Result<(), Error> {
+) -> Result<BTreeMap<PathBuf, String>, Error> {
+    let mut rendered = BTreeMap::new();
     let app_yml_jinja = app_path.to_path_buf().join("app.yml.jinja");
-    if app_yml_jinja.exists() && citadel_seed.is_some() {
-        convert_app_yml(
+    if fs.exists(&app_yml_jinja) && citadel_seed.is_some() {
+        let app_yml = convert_app_yml(
+            fs,
             &app_yml_jinja,
             app_path.file_name().unwrap().to_str().unwrap(),
             services,
             citadel_seed.as_ref().unwrap(),
         )?;
+        rendered.insert(app_path.join("app.yml"), app_yml);
     }
 
     if citadel_seed.is_some() && env_vars.is_some() {
         let citadel_seed = citadel_seed.as_ref().unwrap();
         let env_vars = env_vars.as_ref().unwrap();
 
-        let app_yml = app_path.join("app.yml");
-        if !app_yml.exists() {
-            return Err(Error::new(std::io::ErrorKind::Other, "app.yml not found"));
-        }
-        let app_yml = std::fs::File::open(app_yml)?;
-        let app_yml = load_config_as_v4(app_yml, &Some(&services.to_vec()));
+        let app_yml_path = app_path.join("app.yml");
+        let app_yml = match rendered.get(&app_yml_path) {
+            Some(app_yml) => app_yml.clone(),
+            None if fs.exists(&app_yml_path) => fs.read_to_string(&app_yml_path)?,
+            None => return Err(Error::new(std::io::ErrorKind::Other, "app.yml not found")),
+        };
+        let app_yml = load_config_as_v4(app_yml.as_bytes(), &Some(&services.to_vec()));
         if let Err(e) = app_yml {
             eprintln!("Error processing app.yml: {}", e);
             return Err(Error::new(
@@ -166,14 +171,15 @@ pub fn convert_app_jinja_files(
         let app_version = app_yml.metadata.version;
         let perms = flatten(app_yml.metadata.permissions);
 
-        let other_jinja_files = std::fs::read_dir(app_path)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().extension().unwrap_or_default() == "jinja")
-            .map(|entry| entry.path());
+        let other_jinja_files = fs
+            .read_dir(app_path)?
+            .into_iter()
+            .map(|file_name| app_path.join(file_name))
+            .filter(|path| path.extension().unwrap_or_default() == "jinja");
 
         for jinja_file in other_jinja_files {
-            convert_config_template(
-                &jinja_file,
+            let contents = convert_config_template(
+                &fs.read_to_string(&jinja_file)?,
                 app_path.file_name().unwrap().to_str().unwrap(),
                 &app_version,
                 &perms,
@@ -181,8 +187,9 @@ pub fn convert_app_jinja_files(
                 env_vars,
                 citadel_seed,
             )?;
+            rendered.insert(jinja_file.with_extension(""), contents);
         }
     }
 
-    Ok(())
+    Ok(rendered)
 }
@@ -0,0 +1,401 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use super::{changes::Changeset, fs::Filesystem, root::CitadelRoot};
+use crate::composegenerator::v4::utils::derive_entropy;
+
+pub const DEFAULT_TOR_INSTANCES: u8 = 3;
+
+// App -> client name -> x25519 public key (base32)
+pub type AuthorizedClients = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Reads the number of Tor instances for apps from the .env file
+pub fn tor_instances_from_env(env_vars: &HashMap<String, String>) -> Result<u8> {
+    let Some(instances) = env_vars.get("TOR_APP_INSTANCES") else {
+        return Ok(DEFAULT_TOR_INSTANCES);
+    };
+    let instances: u8 = instances.trim().parse()?;
+    if instances == 0 {
+        bail!("TOR_APP_INSTANCES must be at least 1");
+    }
+    Ok(instances)
+}
+
+/// Gets the Tor instance (starting at 1) an app's hidden services are assigned to
+///
+/// Unless the app is pinned to an instance, this is based on a hash of the app id,
+/// so installing or removing other apps never moves an app to a different instance.
+pub fn assign_tor_instance(app_id: &str, pinned: Option<u8>, instances: u8) -> u8 {
+    match pinned {
+        Some(pinned) if pinned >= 1 && pinned <= instances => return pinned,
+        Some(pinned) => tracing::warn!(
+            "App {} is pinned to Tor instance {}, but only {} are available",
+            app_id,
+            pinned,
+            instances
+        ),
+        None => {}
+    }
+    let hash = hmac_sha256::Hash::hash(app_id.as_bytes());
+    let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    (hash % instances as u64) as u8 + 1
+}
+
+/// The torrc file for a Tor instance
+pub fn torrc_file_name(instance: u8) -> String {
+    if instance == 1 {
+        "torrc-apps".to_string()
+    } else {
+        format!("torrc-apps-{}", instance)
+    }
+}
+
+pub fn load_authorized_clients(root: &CitadelRoot) -> Result<AuthorizedClients> {
+    Ok(root
+        .load_yaml(&root.authorized_clients_file())?
+        .unwrap_or_default())
+}
+
+pub fn save_authorized_clients(root: &CitadelRoot, clients: &AuthorizedClients) -> Result<()> {
+    root.save_yaml(&root.authorized_clients_file(), clients)
+}
+
+/// Checks a client name, which is used as file name in the authorized_clients directory
+pub fn validate_client_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Client names can only contain letters, numbers, - and _");
+    }
+    Ok(())
+}
+
+/// Gets the base32 x25519 public key from a key in either
+/// "descriptor:x25519:<key>" or plain base32 format
+pub fn parse_client_key(key: &str) -> Result<String> {
+    let key = key.trim();
+    let key = key.strip_prefix("descriptor:x25519:").unwrap_or(key);
+    let key = key.to_ascii_uppercase();
+    // 32 bytes are 52 base32 characters without padding
+    if key.len() != 52
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c))
+    {
+        bail!("{} is not a valid base32 x25519 public key", key);
+    }
+    Ok(key)
+}
+
+/// Makes the authorized_clients directory of a hidden service match the given clients
+///
+/// With no clients, all existing keys are removed, which disables client authorization.
+pub fn sync_authorized_clients(
+    changeset: &mut Changeset,
+    service_dir: &Path,
+    clients: &BTreeMap<String, String>,
+) -> Result<()> {
+    let fs = changeset.root().fs();
+    let clients_dir = service_dir.join("authorized_clients");
+    let existing = if fs.exists(&clients_dir) {
+        fs.read_dir(&clients_dir)?
+    } else {
+        Vec::new()
+    };
+    for file_name in existing {
+        let path = clients_dir.join(&file_name);
+        let Some(name) = file_name.strip_suffix(".auth") else {
+            continue;
+        };
+        if !clients.contains_key(name) {
+            changeset.delete(path);
+        }
+    }
+    for (name, key) in clients {
+        let path = clients_dir.join(format!("{}.auth", name));
+        changeset.write_private(path, format!("descriptor:x25519:{}\n", key));
+    }
+    Ok(())
+}
+
+const SECRET_KEY_HEADER: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+const PUBLIC_KEY_HEADER: &[u8; 32] = b"== ed25519v1-public: type0 ==\0\0\0";
+
+/// Gets the .onion address of a v3 hidden service's public key
+pub fn onion_address(public_key: &[u8; 32]) -> String {
+    use sha3::{Digest, Sha3_256};
+
+    let mut checksum = Sha3_256::new();
+    checksum.update(b".onion checksum");
+    checksum.update(public_key);
+    checksum.update([3]);
+    let checksum = checksum.finalize();
+    let mut address = public_key.to_vec();
+    address.extend_from_slice(&checksum[..2]);
+    address.push(3);
+    format!(
+        "{}.onion",
+        data_encoding::BASE32_NOPAD
+            .encode(&address)
+            .to_ascii_lowercase()
+    )
+}
+
+/// Creates a hidden service key pair in the format Tor stores it in
+///
+/// Returns the contents of hs_ed25519_secret_key and hs_ed25519_public_key.
+pub fn hidden_service_keys(seed: &[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+    use sha2::{Digest, Sha512};
+
+    // Tor stores the expanded secret key (clamped scalar and nonce prefix)
+    let mut expanded: [u8; 64] = Sha512::digest(seed).into();
+    expanded[0] &= 248;
+    expanded[31] &= 127;
+    expanded[31] |= 64;
+    let public_key = ed25519_dalek::SigningKey::from_bytes(seed).verifying_key();
+    (
+        [SECRET_KEY_HEADER.as_slice(), &expanded].concat(),
+        [PUBLIC_KEY_HEADER.as_slice(), public_key.as_bytes()].concat(),
+    )
+}
+
+/// Reads the .onion address from a hidden service's hs_ed25519_public_key file
+fn read_onion_address(fs: &dyn Filesystem, public_key_file: &Path) -> Result<String> {
+    let public_key = fs.read(public_key_file)?;
+    let Some(public_key) = public_key
+        .strip_prefix(PUBLIC_KEY_HEADER.as_slice())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+    else {
+        bail!("{} is not a valid public key", public_key_file.display());
+    };
+    Ok(onion_address(&public_key))
+}
+
+fn derived_key_seed(dir: &str, citadel_seed: &str) -> Result<[u8; 32]> {
+    let mut seed = [0u8; 32];
+    let entropy = derive_entropy(citadel_seed, &format!("tor-{}", dir));
+    hex::decode_to_slice(entropy, &mut seed)?;
+    Ok(seed)
+}
+
+/// Gets the address ensure_hidden_service_keys would return, without creating any keys
+///
+/// Without existing keys or the Citadel seed, the address is not known yet.
+pub fn expected_onion_address(
+    fs: &dyn Filesystem,
+    service_dir: &Path,
+    dir: &str,
+    citadel_seed: Option<&str>,
+) -> Result<Option<String>> {
+    if fs.exists(&service_dir.join("hs_ed25519_secret_key")) {
+        return read_onion_address(fs, &service_dir.join("hs_ed25519_public_key")).map(Some);
+    }
+    let Some(citadel_seed) = citadel_seed else {
+        return Ok(None);
+    };
+    let (_, public_key) = hidden_service_keys(&derived_key_seed(dir, citadel_seed)?);
+    Ok(Some(onion_address(&public_key[32..].try_into().unwrap())))
+}
+
+/// Makes sure a hidden service has keys and returns its .onion address
+///
+/// Existing keys are never replaced. New keys are derived from the Citadel seed if it is available,
+/// so they can be restored from it, otherwise they are random.
+/// New files are only written when the changeset is applied.
+pub fn ensure_hidden_service_keys(
+    changeset: &mut Changeset,
+    service_dir: &Path,
+    dir: &str,
+    citadel_seed: Option<&str>,
+) -> Result<String> {
+    let fs = changeset.root().fs();
+    let secret_key_file = service_dir.join("hs_ed25519_secret_key");
+    let public_key_file = service_dir.join("hs_ed25519_public_key");
+    let hostname_file = service_dir.join("hostname");
+    let has_hostname = fs.exists(&hostname_file);
+    let address = if fs.exists(&secret_key_file) {
+        read_onion_address(fs, &public_key_file)?
+    } else {
+        let mut seed = [0u8; 32];
+        if let Some(citadel_seed) = citadel_seed {
+            seed = derived_key_seed(dir, citadel_seed)?;
+        } else {
+            getrandom::getrandom(&mut seed)?;
+        }
+        let (secret_key, public_key) = hidden_service_keys(&seed);
+        let address = onion_address(&public_key[32..].try_into().unwrap());
+        changeset.write_private(secret_key_file, secret_key);
+        changeset.write_private(public_key_file, public_key);
+        address
+    };
+    if !has_hostname {
+        changeset.write_private(hostname_file, format!("{}\n", address));
+    }
+    Ok(address)
+}
+
+/// Adds a client that can connect to an app's hidden services that only allow authorized clients
+///
+/// The key is written to the hidden services the next time the apps are converted.
+pub fn add_client(root: &CitadelRoot, app: &str, name: &str, key: &str) -> Result<()> {
+    if !root.fs().exists(&root.app_yml(app)) {
+        bail!("App {} is not available", app);
+    }
+    validate_client_name(name)?;
+    let key = parse_client_key(key)?;
+    let mut clients = load_authorized_clients(root)?;
+    if clients
+        .entry(app.to_string())
+        .or_default()
+        .insert(name.to_string(), key)
+        .is_some()
+    {
+        println!("Replaced the key of client {}", name);
+    }
+    save_authorized_clients(root, &clients)
+}
+
+pub fn list_clients(root: &CitadelRoot, app: &str) -> Result<()> {
+    let clients = load_authorized_clients(root)?;
+    for (name, key) in clients.get(app).into_iter().flatten() {
+        println!("{}: descriptor:x25519:{}", name, key);
+    }
+    Ok(())
+}
+
+/// Removes a client, the key is removed from the hidden services the next time the apps are converted
+pub fn revoke_client(root: &CitadelRoot, app: &str, name: &str) -> Result<()> {
+    let mut clients = load_authorized_clients(root)?;
+    let Some(app_clients) = clients.get_mut(app) else {
+        bail!("App {} does not have any authorized clients", app);
+    };
+    if app_clients.remove(name).is_none() {
+        bail!("Client {} is not authorized for app {}", name, app);
+    }
+    if app_clients.is_empty() {
+        clients.remove(app);
+    }
+    save_authorized_clients(root, &clients)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+    use super::{
+        assign_tor_instance, ensure_hidden_service_keys, hidden_service_keys, onion_address,
+        parse_client_key, sync_authorized_clients, torrc_file_name,
+    };
+    use crate::cli::{changes::Changeset, fs::MemoryFilesystem, root::CitadelRoot};
+
+    fn memory_root() -> CitadelRoot {
+        CitadelRoot::new("/citadel").with_filesystem(Arc::new(MemoryFilesystem::new()))
+    }
+
+    #[test]
+    fn assignment_is_stable() {
+        let instance = assign_tor_instance("example-app", None, 3);
+        assert!((1..=3).contains(&instance));
+        assert_eq!(assign_tor_instance("example-app", None, 3), instance);
+        assert_eq!(assign_tor_instance("example-app", None, 1), 1);
+    }
+
+    #[test]
+    fn pinned_instance() {
+        assert_eq!(assign_tor_instance("example-app", Some(2), 3), 2);
+        let instance = assign_tor_instance("example-app", None, 3);
+        assert_eq!(assign_tor_instance("example-app", Some(4), 3), instance);
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(torrc_file_name(1), "torrc-apps");
+        assert_eq!(torrc_file_name(3), "torrc-apps-3");
+    }
+
+    #[test]
+    fn client_keys() {
+        let key = "N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ";
+        assert_eq!(parse_client_key(key).unwrap(), key);
+        assert_eq!(
+            parse_client_key(&format!("descriptor:x25519:{}", key.to_lowercase())).unwrap(),
+            key
+        );
+        assert!(parse_client_key("descriptor:x25519:abc").is_err());
+    }
+
+    #[test]
+    fn syncs_authorized_clients() {
+        let root = memory_root();
+        let service_dir = root.hidden_service_dir("app-example");
+        let clients_dir = service_dir.join("authorized_clients");
+        let key = "N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ".to_string();
+        let mut clients = BTreeMap::from([("laptop".to_string(), key.clone())]);
+        let mut changeset = Changeset::new(&root);
+        sync_authorized_clients(&mut changeset, &service_dir, &clients).unwrap();
+        // Client keys are not shown in the diff
+        assert!(!changeset.diff().contains(&key));
+        changeset.apply().unwrap();
+        assert_eq!(
+            root.fs()
+                .read_to_string(&clients_dir.join("laptop.auth"))
+                .unwrap(),
+            format!("descriptor:x25519:{}\n", key)
+        );
+        clients.clear();
+        clients.insert("phone".to_string(), key);
+        let mut changeset = Changeset::new(&root);
+        sync_authorized_clients(&mut changeset, &service_dir, &clients).unwrap();
+        changeset.apply().unwrap();
+        assert!(!root.fs().exists(&clients_dir.join("laptop.auth")));
+        assert!(root.fs().exists(&clients_dir.join("phone.auth")));
+    }
+
+    #[test]
+    fn onion_address_from_seed() {
+        let seed: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let (secret_key, public_key) = hidden_service_keys(&seed);
+        assert_eq!(secret_key.len(), 96);
+        assert_eq!(
+            onion_address(&public_key[32..].try_into().unwrap()),
+            "aoqqpp7tzyil4hlq3umoos6atft6jvrqtosq2xy53sdgiesvgg4bqead.onion"
+        );
+    }
+
+    #[test]
+    fn keeps_existing_keys() {
+        let root = memory_root();
+        let service_dir = Path::new("/citadel/tor/data/app-example");
+        let mut changeset = Changeset::new(&root);
+        let address =
+            ensure_hidden_service_keys(&mut changeset, service_dir, "app-example", Some("seed"))
+                .unwrap();
+        // Nothing is written before the changes are applied
+        assert!(!root.fs().exists(service_dir));
+        changeset.apply().unwrap();
+        assert_eq!(
+            root.fs()
+                .read_to_string(&service_dir.join("hostname"))
+                .unwrap(),
+            format!("{}\n", address)
+        );
+        let mut changeset = Changeset::new(&root);
+        assert_eq!(
+            ensure_hidden_service_keys(&mut changeset, service_dir, "app-example", None).unwrap(),
+            address
+        );
+        assert!(changeset.changed_files().is_empty());
+        let other_dir = Path::new("/citadel/tor/data/other");
+        assert_eq!(
+            ensure_hidden_service_keys(&mut changeset, other_dir, "app-example", Some("seed"))
+                .unwrap(),
+            address
+        );
+    }
+}
@@ -1,4 +1,7 @@
 pub mod compose;
+pub mod i2p;
+pub mod proxy;
+pub mod tor;
 pub mod types;
 #[cfg(feature = "umbrel")]
 pub mod umbrel;
@@ -9,7 +12,7 @@ pub mod output;
 
 use std::collections::HashMap;
 
-use self::types::ResultYml;
+use self::types::{NodeConfig, ResultYml};
 use self::v3::convert::v3_to_v4;
 use self::v3::types::Schema as AppYmlV3;
 use self::v4::types::{AppYml as AppYmlV4, PortMapElement};
@@ -96,26 +99,50 @@ pub fn convert_config<R>(
     installed_services: &Option<Vec<String>>,
     ip_addresses: &Option<HashMap<String, String>>,
 ) -> Result<ResultYml>
+where
+    R: std::io::Read,
+{
+    convert_config_with(
+        app_name,
+        app_reader,
+        port_map,
+        installed_services,
+        ip_addresses,
+        &NodeConfig::default(),
+    )
+}
+
+/// Like convert_config, but with the limits of the node the app runs on
+pub fn convert_config_with<R>(
+    app_name: &str,
+    app_reader: R,
+    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: &Option<Vec<String>>,
+    ip_addresses: &Option<HashMap<String, String>>,
+    node_config: &NodeConfig,
+) -> Result<ResultYml>
 where
     R: std::io::Read,
 {
     let app_yml = load_config(app_reader)?;
     match app_yml {
-        AppYmlFile::V4(app_definition) => v4::convert::convert_config(
+        AppYmlFile::V4(app_definition) => v4::convert::convert_config_with(
             app_name,
             app_definition,
             port_map,
             installed_services,
             ip_addresses,
+            node_config,
         ),
         AppYmlFile::V3(app_definition) => {
             if let Some(installed_services) = installed_services {
-                v3::convert::convert_config(
+                v3::convert::convert_config_with(
                     app_name,
                     app_definition,
                     port_map,
                     installed_services,
                     ip_addresses,
+                    node_config,
                 )
             } else {
                 bail!("No installed services defined. If you are trying to validate an app, please make sure it is an app.yml v4 or later.")
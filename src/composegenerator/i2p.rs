@@ -0,0 +1,162 @@
+use std::fmt;
+
+#[cfg(feature = "schema")]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::tor::{HiddenService, HiddenServicePort};
+
+/// An i2pd server tunnel
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct I2pTunnel {
+    /// The name of the tunnel section, unique across all apps
+    pub name: String,
+    /// The IP address of the container (or a placeholder if it is not known yet)
+    pub host: String,
+    /// The port inside the container
+    pub port: u16,
+    /// The port of the I2P destination, the same as port if this is not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inport: Option<u16>,
+    /// The keys file, tunnels with the same keys share one .b32.i2p address
+    pub keys: String,
+}
+
+impl fmt::Display for I2pTunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}]", self.name)?;
+        writeln!(f, "host = {}", self.host)?;
+        writeln!(f, "port = {}", self.port)?;
+        if let Some(inport) = self.inport {
+            writeln!(f, "inport = {}", inport)?;
+        }
+        writeln!(f, "keys = {}", self.keys)
+    }
+}
+
+impl I2pTunnel {
+    /// Whether this tunnel exposes the same container port as a hidden service port,
+    /// a tunnel without an inport keeps the container's port on I2P
+    fn mirrors(&self, port: &HiddenServicePort) -> bool {
+        self.host == port.target_host
+            && self.port == port.target_port
+            && self.inport.is_none_or(|inport| inport == port.virtual_port)
+    }
+}
+
+/// Keeps the tunnels that expose a port of one of the hidden services
+///
+/// I2P has no client authorization, so services only authorized clients can use are ignored.
+pub fn public_tunnels(
+    tunnels: Vec<I2pTunnel>,
+    hidden_services: &[HiddenService],
+) -> Vec<I2pTunnel> {
+    tunnels
+        .into_iter()
+        .filter(|tunnel| {
+            hidden_services
+                .iter()
+                .filter(|service| !service.client_auth)
+                .flat_map(|service| &service.ports)
+                .any(|port| tunnel.mirrors(port))
+        })
+        .collect()
+}
+
+/// Renders tunnels as i2pd tunnel config sections
+pub fn render_tunnels(tunnels: &[I2pTunnel]) -> String {
+    tunnels
+        .iter()
+        .map(|tunnel| tunnel.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{public_tunnels, render_tunnels, I2pTunnel};
+    use crate::composegenerator::tor::{HiddenService, HiddenServicePort};
+
+    fn tunnel(name: &str, port: u16, inport: Option<u16>) -> I2pTunnel {
+        I2pTunnel {
+            name: name.to_string(),
+            host: "10.21.21.20".to_string(),
+            port,
+            inport,
+            keys: "app-example-main.dat".to_string(),
+        }
+    }
+
+    fn hidden_service(dir: &str, ports: &[(u16, u16)], client_auth: bool) -> HiddenService {
+        HiddenService {
+            dir: dir.to_string(),
+            ports: ports
+                .iter()
+                .map(|(virtual_port, target_port)| HiddenServicePort {
+                    virtual_port: *virtual_port,
+                    target_host: "10.21.21.20".to_string(),
+                    target_port: *target_port,
+                })
+                .collect(),
+            client_auth,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_keeps_tunnels_of_public_hidden_services() {
+        let tunnels = vec![
+            tunnel("app-example-main", 3000, None),
+            tunnel("app-example-main-8333", 18333, Some(8333)),
+            // Tor dropped this port, because port 80 is already used by the main port
+            tunnel("app-example-main-80", 8080, Some(80)),
+            tunnel("app-example-admin-8080", 8080, Some(8080)),
+        ];
+        let hidden_services = [
+            hidden_service("app-example", &[(80, 3000), (8333, 18333)], false),
+            hidden_service("app-example-admin", &[(8080, 8080)], true),
+        ];
+        let names: Vec<String> = public_tunnels(tunnels.clone(), &hidden_services)
+            .into_iter()
+            .map(|tunnel| tunnel.name)
+            .collect();
+        assert_eq!(names, ["app-example-main", "app-example-main-8333"]);
+        // Without any hidden services, nothing is reachable over Tor, so nothing is over I2P either
+        assert!(public_tunnels(tunnels, &[]).is_empty());
+    }
+
+    #[test]
+    fn renders_tunnels() {
+        let tunnels = [
+            I2pTunnel {
+                name: "app-example-main".to_string(),
+                host: "10.21.21.20".to_string(),
+                port: 3000,
+                inport: None,
+                keys: "app-example-main.dat".to_string(),
+            },
+            I2pTunnel {
+                name: "app-example-main-8333".to_string(),
+                host: "10.21.21.20".to_string(),
+                port: 18333,
+                inport: Some(8333),
+                keys: "app-example-main.dat".to_string(),
+            },
+        ];
+        assert_eq!(
+            render_tunnels(&tunnels),
+            "[app-example-main]\n\
+             host = 10.21.21.20\n\
+             port = 3000\n\
+             keys = app-example-main.dat\n\
+             \n\
+             [app-example-main-8333]\n\
+             host = 10.21.21.20\n\
+             port = 18333\n\
+             inport = 8333\n\
+             keys = app-example-main.dat\n"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+
+use super::{compose::ComposeBackend, kubernetes::KubernetesBackend, quadlet::QuadletBackend};
+use crate::composegenerator::types::ResultYml;
+
+/// The files generated for an app (file name -> contents)
+pub type OutputFiles = BTreeMap<String, String>;
+
+/// Turns a converted app into files a container runtime can run it from
+///
+/// Apps are rendered in parallel, so backends have to be thread-safe.
+pub trait OutputBackend: Send + Sync {
+    /// The name used to select this backend
+    fn name(&self) -> &'static str;
+
+    /// Checks if a file in an app's directory could have been generated by this backend
+    fn is_output_file(&self, file_name: &str) -> bool;
+
+    /// Checks if a generated file contains secrets, it is then only readable by its owner
+    fn is_private_file(&self, _file_name: &str) -> bool {
+        false
+    }
+
+    /// Checks if a generated file can contain secrets, like resolved seeds,
+    /// it is then left out of diffs, but keeps its permissions
+    fn contains_secrets(&self, _file_name: &str) -> bool {
+        false
+    }
+
+    /// Renders the files for an app
+    ///
+    /// env contains the values of the variables the converted app refers to,
+    /// for backends that can not resolve them at runtime like Docker Compose does.
+    fn render(
+        &self,
+        app_id: &str,
+        result: &ResultYml,
+        env: &HashMap<String, String>,
+    ) -> Result<OutputFiles>;
+
+    /// Renders the files all apps share, they are written once next to the apps' directories
+    fn render_shared(&self, _env: &HashMap<String, String>) -> Result<OutputFiles> {
+        Ok(OutputFiles::new())
+    }
+}
+
+pub const DEFAULT_BACKEND: &str = "compose";
+
+pub fn all_backends() -> Vec<Box<dyn OutputBackend>> {
+    vec![
+        Box::new(ComposeBackend),
+        Box::new(KubernetesBackend),
+        Box::new(QuadletBackend),
+    ]
+}
+
+pub fn get_backend(name: &str) -> Result<Box<dyn OutputBackend>> {
+    let Some(backend) = all_backends()
+        .into_iter()
+        .find(|backend| backend.name() == name)
+    else {
+        let names: Vec<&str> = all_backends()
+            .iter()
+            .map(|backend| backend.name())
+            .collect();
+        bail!(
+            "Unknown output backend {}, use one of {}",
+            name,
+            names.join(", ")
+        );
+    };
+    Ok(backend)
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use super::backend::{OutputBackend, OutputFiles};
+use crate::composegenerator::types::ResultYml;
+
+pub const COMPOSE_FILE: &str = "docker-compose.yml";
+
+/// Writes the app's docker-compose.yml, variables are resolved by Docker Compose
+pub struct ComposeBackend;
+
+impl OutputBackend for ComposeBackend {
+    fn name(&self) -> &'static str {
+        "compose"
+    }
+
+    fn is_output_file(&self, file_name: &str) -> bool {
+        file_name == COMPOSE_FILE
+    }
+
+    fn render(
+        &self,
+        _app_id: &str,
+        result: &ResultYml,
+        _env: &HashMap<String, String>,
+    ) -> Result<OutputFiles> {
+        Ok(OutputFiles::from([(
+            COMPOSE_FILE.to_string(),
+            serde_yaml::to_string(&result.spec)?,
+        )]))
+    }
+}
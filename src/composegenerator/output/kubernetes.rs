@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use super::{
+    backend::{OutputBackend, OutputFiles},
+    types::Service,
+};
+use crate::{
+    composegenerator::{
+        compose::types::{Command, StringOrIntOrBool},
+        types::ResultYml,
+    },
+    utils::{find_env_vars, parse_size, replace_env_vars},
+};
+
+pub const KUBERNETES_FILE: &str = "kubernetes.yml";
+
+/// Writes Kubernetes manifests for an app to kubernetes.yml
+///
+/// Every container becomes a Deployment with a headless Service, so the other containers
+/// can reach it by name instead of its fixed IP. Seeds are stored in a Secret,
+/// mounts and named volumes become hostPath volumes.
+pub struct KubernetesBackend;
+
+impl OutputBackend for KubernetesBackend {
+    fn name(&self) -> &'static str {
+        "kubernetes"
+    }
+
+    fn is_output_file(&self, file_name: &str) -> bool {
+        file_name == KUBERNETES_FILE
+    }
+
+    // The Secret with the seeds is part of the manifests
+    fn is_private_file(&self, file_name: &str) -> bool {
+        file_name == KUBERNETES_FILE
+    }
+
+    fn render(
+        &self,
+        app_id: &str,
+        result: &ResultYml,
+        env: &HashMap<String, String>,
+    ) -> Result<OutputFiles> {
+        let services = result.spec.services.clone().unwrap_or_default();
+        let mut resolver = Resolver {
+            env,
+            hosts: HashMap::new(),
+            seeds: BTreeSet::new(),
+        };
+        for (service_name, service) in &services {
+            let ip_vars = service
+                .networks
+                .iter()
+                .flatten()
+                .filter_map(|(_, network)| network.ipv4_address.as_ref())
+                .flat_map(|address| find_env_vars(address));
+            for ip_var in ip_vars {
+                resolver
+                    .hosts
+                    .insert(ip_var.to_string(), resource_name(app_id, service_name));
+            }
+        }
+        let named_volumes: Vec<&String> = result
+            .spec
+            .volumes
+            .iter()
+            .flatten()
+            .map(|(name, _)| name)
+            .collect();
+
+        let mut documents = Vec::new();
+        for (service_name, service) in &services {
+            documents.push(deployment(
+                app_id,
+                service_name,
+                service,
+                &named_volumes,
+                &mut resolver,
+            )?);
+            if service.network_mode.as_deref() != Some("host") {
+                documents.push(headless_service(
+                    app_id,
+                    service_name,
+                    service,
+                    &mut resolver,
+                )?);
+            }
+        }
+        if !resolver.seeds.is_empty() {
+            let mut seeds = BTreeMap::new();
+            for seed in &resolver.seeds {
+                let Some(value) = env.get(seed) else {
+                    bail!("{} is not available yet", seed);
+                };
+                seeds.insert(seed.clone(), value.clone());
+            }
+            documents.insert(
+                0,
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "Secret",
+                    "metadata": {
+                        "name": seed_secret_name(app_id),
+                        "labels": { "app.kubernetes.io/part-of": app_id },
+                    },
+                    "type": "Opaque",
+                    "stringData": seeds,
+                }),
+            );
+        }
+
+        let mut manifests = String::new();
+        for document in documents {
+            if !manifests.is_empty() {
+                manifests.push_str("---\n");
+            }
+            manifests += &serde_yaml::to_string(&document)?;
+        }
+        Ok(OutputFiles::from([(
+            KUBERNETES_FILE.to_string(),
+            manifests,
+        )]))
+    }
+}
+
+struct Resolver<'a> {
+    env: &'a HashMap<String, String>,
+    /// The IP variables of the app's containers -> the name of their Service
+    hosts: HashMap<String, String>,
+    /// The seeds used by the app, they are put into a Secret instead of the manifests
+    seeds: BTreeSet<String>,
+}
+
+impl Resolver<'_> {
+    /// Replaces variables with their values
+    ///
+    /// If seeds is true, seeds are replaced with a reference Kubernetes expands at runtime.
+    fn resolve(&mut self, value: &str, seeds: bool) -> Result<String> {
+        replace_env_vars(value, |var| {
+            if seeds && var.starts_with("APP_SEED") {
+                self.seeds.insert(var.to_string());
+                return Ok(format!("$({})", var));
+            }
+            if let Some(host) = self.hosts.get(var) {
+                return Ok(host.clone());
+            }
+            match self.env.get(var) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("Variable {} is not set", var),
+            }
+        })
+    }
+
+    fn resolve_command(&mut self, command: &Command) -> Result<Vec<String>> {
+        let args = match command {
+            Command::SimpleCommand(command) => shell_words::split(command)?,
+            Command::ArrayCommand(args) => args.clone(),
+        };
+        args.iter().map(|arg| self.resolve(arg, true)).collect()
+    }
+}
+
+/// Kubernetes names may only contain lowercase letters, digits and -
+fn resource_name(app_id: &str, service_name: &str) -> String {
+    format!("{}-{}", app_id, service_name)
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+fn seed_secret_name(app_id: &str) -> String {
+    format!("{}-seeds", app_id)
+}
+
+fn labels(app_id: &str, service_name: &str) -> Value {
+    json!({
+        "app.kubernetes.io/name": service_name,
+        "app.kubernetes.io/part-of": app_id,
+    })
+}
+
+struct Port {
+    host: u16,
+    container: u16,
+    protocol: &'static str,
+}
+
+fn parse_port(port: &str) -> Result<Port> {
+    let (ports, protocol) = match port.split_once('/') {
+        Some((ports, "udp")) => (ports, "UDP"),
+        Some((ports, "tcp")) | Some((ports, "")) => (ports, "TCP"),
+        None => (port, "TCP"),
+        Some((_, protocol)) => bail!("Unsupported protocol {}", protocol),
+    };
+    let Some((host, container)) = ports.split_once(':') else {
+        bail!("Invalid port {}", port);
+    };
+    Ok(Port {
+        host: host.parse()?,
+        container: container.parse()?,
+        protocol,
+    })
+}
+
+fn deployment(
+    app_id: &str,
+    service_name: &str,
+    service: &Service,
+    named_volumes: &[&String],
+    resolver: &mut Resolver,
+) -> Result<Value> {
+    let Some(image) = &service.image else {
+        bail!("Container {} does not have an image", service_name);
+    };
+    let mut container = json!({
+        "name": service_name.to_lowercase().replace('_', "-"),
+        "image": resolver.resolve(image, false)?,
+    });
+    if let Some(entrypoint) = &service.entrypoint {
+        container["command"] = json!(resolver.resolve_command(entrypoint)?);
+    }
+    if let Some(command) = &service.command {
+        container["args"] = json!(resolver.resolve_command(command)?);
+    }
+    if let Some(working_dir) = &service.working_dir {
+        container["workingDir"] = json!(working_dir);
+    }
+
+    let mut env = Vec::new();
+    for (key, value) in service.environment.iter().flatten() {
+        let value = match value {
+            StringOrIntOrBool::String(value) => resolver.resolve(value, true)?,
+            StringOrIntOrBool::Int(value) => value.to_string(),
+            StringOrIntOrBool::Bool(value) => value.to_string(),
+        };
+        env.push(json!({ "name": key, "value": value }));
+    }
+    // Kubernetes only expands variables that were defined before
+    let used_seeds: Vec<Value> = resolver
+        .seeds
+        .iter()
+        .filter(|seed| {
+            let reference = format!("$({})", seed);
+            env.iter()
+                .chain(container.get("command"))
+                .chain(container.get("args"))
+                .any(|value| value.to_string().contains(&reference))
+        })
+        .map(|seed| {
+            json!({
+                "name": seed,
+                "valueFrom": {
+                    "secretKeyRef": { "name": seed_secret_name(app_id), "key": seed },
+                },
+            })
+        })
+        .collect();
+    env.splice(0..0, used_seeds);
+    if !env.is_empty() {
+        container["env"] = json!(env);
+    }
+
+    let mut ports = Vec::new();
+    for port in &service.ports {
+        let port = parse_port(&resolver.resolve(port, false)?)?;
+        ports.push(json!({
+            "containerPort": port.container,
+            "hostPort": port.host,
+            "protocol": port.protocol,
+        }));
+    }
+    if !ports.is_empty() {
+        container["ports"] = json!(ports);
+    }
+
+    let mut volume_mounts = Vec::new();
+    let mut volumes = Vec::new();
+    for (i, volume) in service.volumes.iter().enumerate() {
+        let mut parts = volume.splitn(3, ':');
+        let (Some(source), Some(target)) = (parts.next(), parts.next()) else {
+            bail!("Invalid volume {}", volume);
+        };
+        let path = if named_volumes.iter().any(|name| *name == source) {
+            format!(
+                "{}/volumes/{}",
+                resolver.resolve("$APP_DATA_DIR", false)?,
+                source
+            )
+        } else {
+            resolver.resolve(source, false)?
+        };
+        let name = format!("volume-{}", i);
+        let mut mount = json!({ "name": name, "mountPath": target });
+        if parts.next() == Some("ro") {
+            mount["readOnly"] = json!(true);
+        }
+        volume_mounts.push(mount);
+        volumes.push(json!({
+            "name": name,
+            "hostPath": { "path": path, "type": "DirectoryOrCreate" },
+        }));
+    }
+    for (i, tmpfs) in service.tmpfs.iter().enumerate() {
+        let (target, options) = tmpfs.split_once(':').unwrap_or((tmpfs, ""));
+        let name = format!("tmpfs-{}", i);
+        let mut empty_dir = json!({ "medium": "Memory" });
+        if let Some(size) = options
+            .split(',')
+            .find_map(|option| option.strip_prefix("size="))
+        {
+            empty_dir["sizeLimit"] = json!(parse_size(size)?.to_string());
+        }
+        volume_mounts.push(json!({ "name": name, "mountPath": target }));
+        volumes.push(json!({ "name": name, "emptyDir": empty_dir }));
+    }
+    if !volume_mounts.is_empty() {
+        container["volumeMounts"] = json!(volume_mounts);
+    }
+
+    let mut security_context = json!({});
+    if let Some(user) = &service.user {
+        let user = resolver.resolve(user, false)?;
+        let (uid, gid) = user.split_once(':').unwrap_or((&user, ""));
+        match uid.parse::<u32>() {
+            Ok(uid) => security_context["runAsUser"] = json!(uid),
+            Err(_) => tracing::warn!("Ignoring non-numeric user {} of {}", uid, service_name),
+        }
+        if let Ok(gid) = gid.parse::<u32>() {
+            security_context["runAsGroup"] = json!(gid);
+        }
+    }
+    if let Some(cap_add) = &service.cap_add {
+        security_context["capabilities"] = json!({ "add": cap_add });
+    }
+    if security_context != json!({}) {
+        container["securityContext"] = security_context;
+    }
+
+    let mut pod_spec = json!({ "containers": [container] });
+    if service.network_mode.as_deref() == Some("host") {
+        pod_spec["hostNetwork"] = json!(true);
+        pod_spec["dnsPolicy"] = json!("ClusterFirstWithHostNet");
+    }
+    if let Some(extra_hosts) = &service.extra_hosts {
+        let mut host_aliases: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for extra_host in extra_hosts {
+            let extra_host = resolver.resolve(extra_host, false)?;
+            let Some((hostname, ip)) = extra_host.split_once(':') else {
+                bail!("Invalid extra host {}", extra_host);
+            };
+            host_aliases
+                .entry(ip.to_string())
+                .or_default()
+                .push(hostname.to_string());
+        }
+        pod_spec["hostAliases"] = host_aliases
+            .into_iter()
+            .map(|(ip, hostnames)| json!({ "ip": ip, "hostnames": hostnames }))
+            .collect();
+    }
+    if !volumes.is_empty() {
+        pod_spec["volumes"] = json!(volumes);
+    }
+
+    Ok(json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {
+            "name": resource_name(app_id, service_name),
+            "labels": labels(app_id, service_name),
+        },
+        "spec": {
+            "replicas": 1,
+            // hostPath volumes and host ports can not be shared with a new pod
+            "strategy": { "type": "Recreate" },
+            "selector": { "matchLabels": labels(app_id, service_name) },
+            "template": {
+                "metadata": { "labels": labels(app_id, service_name) },
+                "spec": pod_spec,
+            },
+        },
+    }))
+}
+
+fn headless_service(
+    app_id: &str,
+    service_name: &str,
+    service: &Service,
+    resolver: &mut Resolver,
+) -> Result<Value> {
+    let mut ports = Vec::new();
+    for port in &service.ports {
+        let port = parse_port(&resolver.resolve(port, false)?)?;
+        ports.push(json!({
+            "name": format!("{}-{}", port.protocol.to_lowercase(), port.container),
+            "port": port.container,
+            "targetPort": port.container,
+            "protocol": port.protocol,
+        }));
+    }
+    let mut spec = json!({
+        "clusterIP": "None",
+        "selector": labels(app_id, service_name),
+    });
+    if !ports.is_empty() {
+        spec["ports"] = json!(ports);
+    }
+    Ok(json!({
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": {
+            "name": resource_name(app_id, service_name),
+            "labels": labels(app_id, service_name),
+        },
+        "spec": spec,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::KubernetesBackend;
+    use crate::{
+        bmap,
+        composegenerator::{
+            compose::types::StringOrIntOrBool,
+            output::{
+                backend::OutputBackend,
+                types::{ComposeSpecification, NetworkEntry, Service},
+            },
+            types::ResultYml,
+        },
+        map,
+    };
+
+    #[test]
+    fn renders_manifests() {
+        let result = ResultYml {
+            hidden_services: Vec::new(),
+            i2p_tunnels: Vec::new(),
+            proxy_route: Default::default(),
+            metadata: Default::default(),
+            spec: ComposeSpecification {
+                services: Some(bmap! {
+                    "main" => Service {
+                        image: Some("ghcr.io/runcitadel/example:main".to_string()),
+                        user: Some("1000:1000".to_string()),
+                        ports: vec!["3000:3000".to_string()],
+                        environment: Some(bmap! {
+                            "PASSWORD" => StringOrIntOrBool::String("${APP_SEED}".to_string()),
+                            "DB_HOST" => StringOrIntOrBool::String("$APP_EXAMPLE_DB_IP".to_string())
+                        }),
+                        volumes: vec!["${APP_DATA_DIR}/data:/data".to_string()],
+                        ..Default::default()
+                    },
+                    "db" => Service {
+                        image: Some("ghcr.io/runcitadel/example-db:main".to_string()),
+                        networks: Some(bmap! {
+                            "default" => NetworkEntry {
+                                ipv4_address: Some("$APP_EXAMPLE_DB_IP".to_string())
+                            }
+                        }),
+                        ..Default::default()
+                    }
+                }),
+                ..Default::default()
+            },
+        };
+        let env: HashMap<String, String> = map! {
+            "APP_DATA_DIR" => "/citadel/app-data/example".to_string(),
+            "APP_SEED" => "secret".to_string()
+        };
+        let files = KubernetesBackend.render("example", &result, &env).unwrap();
+        let documents: Vec<Value> = serde_yaml::Deserializer::from_str(&files["kubernetes.yml"])
+            .map(|document| Value::deserialize(document).unwrap())
+            .collect();
+        let kinds: Vec<&str> = documents
+            .iter()
+            .map(|document| document["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["Secret", "Deployment", "Service", "Deployment", "Service"]
+        );
+        assert_eq!(documents[0]["stringData"], json!({ "APP_SEED": "secret" }));
+        let main = &documents[3]["spec"]["template"]["spec"];
+        assert_eq!(
+            main["containers"][0]["env"],
+            json!([
+                {
+                    "name": "APP_SEED",
+                    "valueFrom": { "secretKeyRef": { "name": "example-seeds", "key": "APP_SEED" } },
+                },
+                { "name": "DB_HOST", "value": "example-db" },
+                { "name": "PASSWORD", "value": "$(APP_SEED)" },
+            ])
+        );
+        assert_eq!(
+            main["volumes"][0]["hostPath"]["path"],
+            "/citadel/app-data/example/data"
+        );
+        assert_eq!(main["containers"][0]["securityContext"]["runAsUser"], 1000);
+        assert_eq!(documents[4]["spec"]["ports"][0]["name"], json!("tcp-3000"));
+    }
+}
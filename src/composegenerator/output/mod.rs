@@ -1 +1,5 @@
+pub mod backend;
+pub mod compose;
+pub mod kubernetes;
+pub mod quadlet;
 pub mod types;
@@ -0,0 +1,489 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use anyhow::{bail, Result};
+
+use super::{
+    backend::{OutputBackend, OutputFiles},
+    types::Service,
+};
+use crate::{
+    composegenerator::{
+        compose::types::{Command, StringOrIntOrBool},
+        types::ResultYml,
+    },
+    utils::replace_env_vars,
+};
+
+/// The network all apps are attached to, so they can reach each other and the node's services
+pub const NETWORK_NAME: &str = "citadel";
+
+const ENV_FILE_SUFFIX: &str = ".container.env";
+
+/// Writes Podman quadlet files (.container and .volume) for an app
+///
+/// systemd generates a <app>-<container>.service unit for every container,
+/// depends_on is turned into dependencies between these units.
+/// The network all apps use is a shared .network file.
+/// Podman does not resolve variables, so they are replaced with their values.
+/// The containers' variables can contain secrets like the app's seeds,
+/// they are written to a .container.env file next to the unit that only its owner can read.
+pub struct QuadletBackend;
+
+impl OutputBackend for QuadletBackend {
+    fn name(&self) -> &'static str {
+        "quadlet"
+    }
+
+    fn is_output_file(&self, file_name: &str) -> bool {
+        file_name.ends_with(".container")
+            || file_name.ends_with(".volume")
+            || file_name.ends_with(".network")
+            || file_name.ends_with(ENV_FILE_SUFFIX)
+    }
+
+    fn is_private_file(&self, file_name: &str) -> bool {
+        file_name.ends_with(ENV_FILE_SUFFIX)
+    }
+
+    // Commands and entrypoints are written with their variables resolved
+    fn contains_secrets(&self, file_name: &str) -> bool {
+        file_name.ends_with(".container")
+    }
+
+    fn render(
+        &self,
+        app_id: &str,
+        result: &ResultYml,
+        env: &HashMap<String, String>,
+    ) -> Result<OutputFiles> {
+        let mut files = OutputFiles::new();
+        let mut volume_units = HashMap::new();
+        for (volume_name, volume) in result.spec.volumes.iter().flatten() {
+            let unit_name = format!("{}-{}.volume", app_id, volume_name);
+            let mut contents = "[Volume]\n".to_string();
+            if let Some(name) = &volume.name {
+                writeln!(contents, "VolumeName={}", name)?;
+            }
+            files.insert(unit_name.clone(), contents);
+            volume_units.insert(volume_name.clone(), unit_name);
+        }
+        let mut network_units = HashMap::new();
+        for (network_name, network) in result.spec.networks.iter().flatten() {
+            // External networks are not managed by the app, containers join them by name
+            if network.external.is_some() {
+                network_units.insert(network_name.clone(), network_name.clone());
+                continue;
+            }
+            let unit_name = format!("{}-{}.network", app_id, network_name);
+            let mut contents = "[Network]\n".to_string();
+            if let Some(name) = &network.name {
+                writeln!(contents, "NetworkName={}", name)?;
+            }
+            files.insert(unit_name.clone(), contents);
+            network_units.insert(network_name.clone(), unit_name);
+        }
+
+        for (service_name, service) in result.spec.services.iter().flatten() {
+            let env_file = env_file(service, env)?;
+            if !env_file.is_empty() {
+                files.insert(
+                    format!("{}{}", unit_name(app_id, service_name), ENV_FILE_SUFFIX),
+                    env_file,
+                );
+            }
+            files.insert(
+                format!("{}.container", unit_name(app_id, service_name)),
+                container_unit(
+                    app_id,
+                    service_name,
+                    service,
+                    &volume_units,
+                    &network_units,
+                    env,
+                )?,
+            );
+        }
+        Ok(files)
+    }
+
+    fn render_shared(&self, env: &HashMap<String, String>) -> Result<OutputFiles> {
+        let mut contents = format!("[Network]\nNetworkName={}\n", NETWORK_NAME);
+        if let Some(subnet) = env.get("APPS_SUBNET") {
+            writeln!(contents, "Subnet={}", subnet)?;
+        }
+        if let Some(gateway) = env.get("GATEWAY_IP") {
+            writeln!(contents, "Gateway={}", gateway)?;
+        }
+        let mut files = OutputFiles::new();
+        files.insert(format!("{}.network", NETWORK_NAME), contents);
+        Ok(files)
+    }
+}
+
+fn unit_name(app_id: &str, service_name: &str) -> String {
+    format!("{}-{}", app_id, service_name)
+}
+
+fn resolve(value: &str, env: &HashMap<String, String>) -> Result<String> {
+    replace_env_vars(value, |var| match env.get(var) {
+        Some(value) => Ok(value.clone()),
+        None => bail!("Variable {} is not set", var),
+    })
+}
+
+/// Quotes a value so systemd passes it on unchanged
+///
+/// systemd expands specifiers (%) and variables ($) in these values, so both are escaped.
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains(|c: char| c.is_whitespace() || c == '\'') {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+fn command_args(command: &Command, env: &HashMap<String, String>) -> Result<Vec<String>> {
+    let args = match command {
+        Command::SimpleCommand(command) => shell_words::split(command)?,
+        Command::ArrayCommand(args) => args.clone(),
+    };
+    args.iter().map(|arg| resolve(arg, env)).collect()
+}
+
+fn command_line(command: &Command, env: &HashMap<String, String>) -> Result<String> {
+    let args = command_args(command, env)?;
+    Ok(args
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Podman takes the entrypoint as a single value, multiple arguments have to be a JSON array
+fn entrypoint_value(command: &Command, env: &HashMap<String, String>) -> Result<String> {
+    let args = command_args(command, env)?;
+    if let [arg] = args.as_slice() {
+        return Ok(quote(arg));
+    }
+    Ok(serde_json::to_string(&args)?
+        .replace('%', "%%")
+        .replace('$', "$$"))
+}
+
+/// Podman reads env files line by line, without quotes or escapes
+fn env_file(service: &Service, env: &HashMap<String, String>) -> Result<String> {
+    let mut contents = String::new();
+    for (key, value) in service.environment.iter().flatten() {
+        let value = match value {
+            StringOrIntOrBool::String(value) => resolve(value, env)?,
+            StringOrIntOrBool::Int(value) => value.to_string(),
+            StringOrIntOrBool::Bool(value) => value.to_string(),
+        };
+        if value.contains('\n') {
+            bail!("The value of {} contains a line break", key);
+        }
+        writeln!(contents, "{}={}", key, value)?;
+    }
+    Ok(contents)
+}
+
+fn container_unit(
+    app_id: &str,
+    service_name: &str,
+    service: &Service,
+    volume_units: &HashMap<String, String>,
+    network_units: &HashMap<String, String>,
+    env: &HashMap<String, String>,
+) -> Result<String> {
+    let Some(image) = &service.image else {
+        bail!("Container {} does not have an image", service_name);
+    };
+    let mut unit = format!("[Unit]\nDescription={} {}\n", app_id, service_name);
+    for dependency in service.depends_on.iter().flatten() {
+        let dependency = unit_name(app_id, dependency);
+        writeln!(unit, "Requires={}.service", dependency)?;
+        writeln!(unit, "After={}.service", dependency)?;
+    }
+
+    unit.push_str("\n[Container]\n");
+    writeln!(unit, "ContainerName={}", unit_name(app_id, service_name))?;
+    writeln!(unit, "Image={}", resolve(image, env)?)?;
+    if let Some(entrypoint) = &service.entrypoint {
+        writeln!(unit, "Entrypoint={}", entrypoint_value(entrypoint, env)?)?;
+    }
+    if let Some(command) = &service.command {
+        writeln!(unit, "Exec={}", command_line(command, env)?)?;
+    }
+    if let Some(working_dir) = &service.working_dir {
+        writeln!(unit, "WorkingDir={}", working_dir)?;
+    }
+    if let Some(user) = &service.user {
+        let user = resolve(user, env)?;
+        match user.split_once(':') {
+            Some((user, group)) => {
+                writeln!(unit, "User={}", user)?;
+                writeln!(unit, "Group={}", group)?;
+            }
+            None => writeln!(unit, "User={}", user)?,
+        }
+    }
+    if service.init == Some(true) {
+        unit.push_str("RunInit=true\n");
+    }
+    for cap in service.cap_add.iter().flatten() {
+        writeln!(unit, "AddCapability={}", cap)?;
+    }
+    if service
+        .environment
+        .as_ref()
+        .is_some_and(|env| !env.is_empty())
+    {
+        // Relative paths are resolved from the unit's directory
+        writeln!(
+            unit,
+            "EnvironmentFile={}{}",
+            unit_name(app_id, service_name),
+            ENV_FILE_SUFFIX
+        )?;
+    }
+
+    if service.network_mode.as_deref() == Some("host") {
+        unit.push_str("Network=host\n");
+    } else {
+        let address = service
+            .networks
+            .iter()
+            .flatten()
+            .find_map(|(_, network)| network.ipv4_address.as_ref());
+        let mut extra_networks = Vec::new();
+        for network_name in service.networks.iter().flatten().map(|(name, _)| name) {
+            if network_name == "default" {
+                continue;
+            }
+            let Some(network_unit) = network_units.get(network_name) else {
+                bail!("Network {} is not defined", network_name);
+            };
+            extra_networks.push(network_unit);
+        }
+        match address {
+            // Podman only accepts IP= for containers on a single network
+            Some(address) if !extra_networks.is_empty() => writeln!(
+                unit,
+                "Network={}.network:ip={}",
+                NETWORK_NAME,
+                resolve(address, env)?
+            )?,
+            Some(address) => {
+                writeln!(unit, "Network={}.network", NETWORK_NAME)?;
+                writeln!(unit, "IP={}", resolve(address, env)?)?;
+            }
+            None => writeln!(unit, "Network={}.network", NETWORK_NAME)?,
+        }
+        for network_unit in extra_networks {
+            writeln!(unit, "Network={}", network_unit)?;
+        }
+    }
+    for extra_host in service.extra_hosts.iter().flatten() {
+        writeln!(unit, "AddHost={}", resolve(extra_host, env)?)?;
+    }
+    for port in &service.ports {
+        writeln!(unit, "PublishPort={}", resolve(port, env)?)?;
+    }
+    for volume in &service.volumes {
+        let Some((source, target)) = volume.split_once(':') else {
+            bail!("Invalid volume {}", volume);
+        };
+        let source = match volume_units.get(source) {
+            Some(volume_unit) => volume_unit.clone(),
+            None => resolve(source, env)?,
+        };
+        writeln!(unit, "Volume={}:{}", source, target)?;
+    }
+    for tmpfs in &service.tmpfs {
+        writeln!(unit, "Tmpfs={}", tmpfs)?;
+    }
+    if let Some(logging) = &service.logging {
+        if let Some(driver) = &logging.driver {
+            writeln!(unit, "LogDriver={}", driver)?;
+        }
+        let options: BTreeMap<&String, String> = logging
+            .options
+            .iter()
+            .flatten()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value.clone()),
+                _ => (key, value.to_string()),
+            })
+            .collect();
+        for (key, value) in options {
+            writeln!(unit, "PodmanArgs=--log-opt={}={}", key, quote(&value))?;
+        }
+    }
+    if let Some(stop_signal) = &service.stop_signal {
+        writeln!(unit, "PodmanArgs=--stop-signal={}", stop_signal)?;
+    }
+
+    let restart = match service.restart.as_deref() {
+        Some("always") | Some("unless-stopped") => "always",
+        Some("on-failure") => "on-failure",
+        _ => "no",
+    };
+    writeln!(unit, "\n[Service]\nRestart={}", restart)?;
+    unit.push_str("\n[Install]\nWantedBy=default.target\n");
+    Ok(unit)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::QuadletBackend;
+    use crate::{
+        bmap,
+        composegenerator::{
+            compose::types::{Command, Network, StringOrIntOrBool, Volume},
+            output::{
+                backend::OutputBackend,
+                types::{ComposeSpecification, NetworkEntry, Service},
+            },
+            types::ResultYml,
+        },
+        map,
+    };
+
+    #[test]
+    fn renders_units() {
+        let result = ResultYml {
+            hidden_services: Vec::new(),
+            i2p_tunnels: Vec::new(),
+            proxy_route: Default::default(),
+            metadata: Default::default(),
+            spec: ComposeSpecification {
+                services: Some(bmap! {
+                    "main" => Service {
+                        image: Some("ghcr.io/runcitadel/example:main".to_string()),
+                        user: Some("1000:1000".to_string()),
+                        command: Some(Command::ArrayCommand(vec![
+                            "serve".to_string(),
+                            "--password=$APP_PASSWORD".to_string(),
+                        ])),
+                        depends_on: Some(vec!["db".to_string()]),
+                        restart: Some("on-failure".to_string()),
+                        ports: vec!["3000:3000".to_string()],
+                        environment: Some(bmap! {
+                            "GREETING" => StringOrIntOrBool::String("Hello 100% for 5$".to_string()),
+                            "APP_SEED" => StringOrIntOrBool::String("$APP_SEED".to_string())
+                        }),
+                        networks: Some(bmap! {
+                            "backend" => NetworkEntry::default(),
+                            "default" => NetworkEntry {
+                                ipv4_address: Some("$APP_EXAMPLE_MAIN_IP".to_string())
+                            },
+                            "monitoring" => NetworkEntry::default()
+                        }),
+                        volumes: vec![
+                            "${APP_DATA_DIR}/data:/data".to_string(),
+                            "cache:/cache".to_string(),
+                        ],
+                        ..Default::default()
+                    },
+                    "db" => Service {
+                        image: Some("ghcr.io/runcitadel/example-db:main".to_string()),
+                        entrypoint: Some(Command::SimpleCommand(
+                            "/bin/sh -c 'exec db --cache=50%'".to_string(),
+                        )),
+                        network_mode: Some("host".to_string()),
+                        ..Default::default()
+                    }
+                }),
+                networks: Some(bmap! {
+                    "backend" => Network {
+                        name: Some("example_backend".to_string()),
+                        ..Default::default()
+                    },
+                    "monitoring" => Network {
+                        external: Some(serde_json::Value::Bool(true)),
+                        ..Default::default()
+                    }
+                }),
+                volumes: Some(bmap! {
+                    "cache" => Volume {
+                        name: Some("example_cache".to_string()),
+                        ..Default::default()
+                    }
+                }),
+            },
+        };
+        let env: HashMap<String, String> = map! {
+            "APP_DATA_DIR" => "/citadel/app-data/example".to_string(),
+            "APP_PASSWORD" => "pa$$word".to_string(),
+            "APP_EXAMPLE_MAIN_IP" => "10.21.21.20".to_string(),
+            "APPS_SUBNET" => "10.21.21.0/24".to_string(),
+            "GATEWAY_IP" => "10.21.21.1".to_string(),
+            "APP_SEED" => "0123456789abcdef".to_string()
+        };
+        let files = QuadletBackend.render("example", &result, &env).unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                "example-backend.network",
+                "example-cache.volume",
+                "example-db.container",
+                "example-main.container",
+                "example-main.container.env"
+            ]
+        );
+        assert_eq!(
+            files["example-main.container"],
+            "[Unit]
+Description=example main
+Requires=example-db.service
+After=example-db.service
+
+[Container]
+ContainerName=example-main
+Image=ghcr.io/runcitadel/example:main
+Exec=serve --password=pa$$$$word
+User=1000
+Group=1000
+EnvironmentFile=example-main.container.env
+Network=citadel.network:ip=10.21.21.20
+Network=example-backend.network
+Network=monitoring
+PublishPort=3000:3000
+Volume=/citadel/app-data/example/data:/data
+Volume=example-cache.volume:/cache
+
+[Service]
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+"
+        );
+        assert_eq!(
+            files["example-main.container.env"],
+            "APP_SEED=0123456789abcdef\nGREETING=Hello 100% for 5$\n"
+        );
+        assert!(QuadletBackend.is_private_file("example-main.container.env"));
+        assert!(QuadletBackend.contains_secrets("example-main.container"));
+        assert!(files["example-db.container"]
+            .contains("Entrypoint=[\"/bin/sh\",\"-c\",\"exec db --cache=50%%\"]\n"));
+        assert_eq!(
+            files["example-backend.network"],
+            "[Network]\nNetworkName=example_backend\n"
+        );
+        assert_eq!(
+            QuadletBackend.render_shared(&env).unwrap()["citadel.network"],
+            "[Network]\nNetworkName=citadel\nSubnet=10.21.21.0/24\nGateway=10.21.21.1\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::bail;
+#[cfg(feature = "schema")]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Where a reverse proxy should send an app's requests to
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyRoute {
+    /// The app id, used as subdomain or path prefix
+    pub app: String,
+    /// The IP address of the main container (or a placeholder if it is not known yet)
+    pub host: String,
+    /// The port the main container listens on
+    pub port: u16,
+    /// The path the app's root should redirect to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyRouting {
+    /// Every app gets its own subdomain of APP_DOMAIN
+    #[default]
+    Subdomain,
+    /// Apps are available at APP_DOMAIN/<app id>/
+    Path,
+}
+
+impl FromStr for ProxyRouting {
+    type Err = anyhow::Error;
+
+    fn from_str(routing: &str) -> anyhow::Result<Self> {
+        match routing {
+            "subdomain" => Ok(ProxyRouting::Subdomain),
+            "path" => Ok(ProxyRouting::Path),
+            _ => bail!("Unknown proxy routing {}, use subdomain or path", routing),
+        }
+    }
+}
+
+impl ProxyRoute {
+    fn redirect_target(&self, prefix: &str) -> Option<String> {
+        self.path
+            .as_ref()
+            .filter(|path| !path.is_empty() && *path != "/")
+            .map(|path| format!("{}/{}", prefix, path.trim_start_matches('/')))
+    }
+
+    /// Renders the Caddyfile fragment for this app
+    ///
+    /// With path-based routing, this has to be placed inside the site block of the domain.
+    pub fn to_caddy(&self, domain: &str, routing: ProxyRouting) -> String {
+        let mut result = format!("# {}\n", self.app);
+        match routing {
+            ProxyRouting::Subdomain => {
+                writeln!(result, "{}.{} {{", self.app, domain).unwrap();
+                if let Some(target) = self.redirect_target("") {
+                    writeln!(result, "\tredir / {}", target).unwrap();
+                }
+                writeln!(result, "\treverse_proxy {}:{}", self.host, self.port).unwrap();
+                result.push_str("}\n");
+            }
+            ProxyRouting::Path => {
+                let prefix = format!("/{}", self.app);
+                let target = self
+                    .redirect_target(&prefix)
+                    .unwrap_or_else(|| format!("{}/", prefix));
+                writeln!(result, "redir {} {}", prefix, target).unwrap();
+                writeln!(result, "handle_path {}/* {{", prefix).unwrap();
+                writeln!(result, "\treverse_proxy {}:{}", self.host, self.port).unwrap();
+                result.push_str("}\n");
+            }
+        }
+        result
+    }
+
+    /// Renders the nginx fragment for this app
+    ///
+    /// With path-based routing, this has to be placed inside the server block of the domain.
+    pub fn to_nginx(&self, domain: &str, routing: ProxyRouting) -> String {
+        let (prefix, upstream) = match routing {
+            ProxyRouting::Subdomain => {
+                (String::new(), format!("http://{}:{}", self.host, self.port))
+            }
+            ProxyRouting::Path => (
+                format!("/{}", self.app),
+                format!("http://{}:{}/", self.host, self.port),
+            ),
+        };
+        let mut locations = String::new();
+        if let Some(target) = self.redirect_target(&prefix) {
+            locations += &nginx_block(
+                &format!("location = {}/", prefix),
+                &format!("return 302 {};\n", target),
+            );
+        }
+        let mut proxy_pass = format!("proxy_pass {};\n", upstream);
+        for header in NGINX_PROXY_HEADERS {
+            writeln!(proxy_pass, "proxy_set_header {};", header).unwrap();
+        }
+        proxy_pass.push_str("proxy_http_version 1.1;\n");
+        locations += &nginx_block(&format!("location {}/", prefix), &proxy_pass);
+        let fragment = match routing {
+            ProxyRouting::Subdomain => nginx_block(
+                "server",
+                &format!(
+                    "listen 80;\nserver_name {}.{};\n{}",
+                    self.app, domain, locations
+                ),
+            ),
+            ProxyRouting::Path => locations,
+        };
+        format!("# {}\n{}", self.app, fragment)
+    }
+}
+
+const NGINX_PROXY_HEADERS: [&str; 4] = [
+    "Host $host",
+    "X-Forwarded-For $proxy_add_x_forwarded_for",
+    "Upgrade $http_upgrade",
+    "Connection \"upgrade\"",
+];
+
+fn nginx_block(name: &str, body: &str) -> String {
+    format!("{} {{\n{}}}\n", name, indent(body, "    "))
+}
+
+/// Renders the Caddyfile include for all apps
+pub fn render_caddy(routes: &[ProxyRoute], domain: &str, routing: ProxyRouting) -> String {
+    let fragments: Vec<String> = routes
+        .iter()
+        .map(|route| route.to_caddy(domain, routing))
+        .collect();
+    match routing {
+        ProxyRouting::Subdomain => fragments.join("\n"),
+        ProxyRouting::Path => format!("{} {{\n{}}}\n", domain, indent(&fragments.join("\n"), "\t")),
+    }
+}
+
+/// Renders the nginx include for all apps
+pub fn render_nginx(routes: &[ProxyRoute], domain: &str, routing: ProxyRouting) -> String {
+    let fragments: Vec<String> = routes
+        .iter()
+        .map(|route| route.to_nginx(domain, routing))
+        .collect();
+    match routing {
+        ProxyRouting::Subdomain => fragments.join("\n"),
+        ProxyRouting::Path => format!(
+            "server {{\n    listen 80;\n    server_name {};\n\n{}}}\n",
+            domain,
+            indent(&fragments.join("\n"), "    ")
+        ),
+    }
+}
+
+fn indent(text: &str, indentation: &str) -> String {
+    text.lines()
+        .map(|line| {
+            if line.is_empty() {
+                "\n".to_string()
+            } else {
+                format!("{}{}\n", indentation, line)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render_caddy, render_nginx, ProxyRoute, ProxyRouting};
+
+    fn example_route() -> ProxyRoute {
+        ProxyRoute {
+            app: "example".to_string(),
+            host: "10.21.21.20".to_string(),
+            port: 3000,
+            path: Some("/login".to_string()),
+        }
+    }
+
+    #[test]
+    fn caddy_subdomain() {
+        assert_eq!(
+            render_caddy(
+                &[example_route()],
+                "node.example.com",
+                ProxyRouting::Subdomain
+            ),
+            "# example\n\
+             example.node.example.com {\n\
+             \tredir / /login\n\
+             \treverse_proxy 10.21.21.20:3000\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn caddy_path() {
+        assert_eq!(
+            render_caddy(&[example_route()], "node.example.com", ProxyRouting::Path),
+            "node.example.com {\n\
+             \t# example\n\
+             \tredir /example /example/login\n\
+             \thandle_path /example/* {\n\
+             \t\treverse_proxy 10.21.21.20:3000\n\
+             \t}\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn nginx_path() {
+        let mut route = example_route();
+        route.path = None;
+        assert_eq!(
+            render_nginx(&[route], "node.example.com", ProxyRouting::Path),
+            "server {
+    listen 80;
+    server_name node.example.com;
+
+    # example
+    location /example/ {
+        proxy_pass http://10.21.21.20:3000/;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection \"upgrade\";
+        proxy_http_version 1.1;
+    }
+}
+"
+        );
+    }
+
+    #[test]
+    fn nginx_subdomain() {
+        assert_eq!(
+            render_nginx(
+                &[example_route()],
+                "node.example.com",
+                ProxyRouting::Subdomain
+            ),
+            "# example
+server {
+    listen 80;
+    server_name example.node.example.com;
+    location = / {
+        return 302 /login;
+    }
+    location / {
+        proxy_pass http://10.21.21.20:3000;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection \"upgrade\";
+        proxy_http_version 1.1;
+    }
+}
+"
+        );
+    }
+}
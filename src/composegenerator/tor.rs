@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::{bail, Result};
+#[cfg(feature = "schema")]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct HiddenServicePort {
+    /// The port of the .onion address
+    pub virtual_port: u16,
+    /// The IP address of the container (or a placeholder if it is not known yet)
+    pub target_host: String,
+    /// The port inside the container
+    pub target_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct HiddenService {
+    /// The directory name in /var/lib/tor, also used to identify the service
+    pub dir: String,
+    pub ports: Vec<HiddenServicePort>,
+    /// The onion service version, Tor's default is used if this is not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    /// True if only clients in the service's authorized_clients directory can connect
+    #[serde(default)]
+    pub client_auth: bool,
+    /// Additional HiddenService* options for this service, without the HiddenService prefix
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub options: BTreeMap<String, String>,
+}
+
+impl HiddenService {
+    pub fn new(dir: String) -> Self {
+        HiddenService {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    /// Removes ports with a virtual port an earlier port already uses and returns them
+    ///
+    /// Tor would pick one of the targets at random, so only the first one is kept.
+    pub fn remove_duplicate_ports(&mut self) -> Vec<HiddenServicePort> {
+        let mut kept: Vec<HiddenServicePort> = Vec::new();
+        let mut removed = Vec::new();
+        for port in self.ports.drain(..) {
+            if kept
+                .iter()
+                .any(|other| other.virtual_port == port.virtual_port)
+            {
+                removed.push(port);
+            } else {
+                kept.push(port);
+            }
+        }
+        self.ports = kept;
+        removed
+    }
+
+    /// Checks if Tor would accept this service and if all target hosts are known
+    pub fn validate(&self) -> Result<()> {
+        if self.dir.is_empty()
+            || !self
+                .dir
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            bail!("Invalid hidden service directory {:?}", self.dir);
+        }
+        if self.ports.is_empty() {
+            bail!("Hidden service {} does not have any ports", self.dir);
+        }
+        for port in &self.ports {
+            if port.virtual_port == 0 || port.target_port == 0 {
+                bail!("Hidden service {} uses port 0", self.dir);
+            }
+            if port.target_host.starts_with('<') {
+                bail!(
+                    "Hidden service {} points to unknown host {}",
+                    self.dir,
+                    port.target_host
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HiddenService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "HiddenServiceDir /var/lib/tor/{}", self.dir)?;
+        if let Some(version) = self.version {
+            writeln!(f, "HiddenServiceVersion {}", version)?;
+        }
+        for (option, value) in &self.options {
+            writeln!(f, "HiddenService{} {}", option, value)?;
+        }
+        for port in &self.ports {
+            writeln!(
+                f,
+                "HiddenServicePort {} {}:{}",
+                port.virtual_port, port.target_host, port.target_port
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders hidden services as torrc entries
+pub fn render_torrc(services: &[HiddenService]) -> String {
+    services
+        .iter()
+        .map(|service| service.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render_torrc, HiddenService, HiddenServicePort};
+
+    fn example_service() -> HiddenService {
+        let mut service = HiddenService::new("app-example".to_string());
+        service.ports.push(HiddenServicePort {
+            virtual_port: 80,
+            target_host: "10.21.21.20".to_string(),
+            target_port: 3000,
+        });
+        service
+    }
+
+    #[test]
+    fn renders_torrc() {
+        let mut other = HiddenService::new("app-example-rpc".to_string());
+        other.version = Some(3);
+        other
+            .options
+            .insert("MaxStreams".to_string(), "10".to_string());
+        other.ports.push(HiddenServicePort {
+            virtual_port: 8332,
+            target_host: "10.21.21.21".to_string(),
+            target_port: 8332,
+        });
+        assert_eq!(
+            render_torrc(&[example_service(), other]),
+            "HiddenServiceDir /var/lib/tor/app-example\n\
+             HiddenServicePort 80 10.21.21.20:3000\n\
+             \n\
+             HiddenServiceDir /var/lib/tor/app-example-rpc\n\
+             HiddenServiceVersion 3\n\
+             HiddenServiceMaxStreams 10\n\
+             HiddenServicePort 8332 10.21.21.21:8332\n"
+        );
+    }
+
+    #[test]
+    fn validates_services() {
+        assert!(example_service().validate().is_ok());
+        let mut service = example_service();
+        service.ports.push(HiddenServicePort {
+            target_port: 8080,
+            ..service.ports[0].clone()
+        });
+        assert_eq!(service.remove_duplicate_ports()[0].target_port, 8080);
+        assert_eq!(service, example_service());
+        let mut service = example_service();
+        service.ports[0].target_host = "<app-example-main-ip>".to_string();
+        assert!(service.validate().is_err());
+        assert!(HiddenService::new("app-example".to_string())
+            .validate()
+            .is_err());
+    }
+}
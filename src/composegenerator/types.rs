@@ -3,7 +3,9 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 
-use crate::composegenerator::output::types::ComposeSpecification;
+use crate::composegenerator::{
+    i2p::I2pTunnel, output::types::ComposeSpecification, proxy::ProxyRoute, tor::HiddenService,
+};
 
 // General types also relevant for the output
 // Can be re-used by schemas
@@ -16,6 +18,69 @@ pub enum Permissions {
     AlternativeDependency(Vec<String>),
 }
 
+/// Limits and defaults the node operator sets for all apps
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeConfig {
+    /// The maximum size of a single tmpfs mount in bytes
+    pub max_tmpfs_size: u64,
+    /// The logging policy for apps that do not set their own
+    pub logging: LoggingPolicy,
+    /// The maximum size of a log file apps can request in bytes
+    pub max_log_size: u64,
+    /// The maximum number of log files apps can request
+    pub max_log_files: u32,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            max_tmpfs_size: 256 * 1024 * 1024,
+            logging: LoggingPolicy::default(),
+            max_log_size: 50 * 1024 * 1024,
+            max_log_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoggingPolicy {
+    /// The Docker logging driver
+    pub driver: String,
+    /// The maximum size of a log file in bytes
+    pub max_size: u64,
+    /// The maximum number of log files to keep
+    pub max_files: u32,
+}
+
+impl Default for LoggingPolicy {
+    fn default() -> Self {
+        LoggingPolicy {
+            driver: "json-file".to_string(),
+            max_size: 10 * 1024 * 1024,
+            max_files: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct OutputWebEndpoint {
+    /// The endpoint id, unique within the app
+    pub id: String,
+    /// The label of the "Open" link on the dashboard
+    pub label: String,
+    /// The container serving this endpoint
+    pub container: String,
+    /// The port on the host
+    pub port: u16,
+    /// The port inside the container
+    pub internal_port: u16,
+    /// The path the link should lead to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(rename_all = "camelCase")]
@@ -69,13 +134,23 @@ pub struct OutputMetadata {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub release_notes: Option<BTreeMap<String, String>>,
     pub hidden_services: Vec<String>,
+    /// Hidden service directory -> .onion address, for the services whose address is already known
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub hidden_service_addresses: BTreeMap<String, String>,
+    /// Web interfaces in addition to the main port
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub web_endpoints: Vec<OutputWebEndpoint>,
+    /// The Tor instance the app's hidden services are assigned to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tor_instance: Option<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct ResultYml {
-    pub new_tor_entries: String,
-    pub new_i2p_entries: String,
+    pub hidden_services: Vec<HiddenService>,
+    pub i2p_tunnels: Vec<I2pTunnel>,
+    pub proxy_route: ProxyRoute,
     pub spec: ComposeSpecification,
     pub metadata: OutputMetadata,
 }
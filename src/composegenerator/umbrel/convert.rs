@@ -51,6 +51,7 @@ pub fn convert_metadata(metadata: Metadata) -> CitadelMetadata {
         } else {
             None
         },
+        tor_instance: None,
     }
 }
 
@@ -99,6 +100,8 @@ pub fn convert_compose(
             lnd: None,
             c_lightning: None,
             data: Some(HashMap::new()),
+            volumes: None,
+            tmpfs: None,
         });
         for volume in service_def.volumes {
             // Convert mounts using env vars to real mounts
@@ -242,8 +245,12 @@ pub fn convert_compose(
             } else {
                 Some(false)
             },
+            networks: None,
+            external_networks: None,
             hidden_services: None,
             cap_add: service_def.cap_add,
+            web_endpoints: None,
+            logging: None,
         };
         result_services.insert(service_name, new_service);
     }
@@ -1,8 +1,8 @@
 use super::types::Schema as AppYmlV3;
-use crate::composegenerator::types::ResultYml;
+use crate::composegenerator::types::{NodeConfig, ResultYml};
 use crate::composegenerator::v4::types::PortMapElement;
 use crate::composegenerator::v4::{
-    convert::convert_config as convert_config_v4, types as types_v4,
+    convert::convert_config_with as convert_config_v4, types as types_v4,
 };
 use crate::utils::flatten;
 use anyhow::Result;
@@ -31,6 +31,7 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
         implements: None,
         version_control: None,
         release_notes: None,
+        tor_instance: None,
     };
     let mut services = HashMap::<String, types_v4::Container>::with_capacity(app.containers.len());
     let deps = flatten(app.metadata.dependencies.unwrap_or_default());
@@ -80,6 +81,8 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
             lnd: None,
             c_lightning: None,
             data: None,
+            volumes: None,
+            tmpfs: None,
         };
         let requires = container.requires.unwrap_or_default();
         let old_mounts = container.mounts.unwrap_or_default();
@@ -140,6 +143,8 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
                 required_ports,
                 mounts: Some(mounts),
                 assign_fixed_ip,
+                networks: None,
+                external_networks: None,
                 hidden_services: container.hidden_service_ports.map(|value| match value {
                     super::types::HiddenServices::PortMap(map) => {
                         types_v4::HiddenServices::PortMap(map)
@@ -169,6 +174,8 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
                     }
                 }),
                 cap_add: None,
+                web_endpoints: None,
+                logging: None,
             },
         );
     }
@@ -185,6 +192,25 @@ pub fn convert_config(
     port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
     installed_services: &Vec<String>,
     ip_addresses: &Option<HashMap<String, String>>,
+) -> Result<ResultYml> {
+    convert_config_with(
+        app_name,
+        app,
+        port_map,
+        installed_services,
+        ip_addresses,
+        &NodeConfig::default(),
+    )
+}
+
+/// Like convert_config, but with the limits of the node the app runs on
+pub fn convert_config_with(
+    app_name: &str,
+    app: AppYmlV3,
+    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: &Vec<String>,
+    ip_addresses: &Option<HashMap<String, String>>,
+    node_config: &NodeConfig,
 ) -> Result<ResultYml> {
     convert_config_v4(
         app_name,
@@ -192,5 +218,6 @@ pub fn convert_config(
         port_map,
         &Some(installed_services.clone()),
         ip_addresses,
+        node_config,
     )
 }
@@ -6,19 +6,26 @@ use super::{
 use crate::{
     bmap,
     composegenerator::{
-        compose::types::StringOrIntOrBool,
+        compose::types::{
+            Network,
+            ServiceBlkioConfigItemItemItemItemItemItemItemCredentialSpecItemItemItemItemItemItemLogging as Logging,
+            StringOrIntOrBool, Volume,
+        },
+        i2p::I2pTunnel,
         output::types::{ComposeSpecification, NetworkEntry, Service},
+        proxy::ProxyRoute,
+        tor::{HiddenService, HiddenServicePort},
         types::Permissions,
     },
 };
 use crate::{
-    composegenerator::types::OutputMetadata,
-    utils::{find_env_vars, flatten},
+    composegenerator::types::{NodeConfig, OutputMetadata, OutputWebEndpoint},
+    utils::{find_env_vars, flatten, parse_size},
 };
 use std::collections::{BTreeMap, HashMap};
 
 use crate::composegenerator::types::ResultYml;
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
 fn get_main_port(
     containers: &HashMap<String, types::Container>,
@@ -29,12 +36,12 @@ fn get_main_port(
     for service_name in containers.keys() {
         let original_definition = containers.get(service_name).unwrap();
         if service_name != main_container && original_definition.port.is_some() {
-            bail!("port: is not supported for containers other than the main container");
+            bail!("port: is not supported for containers other than the main container, use web_endpoints: instead");
         }
 
         if let Some(internal_port) = original_definition.port {
             if service_name != main_container {
-                bail!("port: is not supported for containers other than the main container");
+                bail!("port: is not supported for containers other than the main container, use web_endpoints: instead");
             }
             let public_port: Option<&PortMapElement>;
             let fake_port = PortMapElement {
@@ -92,12 +99,12 @@ fn configure_ports(
     for (service_name, service) in services {
         let original_definition = containers.get(service_name).unwrap();
         if service_name != main_container && original_definition.port.is_some() {
-            bail!("port: is not supported for containers other than the main container",);
+            bail!("port: is not supported for containers other than the main container, use web_endpoints: instead",);
         }
 
         if let Some(internal_port) = original_definition.port {
             if service_name != main_container {
-                bail!("port: is not supported for containers other than the main container",);
+                bail!("port: is not supported for containers other than the main container, use web_endpoints: instead",);
             }
             let public_port: Option<&PortMapElement>;
             let fake_port = PortMapElement {
@@ -155,6 +162,51 @@ fn configure_ports(
     Ok(())
 }
 
+fn configure_web_endpoints(
+    containers: &HashMap<String, types::Container>,
+    output: &mut ComposeSpecification,
+    port_map: &Option<HashMap<String, Vec<PortMapElement>>>,
+) -> Result<Vec<OutputWebEndpoint>> {
+    let mut result = Vec::new();
+    let services = output.services.as_mut().unwrap();
+    for (service_name, service) in services {
+        let original_definition = containers.get(service_name).unwrap();
+        let Some(web_endpoints) = &original_definition.web_endpoints else {
+            continue;
+        };
+        for (endpoint_id, endpoint) in web_endpoints {
+            let public_port = if let Some(real_port_map) = port_map {
+                let Some(ports) = real_port_map.get(service_name) else {
+                    bail!(
+                        "Container {} not found or invalid in port map",
+                        service_name
+                    );
+                };
+                let Some(port_map_elem) = get_host_port(ports, endpoint.port) else {
+                    bail!("Web endpoint {} not found in port map", endpoint_id);
+                };
+                port_map_elem.public_port
+            } else {
+                endpoint.port
+            };
+            let port_string = format!("{}:{}", public_port, endpoint.port);
+            if !service.ports.contains(&port_string) {
+                service.ports.push(port_string);
+            }
+            result.push(OutputWebEndpoint {
+                id: endpoint_id.to_owned(),
+                label: endpoint.label.to_owned(),
+                container: service_name.to_owned(),
+                port: public_port,
+                internal_port: endpoint.port,
+                path: endpoint.path.to_owned(),
+            });
+        }
+    }
+
+    Ok(result)
+}
+
 fn define_ip_addresses(
     app_name: &str,
     containers: &HashMap<String, types::Container>,
@@ -256,12 +308,93 @@ fn validate_service(
     Ok(())
 }
 
+/// Adds the networks containers join in addition to the app network
+fn convert_networks(
+    app_name: &str,
+    containers: &HashMap<String, types::Container>,
+    permissions: &[String],
+    output: &mut ComposeSpecification,
+) -> Result<()> {
+    let services = output.services.as_mut().unwrap();
+    let mut networks = BTreeMap::new();
+    for (service_name, service) in services {
+        let original_definition = containers.get(service_name).unwrap();
+        let custom_networks = original_definition
+            .networks
+            .iter()
+            .flatten()
+            .map(|name| (name, false));
+        let external_networks = original_definition
+            .external_networks
+            .iter()
+            .flatten()
+            .map(|name| (name, true));
+        for (network_name, external) in custom_networks.chain(external_networks) {
+            if network_name.is_empty()
+                || network_name == "default"
+                || !network_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("Network name {} is invalid", network_name);
+            }
+            if service.network_mode.is_some() {
+                bail!(
+                    "Container {} sets network_mode, so it can not join network {}",
+                    service_name,
+                    network_name
+                );
+            }
+            let network = if external {
+                if !permissions.contains(&"network".to_string()) {
+                    bail!(
+                        "Container {} joins external network {}, but the app does not request the network permission",
+                        service_name,
+                        network_name
+                    );
+                }
+                Network {
+                    external: Some(serde_json::Value::Bool(true)),
+                    ..Default::default()
+                }
+            } else {
+                // Prefix the real network name with the app id so apps can not join each other's networks
+                Network {
+                    name: Some(format!("{}_{}", app_name, network_name)),
+                    ..Default::default()
+                }
+            };
+            if networks
+                .get(network_name)
+                .is_some_and(|other| *other != network)
+            {
+                bail!(
+                    "Network {} is used as a custom and as an external network",
+                    network_name
+                );
+            }
+            networks.insert(network_name.clone(), network);
+            let service_networks = service.networks.get_or_insert_with(BTreeMap::new);
+            // Containers that list networks are only attached to the default network if it is listed too
+            service_networks.entry("default".to_string()).or_default();
+            service_networks.insert(network_name.clone(), NetworkEntry::default());
+        }
+    }
+    if !networks.is_empty() {
+        output.networks = Some(networks);
+    }
+    Ok(())
+}
+
 fn convert_volumes(
+    app_name: &str,
     containers: &HashMap<String, types::Container>,
     permissions: &[String],
+    node_config: &NodeConfig,
     output: &mut ComposeSpecification,
 ) -> Result<()> {
     let services = output.services.as_mut().unwrap();
+    let mut named_volumes = BTreeMap::new();
     for (service_name, service) in services {
         let original_definition = containers.get(service_name).unwrap();
         if let Some(mounts) = &original_definition.mounts {
@@ -310,156 +443,325 @@ fn convert_volumes(
                     .volumes
                     .push(format!("${{C_LIGHTNING_DATA_DIR}}:{}", c_lightning_mount));
             }
+
+            if let Some(volumes) = &mounts.volumes {
+                for (volume_name, container_path) in volumes {
+                    if volume_name.is_empty()
+                        || !volume_name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        bail!("Volume name {} is invalid", volume_name);
+                    }
+                    if !container_path.starts_with('/') || container_path.contains(':') {
+                        bail!(
+                            "Mount path {} of volume {} is invalid",
+                            container_path,
+                            volume_name
+                        );
+                    }
+                    service
+                        .volumes
+                        .push(format!("{}:{}", volume_name, container_path));
+                    // Prefix the real volume name with the app id so apps can not access each other's volumes
+                    named_volumes.insert(
+                        volume_name.clone(),
+                        Volume {
+                            name: Some(format!("{}_{}", app_name, volume_name)),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+
+            if let Some(tmpfs_mounts) = &mounts.tmpfs {
+                let tmpfs_mounts: BTreeMap<&String, &types::TmpfsMount> =
+                    tmpfs_mounts.iter().collect();
+                for (container_path, tmpfs) in tmpfs_mounts {
+                    if !container_path.starts_with('/') || container_path.contains(':') {
+                        bail!("tmpfs mount path {} is invalid", container_path);
+                    }
+                    let size = parse_size(&tmpfs.size)?;
+                    if size > node_config.max_tmpfs_size {
+                        bail!(
+                            "tmpfs mount {} is larger than the maximum of {} bytes",
+                            container_path,
+                            node_config.max_tmpfs_size
+                        );
+                    }
+                    let mut options = format!("size={}", size);
+                    if let Some(mode) = &tmpfs.mode {
+                        if u32::from_str_radix(mode, 8).map_or(true, |mode| mode > 0o7777) {
+                            bail!("tmpfs mode {} is not a valid octal mode", mode);
+                        }
+                        options += &format!(",mode={}", mode);
+                    }
+                    service
+                        .tmpfs
+                        .push(format!("{}:{}", container_path, options));
+                }
+            }
         }
     }
+    if !named_volumes.is_empty() {
+        output.volumes = Some(named_volumes);
+    }
 
     Ok(())
 }
 
+fn get_logging(
+    service_name: &str,
+    logging: &Option<types::Logging>,
+    node_config: &NodeConfig,
+) -> Result<Logging> {
+    let policy = &node_config.logging;
+    // Other drivers (like journald) do not rotate logs themselves
+    if policy.driver != "json-file" && policy.driver != "local" {
+        return Ok(Logging {
+            driver: Some(policy.driver.clone()),
+            options: None,
+        });
+    }
+    let mut max_size = policy.max_size;
+    let mut max_files = policy.max_files;
+    if let Some(logging) = logging {
+        if let Some(size) = &logging.max_size {
+            max_size = parse_size(size)
+                .with_context(|| format!("Invalid log max_size of {}", service_name))?;
+        }
+        max_files = logging.max_file.unwrap_or(max_files);
+    }
+    if max_size > node_config.max_log_size {
+        tracing::warn!(
+            "Limiting the log size of {} to {} bytes",
+            service_name,
+            node_config.max_log_size
+        );
+        max_size = node_config.max_log_size;
+    }
+    if max_files > node_config.max_log_files {
+        tracing::warn!(
+            "Limiting the log files of {} to {}",
+            service_name,
+            node_config.max_log_files
+        );
+        max_files = node_config.max_log_files;
+    }
+    Ok(Logging {
+        driver: Some(policy.driver.clone()),
+        options: Some(BTreeMap::from([
+            ("max-size".to_string(), max_size.to_string().into()),
+            ("max-file".to_string(), max_files.max(1).to_string().into()),
+        ])),
+    })
+}
+
+/// The address other containers (like Tor) can reach a container at
+fn get_container_host(
+    app_name: &str,
+    service_name: &str,
+    container: &types::Container,
+    ip_addresses: &HashMap<String, String>,
+) -> String {
+    // Containers on the host network are reachable through the gateway of the app network
+    if container.network_mode == Some("host".to_string()) {
+        return ip_addresses
+            .get("GATEWAY_IP")
+            .cloned()
+            .unwrap_or_else(|| "<gateway-ip>".to_string());
+    }
+    ip_addresses
+        .get(&format!(
+            "APP_{}_{}_IP",
+            app_name.to_uppercase().replace('-', "_"),
+            service_name.to_uppercase().replace('-', "_")
+        ))
+        .cloned()
+        .unwrap_or_else(|| {
+            format!(
+                "<app-{}-{}-ip>",
+                app_name.to_lowercase().replace('_', "-"),
+                service_name.to_lowercase().replace('_', "-")
+            )
+        })
+}
+
 fn get_hidden_services(
     app_name: &str,
-    containers: HashMap<String, types::Container>,
+    containers: &HashMap<String, types::Container>,
     main_container: &str,
     main_port: u16,
     ip_addresses: &HashMap<String, String>,
-) -> (String, Vec<String>) {
-    let mut result = String::new();
-    let mut service_list = Vec::new();
-    for service_name in containers.keys() {
-        let original_definition = containers.get(service_name).unwrap();
-        if original_definition.network_mode == Some("host".to_string()) {
-            continue;
-        }
-        let app_name_uppercase = app_name.to_uppercase().replace('-', "_");
-        let service_name_uppercase = service_name.to_uppercase().replace('-', "_");
-        let app_name_slug = app_name.to_lowercase().replace('_', "-");
+) -> Vec<HiddenService> {
+    let mut result = Vec::new();
+    let app_name_slug = app_name.to_lowercase().replace('_', "-");
+    // Sort the containers and ports so the output is the same on every run
+    let containers: BTreeMap<&String, &types::Container> = containers.iter().collect();
+    for (service_name, original_definition) in containers {
         let service_name_slug = service_name.to_lowercase().replace('_', "-");
-        if service_name == main_container {
-            let hidden_service_string = format!(
-                "HiddenServiceDir /var/lib/tor/app-{}\nHiddenServicePort 80 {}:{}\n",
-                app_name_slug,
-                ip_addresses
-                    .get(&format!(
-                        "APP_{}_{}_IP",
-                        app_name_uppercase, service_name_uppercase
-                    ))
-                    .unwrap_or(&format!("<app-{}-{}-ip>", app_name_slug, service_name_slug)),
-                main_port
-            );
-            service_list.push("app-".to_owned() + &app_name_slug);
-            result += hidden_service_string.as_str();
+        let target_host =
+            get_container_host(app_name, service_name, original_definition, ip_addresses);
+        let to_ports = |ports: &HashMap<u16, u16>| {
+            let ports: BTreeMap<&u16, &u16> = ports.iter().collect();
+            ports
+                .into_iter()
+                .map(|(virtual_port, target_port)| HiddenServicePort {
+                    virtual_port: *virtual_port,
+                    target_host: target_host.clone(),
+                    target_port: *target_port,
+                })
+                .collect::<Vec<HiddenServicePort>>()
+        };
+        let mut main_hidden_service = None;
+        if *service_name == main_container {
+            let mut hidden_service = HiddenService::new(format!("app-{}", app_name_slug));
+            hidden_service.ports.push(HiddenServicePort {
+                virtual_port: 80,
+                target_host: target_host.clone(),
+                target_port: main_port,
+            });
+            main_hidden_service = Some(hidden_service);
         }
         if let Some(hidden_services) = &original_definition.hidden_services {
             match hidden_services {
                 types::HiddenServices::PortMap(simple_map) => {
-                    if service_name != main_container {
-                        let hidden_service_string = format!(
-                            "HiddenServiceDir /var/lib/tor/app-{}-{}\n",
-                            app_name_slug, service_name_slug
-                        );
-                        service_list.push(format!("app-{}-{}", app_name_slug, service_name_slug));
-                        result += hidden_service_string.as_str();
-                    }
-                    for port in simple_map {
-                        let port_string = format!(
-                            "HiddenServicePort {} {}:{}\n",
-                            port.0,
-                            ip_addresses
-                                .get(&format!(
-                                    "APP_{}_{}_IP",
-                                    app_name_uppercase, service_name_uppercase
-                                ))
-                                .unwrap_or(&format!(
-                                    "<app-{}-{}-ip>",
-                                    app_name_slug, service_name_slug
-                                )),
-                            port.1
-                        );
-                        result += port_string.as_str();
-                    }
+                    // The main container's ports are added to the app's main hidden service
+                    let mut hidden_service = main_hidden_service.take().unwrap_or_else(|| {
+                        HiddenService::new(format!("app-{}-{}", app_name_slug, service_name_slug))
+                    });
+                    hidden_service.ports.append(&mut to_ports(simple_map));
+                    result.push(hidden_service);
                 }
-                types::HiddenServices::LayeredMap(layered_map) => {
-                    for element in layered_map {
-                        let hidden_service_string = format!(
-                            "HiddenServiceDir /var/lib/tor/app-{}-{}\n",
-                            app_name_slug,
-                            element.0.to_lowercase().replace('_', "-")
-                        );
-                        service_list.push(format!(
+                named_services => {
+                    result.extend(main_hidden_service.take());
+                    for (name, ports, authorized_clients_only) in named_services.named() {
+                        let mut hidden_service = HiddenService::new(format!(
                             "app-{}-{}",
                             app_name_slug,
-                            element.0.to_lowercase().replace('_', "-")
+                            name.to_lowercase().replace('_', "-")
                         ));
-                        result += hidden_service_string.as_str();
-                        for port in element.1 {
-                            let port_string = format!(
-                                "HiddenServicePort {} {}:{}\n",
-                                port.0,
-                                ip_addresses
-                                    .get(&format!(
-                                        "APP_{}_{}_IP",
-                                        app_name_uppercase, service_name_uppercase
-                                    ))
-                                    .unwrap_or(&format!(
-                                        "<app-{}-{}-ip>",
-                                        app_name_slug, service_name_slug
-                                    )),
-                                port.1
-                            );
-                            result += port_string.as_str();
-                        }
+                        hidden_service.ports = to_ports(ports);
+                        hidden_service.client_auth = authorized_clients_only;
+                        result.push(hidden_service);
                     }
                 }
             }
         }
+        result.extend(main_hidden_service);
     }
 
-    (result, service_list)
+    result
+}
+
+/// Checks that Tor can reach a main container on the host network, returns a warning if it can not
+///
+/// Port mappings do not apply to the host network, so the container can only be reached on its own port,
+/// and not at all if the port allocator gave that port to another app.
+fn check_host_network_hidden_services(
+    app_name: &str,
+    gateway: &str,
+    main_port: u16,
+    main_port_host: Option<u16>,
+    hidden_services: &[HiddenService],
+) -> Option<String> {
+    if gateway.starts_with('<') {
+        return Some(format!(
+            "The main container of {} uses the host network, but the gateway IP is unknown, so its hidden service is not reachable",
+            app_name
+        ));
+    }
+    let main_port_moved = main_port_host.is_some_and(|port| port != main_port);
+    let reachable = hidden_services
+        .iter()
+        .flat_map(|hidden_service| &hidden_service.ports)
+        .any(|port| {
+            port.target_host == gateway && !(main_port_moved && port.target_port == main_port)
+        });
+    if reachable {
+        None
+    } else if main_port_moved {
+        Some(format!(
+            "The main container of {} uses the host network, but port {} is used by another app, so its hidden service is not reachable",
+            app_name, main_port
+        ))
+    } else {
+        Some(format!(
+            "The main container of {} uses the host network, but none of its hidden services point to it",
+            app_name
+        ))
+    }
 }
 
 fn get_i2p_tunnels(
     app_name: &str,
-    containers: HashMap<String, types::Container>,
+    containers: &HashMap<String, types::Container>,
     main_container: &str,
     main_port: u16,
     ip_addresses: &HashMap<String, String>,
-) -> String {
-    let mut result = String::new();
-    for service_name in containers.keys() {
-        let original_definition = containers.get(service_name).unwrap();
-        if original_definition.network_mode == Some("host".to_string()) {
-            continue;
-        }
-        let app_name_uppercase = app_name.to_uppercase().replace('-', "_");
-        let service_name_uppercase = service_name.to_uppercase().replace('-', "_");
-        let app_name_slug = app_name.to_lowercase().replace('_', "-");
+) -> Vec<I2pTunnel> {
+    let mut result = Vec::new();
+    let app_name_slug = app_name.to_lowercase().replace('_', "-");
+    // Sort the containers and ports so the output is the same on every run
+    let containers: BTreeMap<&String, &types::Container> = containers.iter().collect();
+    for (service_name, original_definition) in containers {
         let service_name_slug = service_name.to_lowercase().replace('_', "-");
-        if service_name == main_container {
-            let hidden_service_string = format!(
-                "[app-{}-{}]\nhost = {}\nport = {}\nkeys = app-{}-{}.dat\n",
-                app_name_slug,
-                service_name_slug,
-                ip_addresses
-                    .get(&format!(
-                        "APP_{}_{}_IP",
-                        app_name_uppercase, service_name_uppercase
-                    ))
-                    .unwrap_or(&format!("<app-{}-{}-ip>", app_name_slug, service_name_slug)),
-                main_port,
-                app_name_slug,
-                service_name_slug
-            );
-            result += hidden_service_string.as_str();
+        let host = get_container_host(app_name, service_name, original_definition, ip_addresses);
+        // Like Tor hidden services, all ports of a destination share the same keys
+        let to_tunnels = |name: &str, ports: &HashMap<u16, u16>| {
+            let ports: BTreeMap<&u16, &u16> = ports.iter().collect();
+            ports
+                .into_iter()
+                .map(|(inport, port)| I2pTunnel {
+                    name: format!("{}-{}", name, inport),
+                    host: host.clone(),
+                    port: *port,
+                    inport: Some(*inport),
+                    keys: format!("{}.dat", name),
+                })
+                .collect::<Vec<I2pTunnel>>()
+        };
+        let container_tunnel_name = format!("app-{}-{}", app_name_slug, service_name_slug);
+        if *service_name == main_container {
+            result.push(I2pTunnel {
+                name: container_tunnel_name.clone(),
+                host: host.clone(),
+                port: main_port,
+                inport: None,
+                keys: format!("{}.dat", container_tunnel_name),
+            });
         }
-        if original_definition.hidden_services.is_some() {
-            tracing::info!("Multi-port hidden services are not yet supported for I2P on Citadel!");
+        if let Some(hidden_services) = &original_definition.hidden_services {
+            match hidden_services {
+                types::HiddenServices::PortMap(simple_map) => {
+                    result.append(&mut to_tunnels(&container_tunnel_name, simple_map));
+                }
+                named_services => {
+                    for (name, ports, authorized_clients_only) in named_services.named() {
+                        // I2P has no client authorization, so these services are only available over Tor
+                        if authorized_clients_only {
+                            continue;
+                        }
+                        let name = format!(
+                            "app-{}-{}",
+                            app_name_slug,
+                            name.to_lowercase().replace('_', "-")
+                        );
+                        result.append(&mut to_tunnels(&name, ports));
+                    }
+                }
+            }
         }
     }
 
     result
 }
 
-fn get_missing_dependencies(required: &[Permissions], installed: &[String]) -> Vec<Permissions> {
+/// The dependencies that are not satisfied by the installed services
+pub fn get_missing_dependencies(
+    required: &[Permissions],
+    installed: &[String],
+) -> Vec<Permissions> {
     let mut missing = Vec::<Permissions>::new();
     for requirement in required {
         match requirement {
@@ -484,9 +786,29 @@ pub fn convert_config(
     port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
     installed_services: &Option<Vec<String>>,
     ip_addresses: &Option<HashMap<String, String>>,
+) -> Result<ResultYml> {
+    convert_config_with(
+        app_name,
+        app,
+        port_map,
+        installed_services,
+        ip_addresses,
+        &NodeConfig::default(),
+    )
+}
+
+/// Like convert_config, but with the limits of the node the app runs on
+pub fn convert_config_with(
+    app_name: &str,
+    app: types::AppYml,
+    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: &Option<Vec<String>>,
+    ip_addresses: &Option<HashMap<String, String>>,
+    node_config: &NodeConfig,
 ) -> Result<ResultYml> {
     let mut spec: ComposeSpecification = ComposeSpecification {
         services: Some(BTreeMap::new()),
+        ..Default::default()
     };
     let spec_services = spec.services.get_or_insert(BTreeMap::new());
     let mut permissions = flatten(app.metadata.permissions.clone());
@@ -499,10 +821,10 @@ pub fn convert_config(
             if let Some(ref implements) = app.metadata.implements {
                 if let Some(implement_port_map_entry) = port_map.get(implements) {
                     for (key, value) in implement_port_map_entry {
-                        if entry.get(key).is_none() {
-                            entry.insert(key.to_owned(), value.clone());
+                        if let Some(existing) = entry.get_mut(key) {
+                            existing.extend(value.clone());
                         } else {
-                            entry.get_mut(key).unwrap().extend(value.clone());
+                            entry.insert(key.to_owned(), value.clone());
                         }
                     }
                 }
@@ -537,6 +859,7 @@ pub fn convert_config(
             depends_on: service.depends_on.clone(),
             extra_hosts: service.extra_hosts.clone(),
             working_dir: service.working_dir.clone(),
+            logging: Some(get_logging(service_name, &service.logging, node_config)?),
             ports: Vec::new(),
             volumes: Vec::new(),
             ..Default::default()
@@ -553,9 +876,19 @@ pub fn convert_config(
     // We can now finalize the process by parsing some of the remaining values
     configure_ports(&app.services, &main_service, &mut spec, &app_port_map)?;
 
+    let web_endpoints = configure_web_endpoints(&app.services, &mut spec, &app_port_map)?;
+
     define_ip_addresses(app_name, &app.services, &main_service, &mut spec)?;
 
-    convert_volumes(&app.services, &permissions, &mut spec)?;
+    convert_networks(app_name, &app.services, &permissions, &mut spec)?;
+
+    convert_volumes(
+        app_name,
+        &app.services,
+        &permissions,
+        node_config,
+        &mut spec,
+    )?;
 
     let mut main_port_host: Option<u16> = None;
     if let Some(converted_map) = app_port_map {
@@ -576,13 +909,38 @@ pub fn convert_config(
         ips = ip_addresses.clone();
     }
 
-    let (new_tor_entries, hidden_services) = get_hidden_services(
-        app_name,
-        app.services.clone(),
-        &main_service,
-        main_port,
-        &ips,
-    );
+    let hidden_services =
+        get_hidden_services(app_name, &app.services, &main_service, main_port, &ips);
+    let main_uses_host_network =
+        app.services.get(&main_service).unwrap().network_mode == Some("host".to_string());
+    if main_uses_host_network && ip_addresses.is_some() {
+        let gateway = get_container_host(
+            app_name,
+            &main_service,
+            app.services.get(&main_service).unwrap(),
+            &ips,
+        );
+        if let Some(warning) = check_host_network_hidden_services(
+            app_name,
+            &gateway,
+            main_port,
+            main_port_host,
+            &hidden_services,
+        ) {
+            tracing::warn!("{}", warning);
+        }
+    }
+    let proxy_route = ProxyRoute {
+        app: app_name.to_string(),
+        host: get_container_host(
+            app_name,
+            &main_service,
+            app.services.get(&main_service).unwrap(),
+            &ips,
+        ),
+        port: main_port,
+        path: app.metadata.path.clone(),
+    };
     let mut metadata = OutputMetadata {
         id: app_name.to_string(),
         name: app.metadata.name,
@@ -606,7 +964,13 @@ pub fn convert_config(
         port: main_port_host.unwrap_or(main_port),
         internal_port: main_port,
         release_notes: app.metadata.release_notes,
-        hidden_services,
+        hidden_services: hidden_services
+            .iter()
+            .map(|hidden_service| hidden_service.dir.clone())
+            .collect(),
+        hidden_service_addresses: BTreeMap::new(),
+        web_endpoints,
+        tor_instance: app.metadata.tor_instance,
     };
     if !missing_deps.is_empty() {
         metadata.missing_dependencies = Some(missing_deps);
@@ -614,8 +978,9 @@ pub fn convert_config(
 
     let result = ResultYml {
         spec,
-        new_tor_entries,
-        new_i2p_entries: get_i2p_tunnels(app_name, app.services, &main_service, main_port, &ips),
+        hidden_services,
+        i2p_tunnels: get_i2p_tunnels(app_name, &app.services, &main_service, main_port, &ips),
+        proxy_route,
         metadata,
     };
 
@@ -625,18 +990,44 @@ pub fn convert_config(
 
 #[cfg(test)]
 mod test {
-    use super::convert_config;
+    use super::{
+        check_host_network_hidden_services, convert_config, convert_config_with,
+        get_hidden_services, get_i2p_tunnels,
+    };
     use crate::{
         bmap,
         composegenerator::{
+            compose::types::{
+                Network,
+                ServiceBlkioConfigItemItemItemItemItemItemItemCredentialSpecItemItemItemItemItemItemLogging as Logging,
+                Volume,
+            },
+            i2p::I2pTunnel,
             output::types::{ComposeSpecification, NetworkEntry, Service},
-            types::{OutputMetadata, Permissions, ResultYml},
-            v4::types::{AppYml, Container, InputMetadata},
+            proxy::ProxyRoute,
+            tor::{HiddenService, HiddenServicePort},
+            types::OutputWebEndpoint,
+            types::{NodeConfig, OutputMetadata, Permissions, ResultYml},
+            v4::types::{
+                AppYml, Container, HiddenServices, InputMetadata, Logging as InputLogging, Mounts,
+                PortMapElement, TmpfsMount, WebEndpoint,
+            },
         },
         map,
     };
 
     use pretty_assertions::assert_eq;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn default_logging() -> Logging {
+        Logging {
+            driver: Some("json-file".to_string()),
+            options: Some(BTreeMap::from([
+                ("max-size".to_string(), "10485760".into()),
+                ("max-file".to_string(), "3".into()),
+            ])),
+        }
+    }
 
     #[test]
     fn test_simple_app() {
@@ -688,6 +1079,7 @@ mod test {
                                 ipv4_address: Some("$APP_EXAMPLE_APP_MAIN_IP".to_string())
                             }
                         }),
+                        logging: Some(default_logging()),
                         ..Default::default()
                     },
                     "database" => Service {
@@ -698,10 +1090,11 @@ mod test {
                                 ipv4_address: Some("$APP_EXAMPLE_APP_DATABASE_IP".to_string())
                             }
                         }),
+                        logging: Some(default_logging()),
                         ..Default::default()
                     }
-                })
-
+                }),
+                ..Default::default()
             },
             metadata: OutputMetadata {
                 id: "example-app".to_string(),
@@ -725,9 +1118,489 @@ mod test {
                 hidden_services: vec!["app-example-app".to_string()],
                 ..Default::default()
             },
-            new_tor_entries: "HiddenServiceDir /var/lib/tor/app-example-app\nHiddenServicePort 80 <app-example-app-main-ip>:3000\n".to_string(),
-            new_i2p_entries: "[app-example-app-main]\nhost = <app-example-app-main-ip>\nport = 3000\nkeys = app-example-app-main.dat\n".to_string(),
+            hidden_services: vec![HiddenService {
+                dir: "app-example-app".to_string(),
+                ports: vec![HiddenServicePort {
+                    virtual_port: 80,
+                    target_host: "<app-example-app-main-ip>".to_string(),
+                    target_port: 3000,
+                }],
+                ..Default::default()
+            }],
+            i2p_tunnels: vec![I2pTunnel {
+                name: "app-example-app-main".to_string(),
+                host: "<app-example-app-main-ip>".to_string(),
+                port: 3000,
+                inport: None,
+                keys: "app-example-app-main.dat".to_string(),
+            }],
+            proxy_route: ProxyRoute {
+                app: "example-app".to_string(),
+                host: "<app-example-app-main-ip>".to_string(),
+                port: 3000,
+                path: None,
+            },
         };
         assert_eq!(expected_result, result.unwrap());
     }
+
+    #[test]
+    fn test_web_endpoints() {
+        let example_app = AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    ..Default::default()
+                },
+                "admin" => Container {
+                    image: "ghcr.io/runcitadel/example-admin:main".to_string(),
+                    web_endpoints: Some(bmap! {
+                        "admin" => WebEndpoint {
+                            port: 8080,
+                            label: "Admin UI".to_string(),
+                            path: Some("/login".to_string()),
+                            ..Default::default()
+                        }
+                    }),
+                    ..Default::default()
+                }
+            },
+        };
+        let port_map = map! {
+            "example-app" => map! {
+                "main" => vec![PortMapElement {
+                    dynamic: false,
+                    internal_port: 3000,
+                    public_port: 3000,
+                }],
+                "admin" => vec![PortMapElement {
+                    dynamic: false,
+                    internal_port: 8080,
+                    public_port: 8081,
+                }]
+            }
+        };
+        let result =
+            convert_config("example-app", example_app, &Some(port_map), &None, &None).unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(services.get("admin").unwrap().ports, vec!["8081:8080"]);
+        assert_eq!(services.get("main").unwrap().ports, vec!["3000:3000"]);
+        assert_eq!(
+            result.metadata.web_endpoints,
+            vec![OutputWebEndpoint {
+                id: "admin".to_string(),
+                label: "Admin UI".to_string(),
+                container: "admin".to_string(),
+                port: 8081,
+                internal_port: 8080,
+                path: Some("/login".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_multi_port_i2p_tunnels() {
+        let containers = map! {
+            "main".to_string() => Container {
+                image: "ghcr.io/runcitadel/example:main".to_string(),
+                hidden_services: Some(HiddenServices::PortMap(HashMap::from([(8333, 18333)]))),
+                ..Default::default()
+            },
+            "rpc".to_string() => Container {
+                image: "ghcr.io/runcitadel/example:main".to_string(),
+                hidden_services: Some(HiddenServices::LayeredMap(map! {
+                    "rpc" => HashMap::from([(8332, 8332)])
+                })),
+                ..Default::default()
+            }
+        };
+        let ips = map! {
+            "APP_EXAMPLE_MAIN_IP".to_string() => "10.21.21.20".to_string(),
+            "APP_EXAMPLE_RPC_IP".to_string() => "10.21.21.21".to_string()
+        };
+        let tunnels = get_i2p_tunnels("example", &containers, "main", 3000, &ips);
+        let tunnels: Vec<(&str, u16, Option<u16>, &str)> = tunnels
+            .iter()
+            .map(|tunnel| {
+                (
+                    tunnel.name.as_str(),
+                    tunnel.port,
+                    tunnel.inport,
+                    tunnel.keys.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tunnels,
+            vec![
+                ("app-example-main", 3000, None, "app-example-main.dat"),
+                (
+                    "app-example-main-8333",
+                    18333,
+                    Some(8333),
+                    "app-example-main.dat"
+                ),
+                (
+                    "app-example-rpc-8332",
+                    8332,
+                    Some(8332),
+                    "app-example-rpc.dat"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_network_hidden_services() {
+        let containers = map! {
+            "main" => Container {
+                image: "ghcr.io/runcitadel/example:main".to_string(),
+                network_mode: Some("host".to_string()),
+                hidden_services: Some(HiddenServices::PortMap(HashMap::from([(9735, 9735)]))),
+                ..Default::default()
+            }
+        };
+        let ips = map! {
+            "GATEWAY_IP" => "10.21.21.1".to_string()
+        };
+        let hidden_services = get_hidden_services("example", &containers, "main", 3000, &ips);
+        assert_eq!(
+            hidden_services,
+            vec![HiddenService {
+                dir: "app-example".to_string(),
+                ports: vec![
+                    HiddenServicePort {
+                        virtual_port: 80,
+                        target_host: "10.21.21.1".to_string(),
+                        target_port: 3000,
+                    },
+                    HiddenServicePort {
+                        virtual_port: 9735,
+                        target_host: "10.21.21.1".to_string(),
+                        target_port: 9735,
+                    }
+                ],
+                ..Default::default()
+            }]
+        );
+        let tunnels = get_i2p_tunnels("example", &containers, "main", 3000, &ips);
+        assert!(tunnels.iter().all(|tunnel| tunnel.host == "10.21.21.1"));
+
+        let check = |main_port_host, hidden_services: &[HiddenService]| {
+            check_host_network_hidden_services(
+                "example",
+                "10.21.21.1",
+                3000,
+                main_port_host,
+                hidden_services,
+            )
+        };
+        assert_eq!(check(Some(3000), &hidden_services), None);
+        assert_eq!(check(None, &hidden_services), None);
+        // Port 9735 still reaches the container
+        assert_eq!(check(Some(3001), &hidden_services), None);
+        let mut main_only = hidden_services.clone();
+        main_only[0].ports.truncate(1);
+        assert!(check(Some(3001), &main_only).is_some());
+        assert!(check(Some(3000), &[]).is_some());
+    }
+
+    #[test]
+    fn test_named_volumes() {
+        let example_app = |container_path: &str| AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    mounts: Some(Mounts {
+                        volumes: Some(map! {
+                            "cache" => container_path.to_string()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            },
+        };
+        let result =
+            convert_config("example-app", example_app("/cache"), &None, &None, &None).unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(services.get("main").unwrap().volumes, vec!["cache:/cache"]);
+        assert_eq!(
+            result.spec.volumes,
+            Some(bmap! {
+                "cache" => Volume {
+                    name: Some("example-app_cache".to_string()),
+                    ..Default::default()
+                }
+            })
+        );
+        for container_path in ["cache", "/cache:rw"] {
+            assert!(convert_config(
+                "example-app",
+                example_app(container_path),
+                &None,
+                &None,
+                &None,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_custom_networks() {
+        let example_app = |networks: &[&str]| AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    networks: Some(networks.iter().map(|name| name.to_string()).collect()),
+                    ..Default::default()
+                },
+                "db" => Container {
+                    image: "ghcr.io/runcitadel/example-db:main".to_string(),
+                    assign_fixed_ip: Some(false),
+                    networks: Some(networks.iter().map(|name| name.to_string()).collect()),
+                    ..Default::default()
+                }
+            },
+        };
+        let result = convert_config(
+            "example-app",
+            example_app(&["backend"]),
+            &None,
+            &None,
+            &None,
+        )
+        .unwrap();
+        let services = result.spec.services.unwrap();
+        let main_networks = services.get("main").unwrap().networks.as_ref().unwrap();
+        assert_eq!(
+            main_networks.keys().collect::<Vec<_>>(),
+            ["backend", "default"]
+        );
+        assert!(main_networks.get("default").unwrap().ipv4_address.is_some());
+        assert_eq!(
+            services.get("db").unwrap().networks,
+            Some(bmap! {
+                "backend" => NetworkEntry::default(),
+                "default" => NetworkEntry::default()
+            })
+        );
+        assert_eq!(
+            result.spec.networks,
+            Some(bmap! {
+                "backend" => Network {
+                    name: Some("example-app_backend".to_string()),
+                    ..Default::default()
+                }
+            })
+        );
+        for name in ["default", "back:end", ""] {
+            assert!(
+                convert_config("example-app", example_app(&[name]), &None, &None, &None).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_external_networks() {
+        let example_app = |permissions: Vec<Permissions>| AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                permissions,
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    external_networks: Some(vec!["monitoring".to_string()]),
+                    ..Default::default()
+                }
+            },
+        };
+        let network_permission = vec![Permissions::OneDependency("network".to_string())];
+        let result = convert_config(
+            "example-app",
+            example_app(network_permission),
+            &None,
+            &None,
+            &None,
+        )
+        .unwrap();
+        let services = result.spec.services.unwrap();
+        assert!(services
+            .get("main")
+            .unwrap()
+            .networks
+            .as_ref()
+            .unwrap()
+            .contains_key("monitoring"));
+        // External networks keep their name
+        assert_eq!(
+            result.spec.networks,
+            Some(bmap! {
+                "monitoring" => Network {
+                    external: Some(serde_json::Value::Bool(true)),
+                    ..Default::default()
+                }
+            })
+        );
+        assert!(
+            convert_config("example-app", example_app(Vec::new()), &None, &None, &None).is_err()
+        );
+    }
+
+    #[test]
+    fn test_tmpfs_mounts() {
+        let app_with_tmpfs = |size: &str| AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    mounts: Some(Mounts {
+                        tmpfs: Some(map! {
+                            "/tmp" => TmpfsMount {
+                                size: size.to_string(),
+                                mode: Some("1777".to_string()),
+                            }
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            },
+        };
+        let node_config = NodeConfig::default();
+        let result = convert_config_with(
+            "example-app",
+            app_with_tmpfs("64m"),
+            &None,
+            &None,
+            &None,
+            &node_config,
+        )
+        .unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(
+            services.get("main").unwrap().tmpfs,
+            vec!["/tmp:size=67108864,mode=1777"]
+        );
+        // size=0 would make the mount unlimited
+        for size in ["1g", "0"] {
+            assert!(convert_config_with(
+                "example-app",
+                app_with_tmpfs(size),
+                &None,
+                &None,
+                &None,
+                &node_config,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_logging_limits() {
+        let example_app = AppYml {
+            citadel_version: 4,
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                version: "1.0.0".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    logging: Some(InputLogging {
+                        max_size: Some("1g".to_string()),
+                        max_file: Some(2),
+                    }),
+                    ..Default::default()
+                }
+            },
+        };
+        let node_config = NodeConfig::default();
+        let result = convert_config_with(
+            "example-app",
+            example_app.clone(),
+            &None,
+            &None,
+            &None,
+            &node_config,
+        )
+        .unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(
+            services.get("main").unwrap().logging,
+            Some(Logging {
+                driver: Some("json-file".to_string()),
+                options: Some(BTreeMap::from([
+                    ("max-size".to_string(), "52428800".into()),
+                    ("max-file".to_string(), "2".into()),
+                ])),
+            })
+        );
+        let mut zero_size = example_app.clone();
+        zero_size
+            .services
+            .get_mut("main")
+            .unwrap()
+            .logging
+            .as_mut()
+            .unwrap()
+            .max_size = Some("0".to_string());
+        assert!(
+            convert_config_with("example-app", zero_size, &None, &None, &None, &node_config)
+                .is_err()
+        );
+        let mut node_config = NodeConfig::default();
+        node_config.logging.driver = "journald".to_string();
+        let result = convert_config_with(
+            "example-app",
+            example_app,
+            &None,
+            &None,
+            &None,
+            &node_config,
+        )
+        .unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(
+            services.get("main").unwrap().logging,
+            Some(Logging {
+                driver: Some("journald".to_string()),
+                options: None,
+            })
+        );
+    }
 }
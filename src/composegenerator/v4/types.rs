@@ -12,6 +12,38 @@ use crate::composegenerator::types::Permissions;
 pub enum HiddenServices {
     PortMap(HashMap<u16, u16>),
     LayeredMap(HashMap<String, HashMap<u16, u16>>),
+    DetailedMap(HashMap<String, HiddenServiceDefinition>),
+}
+
+impl HiddenServices {
+    /// The named hidden services as (name, ports, authorized_clients_only), sorted by name
+    pub fn named(&self) -> Vec<(&String, &HashMap<u16, u16>, bool)> {
+        let mut named: Vec<(&String, &HashMap<u16, u16>, bool)> = match self {
+            HiddenServices::PortMap(_) => Vec::new(),
+            HiddenServices::LayeredMap(layered_map) => layered_map
+                .iter()
+                .map(|(name, ports)| (name, ports, false))
+                .collect(),
+            HiddenServices::DetailedMap(detailed_map) => detailed_map
+                .iter()
+                .map(|(name, definition)| {
+                    (name, &definition.ports, definition.authorized_clients_only)
+                })
+                .collect(),
+        };
+        named.sort_by_key(|(name, _, _)| *name);
+        named
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct HiddenServiceDefinition {
+    /// Port on the .onion address -> port in the container
+    pub ports: HashMap<u16, u16>,
+    /// Only allow clients whose keys were added with app-cli to connect
+    #[serde(default)]
+    pub authorized_clients_only: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -32,6 +64,20 @@ pub enum PortPriority {
     Required,
 }
 
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct WebEndpoint {
+    /// The port the web interface listens on inside the container
+    pub port: u16,
+    /// The label of the "Open" link on the dashboard
+    pub label: String,
+    /// The path the link should lead to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_priority: Option<PortPriority>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct Mounts {
@@ -43,6 +89,33 @@ pub struct Mounts {
     pub c_lightning: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<HashMap<String, String>>,
+    /// Named volume -> path in the container, volume names are only visible to the app itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<HashMap<String, String>>,
+    /// Path in the container -> in-memory filesystem to mount there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmpfs: Option<HashMap<String, TmpfsMount>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Logging {
+    /// The maximum size of a log file before it is rotated, like 10m
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<String>,
+    /// The number of rotated log files to keep
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct TmpfsMount {
+    /// The maximum size, like 64m
+    pub size: String,
+    /// The permissions as an octal number, like 1777
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -89,8 +162,20 @@ pub struct Container {
     pub mounts: Option<Mounts>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub assign_fixed_ip: Option<bool>,
+    /// Networks only the app's own containers can join, in addition to the network all apps share
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub networks: Option<Vec<String>>,
+    /// Networks created outside of Citadel, they are joined by their name and require the network permission
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_networks: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hidden_services: Option<HiddenServices>,
+    /// Additional web interfaces (endpoint id -> definition), can be used on any container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub web_endpoints: Option<BTreeMap<String, WebEndpoint>>,
+    /// Log rotation settings, limited by the node's maximum
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<Logging>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -136,6 +221,9 @@ pub struct InputMetadata {
     pub version_control: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub release_notes: Option<BTreeMap<String, String>>,
+    /// Pin the app's hidden services to a specific Tor instance (starting at 1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tor_instance: Option<u8>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -18,6 +18,21 @@ pub fn derive_entropy(seed: &str, identifier: &str) -> String {
     hex::encode(result)
 }
 
+/// The APP_SEED and APP_SEED_1 to APP_SEED_5 variables of an app
+pub fn app_seeds(citadel_seed: &str, app_id: &str) -> Vec<(String, String)> {
+    let mut seeds = vec![(
+        "APP_SEED".to_string(),
+        derive_entropy(citadel_seed, &format!("app-{}-seed", app_id)),
+    )];
+    for i in 1..6 {
+        seeds.push((
+            format!("APP_SEED_{}", i),
+            derive_entropy(citadel_seed, &format!("app-{}-seed{}", app_id, i)),
+        ));
+    }
+    seeds
+}
+
 pub fn validate_cmd(app_name: &str, command: &Command, permissions: &[String]) -> Result<()> {
     match command {
         Command::SimpleCommand(simple_command) => {
@@ -43,9 +58,9 @@ pub fn validate_cmd(app_name: &str, command: &Command, permissions: &[String]) -
 }
 
 pub fn get_host_port(port_map: &[PortMapElement], internal_port: u16) -> Option<&PortMapElement> {
-    return port_map
+    port_map
         .iter()
-        .find(|&elem| elem.internal_port == internal_port);
+        .find(|&elem| elem.internal_port == internal_port)
 }
 
 pub fn validate_port_map_app(
@@ -71,11 +86,11 @@ pub fn get_main_container(spec: &super::types::AppYml) -> Result<String> {
             main_service_name = Some(service_name.to_string());
             break;
         } else if service_name.starts_with("main") {
-            if main_service_name.is_some() {
+            if let Some(main_service_name) = main_service_name {
                 tracing::error!(
                     "Container {} and {} could both be main container",
                     service_name,
-                    main_service_name.unwrap()
+                    main_service_name
                 );
                 bail!("Multiple main containers in app!");
             }
@@ -1,3 +1,4 @@
+use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 
@@ -53,9 +54,31 @@ pub fn find_env_vars(string: &str) -> Vec<&str> {
     result
 }
 
+/// Replaces every env var in a string with the result of the given function
+pub fn replace_env_vars<F>(string: &str, mut replacement: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    let mut result = String::with_capacity(string.len());
+    let mut last_end = 0;
+    for matched in ENV_VAR_REGEX.find_iter(string) {
+        let var = matched.as_str();
+        let name = if var.starts_with("${") {
+            &var[2..var.len() - 1]
+        } else {
+            &var[1..]
+        };
+        result.push_str(&string[last_end..matched.start()]);
+        result.push_str(&replacement(name)?);
+        last_end = matched.end();
+    }
+    result.push_str(&string[last_end..]);
+    Ok(result)
+}
+
 #[cfg(test)]
 mod test_env_vars {
-    use crate::utils::find_env_vars;
+    use crate::utils::{find_env_vars, replace_env_vars};
 
     #[test]
     fn handle_empty_properly() {
@@ -82,6 +105,12 @@ mod test_env_vars {
 
         assert!(expected.iter().all(|item| result.contains(item)));
     }
+
+    #[test]
+    fn replace_both_syntaxes() {
+        let result = replace_env_vars("${APP_SEED}:$APP_SEED_1", |var| Ok(var.to_lowercase()));
+        assert_eq!(result.unwrap(), "app_seed:app_seed_1");
+    }
 }
 
 pub fn flatten(perms: Vec<Permissions>) -> Vec<String> {
@@ -131,3 +160,43 @@ mod test_flatten {
         );
     }
 }
+
+/// Parses a size like 64m or 1g (in binary units, like Docker) into bytes
+///
+/// A size of 0 is rejected, because Docker treats it as unlimited.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim().to_lowercase();
+    let size = size.strip_suffix('b').unwrap_or(&size);
+    let (number, multiplier) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    let Ok(number) = number.parse::<u64>() else {
+        bail!("Invalid size: {}", size);
+    };
+    let Some(bytes) = number.checked_mul(multiplier) else {
+        bail!("Size is too large: {}", size);
+    };
+    if bytes == 0 {
+        bail!("Size must not be 0");
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test_parse_size {
+    use crate::utils::parse_size;
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64m").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_size("1GB").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("m").is_err());
+        assert!(parse_size("-1k").is_err());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0m").is_err());
+    }
+}